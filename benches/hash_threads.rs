@@ -0,0 +1,55 @@
+//! Times a step hashing many `run` files on one thread and on several.
+//!
+//! Run with `cargo bench`, the services are only hashed, nothing is spawned.
+
+use runsvdir::Stepper;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const SERVICES: usize = 200;
+const RUN_SIZE: usize = 1 << 20;
+const ROUNDS: u32 = 5;
+
+fn services() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("runsvdir-bench-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut run = b"#!/bin/sh\n".to_vec();
+    run.resize(RUN_SIZE, b'#');
+    for i in 0..SERVICES {
+        let service = dir.join(format!("svc{i:03}"));
+        fs::create_dir_all(&service).unwrap();
+        // Every run differs, or the hashes would all be the same
+        run[10..14].copy_from_slice(&(i as u32).to_le_bytes());
+        fs::write(service.join("run"), &run).unwrap();
+    }
+    dir
+}
+
+/// The mean duration of a step hashing every service on `threads` threads
+fn step(dir: &Path, threads: usize) -> Duration {
+    let mut stepper = Stepper::builder(dir.into())
+        .dry_run(true)
+        .hash_cache(false)
+        .hash_threads(threads)
+        .build();
+    stepper.invoke().unwrap();
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        stepper.invoke().unwrap();
+    }
+    started.elapsed() / ROUNDS
+}
+
+fn main() {
+    let dir = services();
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get().min(8));
+    let sequential = step(&dir, 1);
+    println!("{SERVICES} services, 1 thread: {sequential:?} per step");
+    if threads > 1 {
+        let parallel = step(&dir, threads);
+        let speedup = sequential.as_secs_f64() / parallel.as_secs_f64();
+        println!("{SERVICES} services, {threads} threads: {parallel:?} per step, {speedup:.1}x");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
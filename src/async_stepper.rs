@@ -0,0 +1,363 @@
+//! A [`Stepper`](crate::Stepper) counterpart for Tokio runtimes.
+//!
+//! Directory reads go through `tokio::fs`, hashing runs on the blocking pool and every child is
+//! awaited by its own task instead of being polled. Failed services are respawned on the next
+//! step without backoff, and stale services get SIGTERM only. [`AsyncStepper::run`] steps in a
+//! `select!` loop, as soon as the directory changes where it can be watched.
+
+use crate::envdir::{apply_env, read_env};
+use crate::process::{run_script, terminate, ProcessGroup, Signal};
+use crate::{
+    EventSink, HashAlg, Identity, LogMode, Shash, StepError, StepReport, TracingSink, Watcher,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{absolute, Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep_until, timeout};
+use tracing::{error, info, warn};
+
+/// The PID of a child and the outcome of waiting for it
+type Exit = (Shash, u32, io::Result<ExitStatus>);
+
+pub struct AsyncStepper {
+    dir: PathBuf,
+    identity: Identity,
+    /// The PIDs of running services and whether SIGTERM has been sent
+    running: HashMap<Shash, (u32, bool)>,
+    exits: UnboundedReceiver<Exit>,
+    exits_tx: UnboundedSender<Exit>,
+    /// The time to wait for services to exit on shutdown before killing them
+    pub shutdown_timeout: Duration,
+    /// Where the output of services goes
+    pub log_mode: LogMode,
+    /// The directory for per-service log files, the service directory if unset
+    pub log_dir: Option<PathBuf>,
+    /// Whether to run each service in its own process group and signal the whole group
+    pub process_group: bool,
+    /// The digest identifying services
+    pub hash_alg: HashAlg,
+    /// The number of leading bytes of `run` hashed, the whole file if `None`
+    pub hash_limit: Option<u64>,
+    /// Receives spawn, exit and stale events
+    pub events: Box<dyn EventSink>,
+}
+
+impl AsyncStepper {
+    pub fn new(dir: PathBuf) -> Self {
+        Self::with_identity(dir, Identity::default())
+    }
+
+    pub fn with_identity(dir: PathBuf, identity: Identity) -> Self {
+        let (exits_tx, exits) = unbounded_channel();
+        Self {
+            dir,
+            identity,
+            running: HashMap::new(),
+            exits,
+            exits_tx,
+            shutdown_timeout: Duration::from_secs(7),
+            log_mode: LogMode::default(),
+            log_dir: None,
+            process_group: true,
+            hash_alg: HashAlg::default(),
+            hash_limit: None,
+            events: Box::new(TracingSink),
+        }
+    }
+
+    pub async fn invoke(&mut self) -> Result<StepReport, StepError> {
+        let mut report = StepReport::default();
+        while let Ok(exit) = self.exits.try_recv() {
+            self.exited(exit);
+        }
+
+        let mut dirs = vec![];
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .map_err(|err| StepError::ReadDir(self.dir.clone(), err))?;
+        loop {
+            match entries.next_entry().await {
+                Ok(Some(d)) => dirs.push(d.path()),
+                Ok(None) => break,
+                Err(err) => {
+                    let err = StepError::ReadDirEntry(self.dir.clone(), err);
+                    error!("skipping entry, err: {err}");
+                    report.errors.push(err);
+                    break;
+                }
+            }
+        }
+
+        let mut hashing = vec![];
+        for dir in dirs {
+            if tokio::fs::try_exists(dir.join("down"))
+                .await
+                .unwrap_or_default()
+            {
+                info!("{dir:?} is down");
+                continue;
+            }
+            let run = run_script(&dir);
+            let (identity, alg, limit) = (self.identity, self.hash_alg, self.hash_limit);
+            let hash = spawn_blocking({
+                let run = run.clone();
+                move || identity.shash(&run, alg, limit)
+            });
+            hashing.push((dir, run, hash));
+        }
+
+        let mut cur = HashSet::new();
+        for (dir, run, hash) in hashing {
+            let hash = match hash.await.expect("hashing panicked") {
+                Ok(hash) => hash,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    let err = StepError::Shash(run, err);
+                    error!("skipping entry, err: {err}");
+                    report.errors.push(err);
+                    continue;
+                }
+            };
+            cur.insert(hash.clone());
+            if self.running.contains_key(&hash) {
+                info!("{hash} is already running");
+                continue;
+            }
+            match self.spawn(&dir, &run, &hash) {
+                Ok(mut child) => {
+                    let pid = child.id().unwrap_or_default();
+                    self.events.on_spawn(&hash, pid);
+                    self.running.insert(hash.clone(), (pid, false));
+                    let exits = self.exits_tx.clone();
+                    report.spawned.push(hash.clone());
+                    tokio::spawn(async move {
+                        let status = child.wait().await;
+                        let _ = exits.send((hash, pid, status));
+                    });
+                }
+                Err(err) => {
+                    report.failures += 1;
+                    self.events.on_spawn_failed(err.path(), &err);
+                    error!("skipping entry, err: {err}");
+                    report.errors.push(err);
+                }
+            }
+        }
+
+        let group = ProcessGroup::new(self.process_group);
+        for (hash, (pid, term_sent)) in &mut self.running {
+            if !cur.contains(hash) && !*term_sent {
+                self.events.on_stale(hash);
+                terminate(hash, *pid, Signal::SIGTERM, group);
+                *term_sent = true;
+            }
+        }
+        Ok(report)
+    }
+
+    fn spawn(
+        &self,
+        service_dir: &Path,
+        program: &Path,
+        hash: &Shash,
+    ) -> Result<tokio::process::Child, StepError> {
+        let (stdout, stderr) = self
+            .log_mode
+            .stdio(self.log_dir.as_deref(), service_dir)
+            .map_err(|err| StepError::Log(service_dir.into(), err))?;
+        let env = read_env(service_dir).map_err(|err| StepError::Env(service_dir.into(), err))?;
+        let program = absolute(program).map_err(|err| StepError::Spawn(hash.clone(), err))?;
+        let mut command = std::process::Command::new(program);
+        command
+            .current_dir(service_dir)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr);
+        ProcessGroup::new(self.process_group).configure(&mut command);
+        apply_env(&mut command, env);
+        let mut child = Command::from(command)
+            .spawn()
+            .map_err(|err| StepError::Spawn(hash.clone(), err))?;
+        let log = |err| StepError::Log(service_dir.into(), err);
+        #[cfg(unix)]
+        let (stdout, stderr) = (
+            child.stdout.take().map(|o| o.into_owned_fd()).transpose(),
+            child.stderr.take().map(|e| e.into_owned_fd()).transpose(),
+        );
+        #[cfg(windows)]
+        let (stdout, stderr) = (
+            child
+                .stdout
+                .take()
+                .map(|o| o.into_owned_handle())
+                .transpose(),
+            child
+                .stderr
+                .take()
+                .map(|e| e.into_owned_handle())
+                .transpose(),
+        );
+        self.log_mode
+            .capture(
+                self.log_dir.as_deref(),
+                service_dir,
+                stdout.map_err(log)?.map(File::from),
+                stderr.map_err(log)?.map(File::from),
+            )
+            .map_err(log)?;
+        Ok(child)
+    }
+
+    fn exited(&mut self, (hash, pid, status): Exit) {
+        if self.running.get(&hash).is_some_and(|(p, _)| *p == pid) {
+            self.running.remove(&hash);
+        }
+        match status {
+            Ok(status) => self.events.on_exit(&hash, status),
+            Err(err) => error!("get exit status for {hash} failed: {err}"),
+        }
+    }
+
+    /// Steps every `pause` and whenever the directory changes until `shutdown` completes, then
+    /// stops all services
+    ///
+    /// Exits are handled as they happen, the exited services are respawned on the next step.
+    pub async fn run(&mut self, pause: Duration, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        let mut changes = self.watch();
+        'run: loop {
+            if let Err(err) = self.invoke().await {
+                error!("step failed: {err}");
+            }
+            let deadline = tokio::time::Instant::now() + pause;
+            loop {
+                tokio::select! {
+                    () = &mut shutdown => break 'run,
+                    () = sleep_until(deadline) => break,
+                    Some(()) = changes.recv() => break,
+                    Some(exit) = self.exits.recv() => self.exited(exit),
+                }
+            }
+        }
+        self.shutdown().await;
+    }
+
+    /// Watches the directory on the blocking pool, the channel is closed if it cannot be watched
+    fn watch(&self) -> UnboundedReceiver<()> {
+        let (tx, rx) = unbounded_channel();
+        let mut watcher = match Watcher::new(&self.dir) {
+            Ok(watcher) => watcher,
+            Err(err) => {
+                warn!("watching {:?} failed, polling: {err}", self.dir);
+                return rx;
+            }
+        };
+        spawn_blocking(move || {
+            while !tx.is_closed() {
+                match watcher.wait(Duration::from_millis(100)) {
+                    Ok(true) => {
+                        let _ = tx.send(());
+                    }
+                    Ok(false) => {}
+                    Err(err) => {
+                        error!("watching failed, polling: {err}");
+                        break;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    /// Stops all services, killing those which do not exit within `shutdown_timeout`
+    pub async fn shutdown(&mut self) {
+        let group = ProcessGroup::new(self.process_group);
+        for (hash, (pid, _)) in &self.running {
+            info!("stop {hash}");
+            terminate(hash, *pid, Signal::SIGTERM, group);
+        }
+        let deadline = tokio::time::Instant::now() + self.shutdown_timeout;
+        while !self.running.is_empty() {
+            match tokio::time::timeout_at(deadline, self.exits.recv()).await {
+                Ok(Some(exit)) => self.exited(exit),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        for (hash, (pid, _)) in &self.running {
+            self.events.on_kill(hash);
+            terminate(hash, *pid, Signal::SIGKILL, group);
+        }
+        while !self.running.is_empty() {
+            match timeout(Duration::from_secs(1), self.exits.recv()).await {
+                Ok(Some(exit)) => self.exited(exit),
+                Ok(None) | Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn async_step_test() {
+        let mut stepper = AsyncStepper::new(PathBuf::from("test_res"));
+        stepper.invoke().await.unwrap();
+
+        assert_eq!(
+            stepper.running.keys().collect::<HashSet<_>>(),
+            HashSet::from([
+                &Shash::try_from(Path::new("test_res/b/run")).unwrap(),
+                &Shash::try_from(Path::new("test_res/d/run")).unwrap()
+            ])
+        );
+
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown().await;
+        assert!(stepper.running.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn run_test() {
+        let dir = std::env::temp_dir().join(format!("runsvdir-async-run-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let started = dir.with_extension("started");
+        let _ = std::fs::remove_file(&started);
+        let mut stepper = AsyncStepper::new(dir.clone());
+        stepper.shutdown_timeout = Duration::ZERO;
+
+        let service = async {
+            // After the first step, which is long over before the second one is due
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let svc = dir.join("svc");
+            std::fs::create_dir(&svc).unwrap();
+            let run = svc.join("run");
+            let script = format!("#!/bin/sh\ntouch {}\nexec sleep 100\n", started.display());
+            std::fs::write(&run, script).unwrap();
+            std::fs::set_permissions(&run, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+                .unwrap();
+            while !started.exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(
+            Duration::from_secs(10),
+            stepper.run(Duration::from_secs(60), service),
+        )
+        .await
+        .unwrap();
+        assert!(stepper.running.is_empty());
+    }
+}
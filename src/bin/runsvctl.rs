@@ -0,0 +1,84 @@
+use clap::{Parser, Subcommand};
+use runsvdir::ControlCommand;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Sends a command to the control socket of runsvdir and prints the reply
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// The control socket runsvdir listens on
+    #[clap(long)]
+    socket: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the services, or the one named
+    Status { name: Option<String> },
+    /// Terminates the service with its term signal, it gets respawned
+    Restart { name: String },
+    /// Sends the service SIGTERM, it gets respawned
+    Term { name: String },
+    /// Sends the service SIGKILL, it gets respawned
+    Kill { name: String },
+    /// Keeps the service stopped until `up`
+    Down { name: String },
+    /// Reverts `down` and `once`, respawning a completed service
+    Up { name: String },
+    /// Spawns the service if it is down, it is not respawned once it exits
+    Once { name: String },
+}
+
+impl From<Command> for ControlCommand {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Status { name } => Self::Status(name),
+            Command::Restart { name } => Self::Restart(name),
+            Command::Term { name } => Self::Term(name),
+            Command::Kill { name } => Self::Kill(name),
+            Command::Down { name } => Self::Down(name),
+            Command::Up { name } => Self::Up(name),
+            Command::Once { name } => Self::Once(name),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let command = ControlCommand::from(args.command);
+    match send(&args.socket, &command) {
+        Ok(reply) => {
+            print!("{reply}");
+            if reply.starts_with("error:") {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(err) => {
+            eprintln!("sending {command} to {:?} failed: {err}", args.socket);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Writes `command` to the socket at `path` and reads the reply until the supervisor hangs up
+#[cfg(unix)]
+fn send(path: &std::path::Path, command: &ControlCommand) -> std::io::Result<String> {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{command}")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+#[cfg(not(unix))]
+fn send(_path: &std::path::Path, _command: &ControlCommand) -> std::io::Result<String> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
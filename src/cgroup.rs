@@ -0,0 +1,168 @@
+//! Running every service in a cgroup v2 group of its own, named after its directory.
+//!
+//! The settings of a group come from `<service_dir>/cgroup`, one `file value` per line such as
+//! `memory.max 512M` or `cpu.max 50000 100000`, written to the files of the group before the
+//! service joins it.
+
+use crate::stepper::service_name;
+use crate::Shash;
+use std::fs::{create_dir_all, read_to_string, remove_dir, File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::debug;
+
+/// The files written to a group, in order
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct CgroupSettings(pub Vec<(String, String)>);
+
+#[derive(Debug, Error)]
+pub enum CgroupError {
+    #[error("Reading cgroup settings failed: {0}")]
+    Read(#[source] io::Error),
+    #[error("Line {0}: expected `file value`")]
+    Syntax(usize),
+    #[error("Line {0}: invalid file {1:?}")]
+    InvalidFile(usize, String),
+    #[error("Creating {0:?} failed: {1}")]
+    Create(PathBuf, #[source] io::Error),
+    #[error("Writing {0:?} failed: {1}")]
+    Write(PathBuf, #[source] io::Error),
+}
+
+impl CgroupSettings {
+    /// Reads `<service_dir>/cgroup`, no file means no settings
+    ///
+    /// Empty lines and lines starting with `#` are skipped.
+    pub fn read(service_dir: &Path) -> Result<Self, CgroupError> {
+        match read_to_string(service_dir.join("cgroup")) {
+            Ok(content) => content.parse(),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(CgroupError::Read(err)),
+        }
+    }
+}
+
+impl FromStr for CgroupSettings {
+    type Err = CgroupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let n = i + 1;
+            let (file, value) = line
+                .split_once(char::is_whitespace)
+                .ok_or(CgroupError::Syntax(n))?;
+            // Only the interface files of the group itself, not the ones moving processes
+            if file.contains('/') || file.starts_with('.') || file.starts_with("cgroup.") {
+                return Err(CgroupError::InvalidFile(n, file.into()));
+            }
+            settings.push((file.into(), value.trim().into()));
+        }
+        Ok(Self(settings))
+    }
+}
+
+/// The group of a single service
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// The group of the service of `hash` under `root`
+    pub(crate) fn new(root: &Path, hash: &Shash) -> Option<Self> {
+        Some(Self {
+            path: root.join(service_name(hash)?),
+        })
+    }
+
+    /// Creates the group with the settings of `service_dir`, making `command` join it right
+    /// before exec
+    pub(crate) fn prepare(
+        &self,
+        service_dir: &Path,
+        command: &mut Command,
+    ) -> Result<(), CgroupError> {
+        let settings = CgroupSettings::read(service_dir)?;
+        create_dir_all(&self.path).map_err(|err| CgroupError::Create(self.path.clone(), err))?;
+        for (file, value) in &settings.0 {
+            self.write(file, value)?;
+        }
+        let procs = self.path.join("cgroup.procs");
+        let mut procs = open(&procs).map_err(|err| CgroupError::Write(procs, err))?;
+        // SAFETY: write is async-signal-safe and nothing is allocated
+        unsafe {
+            command.pre_exec(move || procs.write_all(b"0"));
+        }
+        Ok(())
+    }
+
+    /// Kills every process in the group, returning whether it could, `cgroup.kill` takes Linux 5.14
+    pub(crate) fn kill(&self) -> bool {
+        self.write("cgroup.kill", "1")
+            .inspect_err(|err| debug!("{err}"))
+            .is_ok()
+    }
+
+    /// Removes the group, which fails while processes are left in it
+    pub(crate) fn remove(&self) {
+        match remove_dir(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                debug!("removing {:?} failed: {err}", self.path);
+            }
+            _ => {}
+        }
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<(), CgroupError> {
+        let path = self.path.join(file);
+        open(&path)
+            .and_then(|mut f| f.write_all(value.as_bytes()))
+            .map_err(|err| CgroupError::Write(path, err))
+    }
+}
+
+/// Opens an existing interface file, the kernel creates them along with the group
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).truncate(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_test() {
+        assert_eq!(
+            CgroupSettings::read(Path::new("test_res/b")).unwrap(),
+            CgroupSettings::default()
+        );
+        assert_eq!(
+            "# limits\nmemory.max 512M\n\ncpu.max 50000 100000\n"
+                .parse::<CgroupSettings>()
+                .unwrap(),
+            CgroupSettings(vec![
+                ("memory.max".into(), "512M".into()),
+                ("cpu.max".into(), "50000 100000".into()),
+            ])
+        );
+        assert!(matches!(
+            "memory.max".parse::<CgroupSettings>(),
+            Err(CgroupError::Syntax(1))
+        ));
+        for file in ["../memory.max", "cgroup.procs"] {
+            assert!(matches!(
+                format!("{file} 1").parse::<CgroupSettings>(),
+                Err(CgroupError::InvalidFile(1, f)) if f == file
+            ));
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The source of time for [`crate::Stepper`] deadlines
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic system clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock standing still until advanced, clones share the time
+#[derive(Debug, Clone)]
+pub struct TestClock(Arc<Mutex<Instant>>);
+
+impl TestClock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Default for TestClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_test() {
+        let clock = TestClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.clone().advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,367 @@
+use crate::{HashAlg, Identity, LogMode, RestartPolicy};
+use clap::Args;
+use glob::Pattern;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing::level_filters::LevelFilter;
+
+/// Supervisor settings, given on the command line or in a TOML file
+///
+/// Unset values fall back to the defaults documented on each field.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Args, Deserialize)]
+#[command(about = None, long_about = None)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The number of millis to wait between each check [default: 1000]
+    #[clap(short, long)]
+    pub pause: Option<u64>,
+    /// The number of millis to delay the first respawn of a failed service [default: 1000]
+    #[clap(long)]
+    pub backoff_base: Option<u64>,
+    /// The maximum number of millis to delay the respawn of a failed service [default: 60000]
+    #[clap(long)]
+    pub backoff_cap: Option<u64>,
+    /// The number of failures within the restart window after which a service is given up
+    /// instead of respawned, 0 never gives up [default: 0]
+    #[clap(long)]
+    pub restart_limit: Option<usize>,
+    /// The number of millis the failures counted by the restart limit must be within
+    /// [default: 60000]
+    #[clap(long)]
+    pub restart_window: Option<u64>,
+    /// The number of millis a service has to stay up for its exit not to count as a failed start,
+    /// which backs off like a failure [default: 0]
+    #[clap(long)]
+    pub min_uptime: Option<u64>,
+    /// The number of millis to wait for services to stop on shutdown before killing them
+    /// [default: 7000]
+    #[clap(long)]
+    pub shutdown_timeout: Option<u64>,
+    /// The number of millis to wait for a service to stop after its term signal before killing it
+    /// [default: 7000]
+    #[clap(long)]
+    pub kill_timeout: Option<u64>,
+    /// The number of millis reading the directory may take before the step fails, leaving the
+    /// services as they are [default: 10000]
+    #[clap(long)]
+    pub scan_timeout: Option<u64>,
+    /// The number of millis a `finish` script may run before it is killed [default: 5000]
+    #[clap(long)]
+    pub finish_timeout: Option<u64>,
+    /// The number of millis `run` has to stay unmodified before an edit restarts the service
+    /// [default: 0]
+    #[clap(long)]
+    pub debounce: Option<u64>,
+    /// The number of millis a service with a `check-port` or a `notification-fd` may take to be
+    /// ready [default: 10000]
+    #[clap(long)]
+    pub ready_timeout: Option<u64>,
+    /// The number of millis the same error of an entry is counted instead of logged again
+    /// [default: 60000]
+    #[clap(long)]
+    pub error_cooldown: Option<u64>,
+    /// The number of millis between runs of the `check` script of a service, which fails if it
+    /// takes longer [default: 10000]
+    #[clap(long)]
+    pub health_interval: Option<u64>,
+    /// The number of consecutive failures of `check` restarting a service [default: 3]
+    #[clap(long)]
+    pub health_failures: Option<u32>,
+    /// The number of consecutive empty scans of the directory ignored while services run, as a
+    /// directory being swapped may look empty for a moment [default: 0]
+    #[clap(long)]
+    pub empty_confirmations: Option<u32>,
+    /// The restart policy of services without a `restart` file [default: always]
+    #[clap(long)]
+    pub restart: Option<RestartPolicy>,
+    /// Whether to stop the services which need a service that exited, they start again once it is
+    /// up [default: false]
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub restart_dependents: Option<bool>,
+    /// Whether to keep a runit `<service>/supervise` directory for `sv`, executing the commands
+    /// written to its `control` and adopting the live service its `pid` names instead of spawning
+    /// another, adopting is Linux only [default: false]
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub supervise: Option<bool>,
+    /// The maximum number of stale services stopped per step, in the order of their paths
+    #[clap(long = "max-stops-per-tick")]
+    pub max_stops_per_tick: Option<usize>,
+    /// Leave services removed from the directory running until they exit on their own
+    ///
+    /// Set as `kill_stale = false` in the config file.
+    #[clap(long = "no-kill-stale", num_args = 0, default_missing_value = "false")]
+    pub kill_stale: Option<bool>,
+    /// Where the output of services goes [default: null]
+    #[clap(long, value_enum)]
+    pub log_mode: Option<LogMode>,
+    /// The directory for per-service log files, the service directory if unset
+    #[clap(long)]
+    pub log_dir: Option<PathBuf>,
+    /// Whether to run services from their own directory [default: true]
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub chdir: Option<bool>,
+    /// What makes up the identity of a service [default: content]
+    #[clap(long, value_enum)]
+    pub identity: Option<Identity>,
+    /// Whether to reap exited children the supervisor does not track, becoming a subreaper on Linux
+    /// so orphans of the services are its children, Unix only, fixed at startup [default: true when
+    /// running as PID 1]
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub reap_orphans: Option<bool>,
+    /// Whether to look for services in subdirectories without `run` as well
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub recursive: Option<bool>,
+    /// Whether to run each service in its own process group and signal the whole group
+    /// [default: true]
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub process_group: Option<bool>,
+    /// The cgroup v2 directory every service gets a group of its own in, needs the `cgroup`
+    /// feature and Linux
+    #[clap(long)]
+    pub cgroup_root: Option<PathBuf>,
+    /// The number of threads hashing `run` files, up to 8 depending on the available parallelism
+    /// by default
+    #[clap(long)]
+    pub hash_threads: Option<usize>,
+    /// A glob matched against directory entry names, skipping matching entries, may be repeated
+    #[clap(long)]
+    #[serde(default, deserialize_with = "patterns")]
+    pub ignore: Option<Vec<Pattern>>,
+    /// The maximum number of services running at once, started in the order of their paths
+    #[clap(long)]
+    pub max_concurrent: Option<usize>,
+    /// Whether to reuse the hash of a run whose size, modification time and inode are unchanged
+    /// instead of reading it on every step [default: true]
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub hash_cache: Option<bool>,
+    /// The number of millis hashing the `run` files may take, each on a thread of its own, before
+    /// the slow ones are deferred to a later step, leaving their services as they are, they are
+    /// hashed on the hash threads waited for if unset
+    #[clap(long)]
+    pub hash_timeout: Option<u64>,
+    /// The digest identifying services [default: sha512_256]
+    #[clap(long, value_enum)]
+    pub hash_alg: Option<HashAlg>,
+    /// The number of leading bytes of `run` to hash, its size counts still, the whole file if unset
+    #[clap(long)]
+    pub hash_limit: Option<u64>,
+    /// Whether to step only when the directory changes, a service exits or a deadline is due
+    /// instead of after every pause
+    ///
+    /// Uses inotify, degrades to polling on non-Linux systems.
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub watch: Option<bool>,
+    /// The number of millis between steps while watching and nothing is due, a fallback for the
+    /// changes inotify misses [default: 60000]
+    #[clap(long)]
+    pub watch_interval: Option<u64>,
+    /// The path of a Unix domain socket accepting `up`, `down`, `once`, `restart`, `term`, `kill`
+    /// and `status` commands, Unix only
+    #[clap(long)]
+    pub control_socket: Option<PathBuf>,
+    /// The address to serve Prometheus metrics on at `/metrics`, needs the `metrics` feature
+    #[clap(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// A file to write the PID of the supervisor to, refusing to start if it names a running process
+    #[clap(long)]
+    pub pidfile: Option<PathBuf>,
+    /// A file of `KEY=VALUE` lines setting variables for every service, read on start, under the
+    /// `env` directory of each service
+    #[clap(long)]
+    pub env_file: Option<PathBuf>,
+    /// A JSON file keeping restart counts, backoff and processes of services across restarts of
+    /// the supervisor, read on start and written on every change, services still running are
+    /// adopted instead of spawned again on Linux
+    #[clap(long)]
+    pub state_file: Option<PathBuf>,
+    /// The most verbose level of the supervisor's own log records, directives in `RUST_LOG`
+    /// take precedence, read on start [default: info]
+    #[clap(long)]
+    #[serde(default, deserialize_with = "level")]
+    pub log_level: Option<LevelFilter>,
+    /// Settings overriding the ones above for single services, by their directory names, in
+    /// `[services.<name>]` tables of the config file only
+    #[clap(skip)]
+    #[serde(default)]
+    pub services: HashMap<String, ServiceOverrides>,
+    /// The directory to store process states
+    pub dir: Option<PathBuf>,
+}
+
+/// The settings of a single service overriding the global ones
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceOverrides {
+    /// The restart policy, a `restart` file or `service.toml` of the service take precedence
+    pub restart: Option<RestartPolicy>,
+    /// The number of millis to wait for the service to stop after its term signal before killing
+    /// it
+    pub kill_timeout: Option<u64>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Reading config {0:?} failed: {1}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Parsing config {0:?} failed: {1}")]
+    Parse(PathBuf, #[source] Box<toml::de::Error>),
+}
+
+impl Config {
+    /// Reads a TOML config file
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let config = read_to_string(path).map_err(|err| ConfigError::Read(path.into(), err))?;
+        toml::from_str(&config).map_err(|err| ConfigError::Parse(path.into(), Box::new(err)))
+    }
+
+    /// Takes the values set in `self`, falling back to `other`
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            pause: self.pause.or(other.pause),
+            backoff_base: self.backoff_base.or(other.backoff_base),
+            backoff_cap: self.backoff_cap.or(other.backoff_cap),
+            restart_limit: self.restart_limit.or(other.restart_limit),
+            restart_window: self.restart_window.or(other.restart_window),
+            min_uptime: self.min_uptime.or(other.min_uptime),
+            shutdown_timeout: self.shutdown_timeout.or(other.shutdown_timeout),
+            kill_timeout: self.kill_timeout.or(other.kill_timeout),
+            scan_timeout: self.scan_timeout.or(other.scan_timeout),
+            finish_timeout: self.finish_timeout.or(other.finish_timeout),
+            debounce: self.debounce.or(other.debounce),
+            ready_timeout: self.ready_timeout.or(other.ready_timeout),
+            error_cooldown: self.error_cooldown.or(other.error_cooldown),
+            health_interval: self.health_interval.or(other.health_interval),
+            health_failures: self.health_failures.or(other.health_failures),
+            max_stops_per_tick: self.max_stops_per_tick.or(other.max_stops_per_tick),
+            empty_confirmations: self.empty_confirmations.or(other.empty_confirmations),
+            restart: self.restart.or(other.restart),
+            restart_dependents: self.restart_dependents.or(other.restart_dependents),
+            supervise: self.supervise.or(other.supervise),
+            kill_stale: self.kill_stale.or(other.kill_stale),
+            log_mode: self.log_mode.or(other.log_mode),
+            log_dir: self.log_dir.or(other.log_dir),
+            chdir: self.chdir.or(other.chdir),
+            identity: self.identity.or(other.identity),
+            reap_orphans: self.reap_orphans.or(other.reap_orphans),
+            recursive: self.recursive.or(other.recursive),
+            process_group: self.process_group.or(other.process_group),
+            cgroup_root: self.cgroup_root.or(other.cgroup_root),
+            hash_threads: self.hash_threads.or(other.hash_threads),
+            ignore: self.ignore.or(other.ignore),
+            max_concurrent: self.max_concurrent.or(other.max_concurrent),
+            hash_alg: self.hash_alg.or(other.hash_alg),
+            hash_limit: self.hash_limit.or(other.hash_limit),
+            hash_cache: self.hash_cache.or(other.hash_cache),
+            hash_timeout: self.hash_timeout.or(other.hash_timeout),
+            watch: self.watch.or(other.watch),
+            watch_interval: self.watch_interval.or(other.watch_interval),
+            control_socket: self.control_socket.or(other.control_socket),
+            metrics_addr: self.metrics_addr.or(other.metrics_addr),
+            pidfile: self.pidfile.or(other.pidfile),
+            env_file: self.env_file.or(other.env_file),
+            state_file: self.state_file.or(other.state_file),
+            log_level: self.log_level.or(other.log_level),
+            services: other.services.into_iter().chain(self.services).collect(),
+            dir: self.dir.or(other.dir),
+        }
+    }
+}
+
+fn patterns<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Pattern>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(D::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LevelFilter>, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        config: Config,
+    }
+
+    #[test]
+    fn config_test() {
+        let file = Config::load(Path::new("test_res/config/runsvdir.toml")).unwrap();
+        assert_eq!(file.pause, Some(500));
+        assert_eq!(file.log_mode, Some(LogMode::File));
+        assert_eq!(file.hash_alg, Some(HashAlg::Sha512_256));
+        assert_eq!(file.dir, Some(PathBuf::from("/etc/service")));
+        assert_eq!(file.restart, Some(RestartPolicy::OnFailure));
+        assert_eq!(file.log_level, Some(LevelFilter::DEBUG));
+        let web = ServiceOverrides {
+            restart: Some(RestartPolicy::Never),
+            kill_timeout: Some(100),
+        };
+        assert_eq!(file.services, HashMap::from([("web".into(), web)]));
+
+        let cli = Cli::parse_from(["runsvdir", "--pause", "2000", "--reap-orphans", "/srv"]).config;
+        let config = cli.or(file);
+        assert_eq!(config.pause, Some(2000));
+        assert_eq!(config.kill_timeout, Some(3000));
+        assert_eq!(config.restart_limit, Some(5));
+        assert_eq!(config.log_mode, Some(LogMode::File));
+        assert_eq!(config.log_dir, Some(PathBuf::from("/var/log/runsvdir")));
+        assert_eq!(config.reap_orphans, Some(true));
+        assert_eq!(config.chdir, None);
+        assert_eq!(config.kill_stale, None);
+        assert_eq!(config.dir, Some(PathBuf::from("/srv")));
+        assert_eq!(config.ignore, Some(vec![Pattern::new(".*").unwrap()]));
+        assert_eq!(config.services["web"], web);
+
+        assert!(matches!(
+            Config::load(Path::new("test_res/config/non_existent.toml")),
+            Err(ConfigError::Read(..))
+        ));
+
+        let cli = Cli::parse_from(["runsvdir", "--no-kill-stale", "/srv"]).config;
+        assert_eq!(cli.kill_stale, Some(false));
+
+        // Bool flags are all set alike, a bare flag meaning true
+        let cli = Cli::parse_from(["runsvdir", "--supervise", "--chdir=false", "/srv"]).config;
+        assert_eq!(cli.supervise, Some(true));
+        assert_eq!(cli.chdir, Some(false));
+        assert_eq!(cli.dir, Some(PathBuf::from("/srv")));
+
+        let cli = Cli::parse_from([
+            "runsvdir",
+            "--restart",
+            "never",
+            "--log-level",
+            "warn",
+            "/srv",
+        ]);
+        assert_eq!(cli.config.restart, Some(RestartPolicy::Never));
+        assert_eq!(cli.config.log_level, Some(LevelFilter::WARN));
+
+        let cli = Cli::parse_from([
+            "runsvdir",
+            "--ignore",
+            "*~",
+            "--ignore",
+            "lost+found",
+            "/srv",
+        ]);
+        let ignore = cli.config.ignore.unwrap();
+        assert!(ignore[0].matches("run~") && ignore[1].matches("lost+found"));
+    }
+}
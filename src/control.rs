@@ -0,0 +1,272 @@
+use std::fmt::{Display, Formatter};
+#[cfg(unix)]
+use std::fs::remove_file;
+use std::io;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+#[cfg(unix)]
+use std::sync::mpsc::{channel, Receiver};
+#[cfg(unix)]
+use std::thread;
+#[cfg(unix)]
+use std::time::Duration;
+use thiserror::Error;
+#[cfg(unix)]
+use tracing::{error, warn};
+
+/// A command accepted on the control socket, one per line
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ControlCommand {
+    /// Terminates the service with its term signal, it gets respawned on the next step
+    Restart(String),
+    /// Sends the service SIGTERM, it gets respawned on the next step
+    Term(String),
+    /// Sends the service SIGKILL, it gets respawned on the next step
+    Kill(String),
+    /// Keeps the service stopped until `up`
+    Down(String),
+    /// Reverts `down` and `once`, respawning a completed service
+    Up(String),
+    /// Spawns the service if it is down, it completes instead of being respawned once it exits
+    Once(String),
+    /// Lists the service with the name, or all services
+    Status(Option<String>),
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum ParseCommandError {
+    #[error("Unknown command {0:?}")]
+    Unknown(String),
+    #[error("Command {0:?} needs a service name")]
+    MissingService(String),
+}
+
+impl FromStr for ControlCommand {
+    type Err = ParseCommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let mut service = || {
+            words
+                .next()
+                .map(str::to_string)
+                .ok_or_else(|| ParseCommandError::MissingService(command.into()))
+        };
+        match command {
+            "restart" => Ok(Self::Restart(service()?)),
+            "term" => Ok(Self::Term(service()?)),
+            "kill" => Ok(Self::Kill(service()?)),
+            "down" => Ok(Self::Down(service()?)),
+            "up" => Ok(Self::Up(service()?)),
+            "once" => Ok(Self::Once(service()?)),
+            "status" => Ok(Self::Status(words.next().map(str::to_string))),
+            _ => Err(ParseCommandError::Unknown(command.into())),
+        }
+    }
+}
+
+/// The line sent for the command, without the newline
+impl Display for ControlCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Restart(name) => write!(f, "restart {name}"),
+            Self::Term(name) => write!(f, "term {name}"),
+            Self::Kill(name) => write!(f, "kill {name}"),
+            Self::Down(name) => write!(f, "down {name}"),
+            Self::Up(name) => write!(f, "up {name}"),
+            Self::Once(name) => write!(f, "once {name}"),
+            Self::Status(None) => f.write_str("status"),
+            Self::Status(Some(name)) => write!(f, "status {name}"),
+        }
+    }
+}
+
+/// A command waiting to be executed by the supervisor
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply: Sender<String>,
+}
+
+impl ControlRequest {
+    pub fn reply(self, reply: String) {
+        // The client may be gone already
+        let _ = self.reply.send(reply);
+    }
+}
+
+/// Listens on a Unix domain socket and hands commands over to the supervisor
+#[cfg(unix)]
+pub struct ControlServer {
+    path: PathBuf,
+    requests: Receiver<ControlRequest>,
+}
+
+#[cfg(unix)]
+impl ControlServer {
+    pub fn bind(path: PathBuf) -> io::Result<Self> {
+        match remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        let (tx, requests) = channel();
+        thread::Builder::new()
+            .name("control".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(err) = serve(stream, &tx) {
+                                warn!("serving control client failed: {err}");
+                            }
+                        }
+                        Err(err) => error!("accepting control client failed: {err}"),
+                    }
+                }
+            })?;
+        Ok(Self { path, requests })
+    }
+
+    /// Returns the next pending request without blocking
+    pub fn try_recv(&self) -> Option<ControlRequest> {
+        self.requests.try_recv().ok()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn serve(stream: UnixStream, tx: &Sender<ControlRequest>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let reply = match line.parse() {
+        Ok(command) => {
+            let (reply, rx) = channel();
+            tx.send(ControlRequest { command, reply })
+                .ok()
+                .and_then(|()| rx.recv().ok())
+                .unwrap_or_else(|| "error: shutting down\n".into())
+        }
+        Err(err) => format!("error: {err}\n"),
+    };
+    (&stream).write_all(reply.as_bytes())
+}
+
+/// Unix domain sockets are not available, [`ControlServer::bind`] fails with
+/// [`io::ErrorKind::Unsupported`]
+#[cfg(not(unix))]
+pub struct ControlServer(());
+
+#[cfg(not(unix))]
+impl ControlServer {
+    pub fn bind(_path: PathBuf) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn try_recv(&self) -> Option<ControlRequest> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!("status\n".parse(), Ok(ControlCommand::Status(None)));
+        assert_eq!(
+            "status svc".parse(),
+            Ok(ControlCommand::Status(Some("svc".into())))
+        );
+        assert_eq!("kill svc".parse(), Ok(ControlCommand::Kill("svc".into())));
+        assert_eq!(
+            "term".parse::<ControlCommand>(),
+            Err(ParseCommandError::MissingService("term".into()))
+        );
+        assert_eq!(
+            "restart svc".parse(),
+            Ok(ControlCommand::Restart("svc".into()))
+        );
+        assert_eq!("once svc".parse(), Ok(ControlCommand::Once("svc".into())));
+        assert_eq!(
+            "down".parse::<ControlCommand>(),
+            Err(ParseCommandError::MissingService("down".into()))
+        );
+        assert_eq!(
+            "halt".parse::<ControlCommand>(),
+            Err(ParseCommandError::Unknown("halt".into()))
+        );
+    }
+
+    #[test]
+    fn display_test() {
+        for command in [
+            ControlCommand::Restart("svc".into()),
+            ControlCommand::Term("svc".into()),
+            ControlCommand::Kill("svc".into()),
+            ControlCommand::Down("svc".into()),
+            ControlCommand::Up("svc".into()),
+            ControlCommand::Once("svc".into()),
+            ControlCommand::Status(None),
+            ControlCommand::Status(Some("svc".into())),
+        ] {
+            assert_eq!(command.to_string().parse(), Ok(command));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_test() {
+        use crate::Stepper;
+        use std::io::Read;
+        use std::time::Instant;
+
+        let path = std::env::temp_dir().join(format!("runsvdir-control-{}", std::process::id()));
+        let server = ControlServer::bind(path.clone()).unwrap();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        stepper.invoke().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = UnixStream::connect(path).unwrap();
+            stream.write_all(b"status\n").unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let request = loop {
+            if let Some(request) = server.try_recv() {
+                break request;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        };
+        let reply = stepper.control(&request.command);
+        request.reply(reply);
+        let reply = client.join().unwrap();
+
+        let status = stepper.status();
+        let pid = status[0].pid.unwrap();
+        let words: Vec<_> = reply.split_whitespace().collect();
+        assert_eq!(words, ["svc", "running", &pid.to_string()]);
+
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+        let path = server.path.clone();
+        drop(server);
+        assert!(!path.exists());
+    }
+}
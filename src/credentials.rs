@@ -0,0 +1,174 @@
+use nix::errno::Errno;
+use nix::unistd::{setgid, setuid, Gid, Group, Uid, User};
+use std::ffi::CString;
+use std::fs::read_to_string;
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use thiserror::Error;
+
+/// The user and group a service runs as
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    /// The primary group, unchanged if `None`
+    pub gid: Option<u32>,
+    /// The supplementary groups, set only when running as root
+    pub groups: Vec<u32>,
+}
+
+#[derive(Debug, Error)]
+pub enum CredentialError {
+    #[error("Reading {0:?} failed: {1}")]
+    Read(String, #[source] io::Error),
+    #[error("Unknown user {0:?}")]
+    UnknownUser(String),
+    #[error("Unknown group {0:?}")]
+    UnknownGroup(String),
+    #[error("Looking up {0:?} failed: {1}")]
+    Lookup(String, #[source] nix::Error),
+}
+
+impl Credentials {
+    /// Reads `<service_dir>/uid` and `<service_dir>/gid`, numeric or names
+    ///
+    /// Returns `None` if there is no `uid` file. Without a `gid` file the primary group of the user
+    /// is used if the user is known. The supplementary groups are the primary group and, like with
+    /// `initgroups`, the groups listing a known user.
+    pub fn read(service_dir: &Path) -> Result<Option<Self>, CredentialError> {
+        let Some(user) = read_entry(service_dir, "uid")? else {
+            return Ok(None);
+        };
+        let group = read_entry(service_dir, "gid")?;
+        Self::resolve(&user, group.as_deref()).map(Some)
+    }
+
+    /// Resolves `user:group` or `user`, each numeric or a name, like the `uid` and `gid` files
+    pub fn parse(spec: &str) -> Result<Self, CredentialError> {
+        match spec.split_once(':') {
+            Some((user, group)) => Self::resolve(user, Some(group)),
+            None => Self::resolve(spec, None),
+        }
+    }
+
+    fn resolve(user: &str, group: Option<&str>) -> Result<Self, CredentialError> {
+        let user = user.to_owned();
+        let lookup = |err| CredentialError::Lookup(user.clone(), err);
+        let (uid, entry) = match user.parse() {
+            Ok(uid) => (uid, User::from_uid(Uid::from_raw(uid)).map_err(lookup)?),
+            Err(_) => {
+                let entry = User::from_name(&user)
+                    .map_err(lookup)?
+                    .ok_or_else(|| CredentialError::UnknownUser(user.clone()))?;
+                (entry.uid.as_raw(), Some(entry))
+            }
+        };
+        let primary_gid = entry.as_ref().map(|entry| entry.gid.as_raw());
+        let gid = match group {
+            Some(group) => Some(match group.parse() {
+                Ok(gid) => gid,
+                Err(_) => Group::from_name(group)
+                    .map_err(|err| CredentialError::Lookup(group.into(), err))?
+                    .ok_or_else(|| CredentialError::UnknownGroup(group.into()))?
+                    .gid
+                    .as_raw(),
+            }),
+            None => primary_gid,
+        };
+        let groups = match (&entry, gid) {
+            (Some(entry), Some(gid)) => supplementary_groups(entry, gid)
+                .map_err(|err| CredentialError::Lookup(entry.name.clone(), err))?,
+            (None, Some(gid)) => vec![gid],
+            (_, None) => vec![],
+        };
+        Ok(Self { uid, gid, groups })
+    }
+
+    /// Switches `command` to the credentials right before exec, after [`crate::Limits`]
+    pub(crate) fn apply(self, command: &mut Command) {
+        let groups = self.groups;
+        let root = Uid::effective().is_root();
+        // SAFETY: setgroups, setgid and setuid are async-signal-safe and nothing is allocated
+        unsafe {
+            command.pre_exec(move || {
+                if root {
+                    set_groups(&groups)?;
+                }
+                if let Some(gid) = self.gid {
+                    setgid(Gid::from_raw(gid))?;
+                }
+                setuid(Uid::from_raw(self.uid))?;
+                Ok(())
+            });
+        }
+    }
+}
+
+/// The groups listing `user`, starting with `gid`
+#[cfg(not(target_vendor = "apple"))]
+fn supplementary_groups(user: &User, gid: u32) -> nix::Result<Vec<u32>> {
+    let name = CString::new(user.name.as_str()).map_err(|_| nix::Error::EINVAL)?;
+    let groups = nix::unistd::getgrouplist(&name, Gid::from_raw(gid))?;
+    Ok(groups.into_iter().map(Gid::as_raw).collect())
+}
+
+/// The primary group only, the group database is not searched
+#[cfg(target_vendor = "apple")]
+fn supplementary_groups(_user: &User, gid: u32) -> nix::Result<Vec<u32>> {
+    Ok(vec![gid])
+}
+
+/// `setgroups`, which nix lacks on some platforms
+fn set_groups(groups: &[u32]) -> nix::Result<()> {
+    // SAFETY: the pointer and the length come from a slice
+    let res = unsafe { nix::libc::setgroups(groups.len() as _, groups.as_ptr()) };
+    Errno::result(res).map(drop)
+}
+
+fn read_entry(service_dir: &Path, name: &str) -> Result<Option<String>, CredentialError> {
+    match read_to_string(service_dir.join(name)) {
+        Ok(content) => Ok(Some(content.trim().into())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(CredentialError::Read(name.into(), err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_test() {
+        assert_eq!(Credentials::read(Path::new("test_res/b")).unwrap(), None);
+        let root = Credentials::read(Path::new("test_res/credentials/root"))
+            .unwrap()
+            .unwrap();
+        assert_eq!((root.uid, root.gid), (0, Some(0)));
+        assert_eq!(root.groups.first(), Some(&0));
+        assert_eq!(
+            Credentials::read(Path::new("test_res/credentials/numeric")).unwrap(),
+            Some(Credentials {
+                uid: 1234,
+                gid: Some(5678),
+                groups: vec![5678]
+            })
+        );
+        assert!(matches!(
+            Credentials::read(Path::new("test_res/credentials/unknown")),
+            Err(CredentialError::UnknownUser(user)) if user == "no-such-user"
+        ));
+        assert_eq!(
+            Credentials::parse("1234:5678").unwrap(),
+            Credentials::read(Path::new("test_res/credentials/numeric"))
+                .unwrap()
+                .unwrap()
+        );
+        assert_eq!(Credentials::parse("root").unwrap(), root);
+        assert!(matches!(
+            Credentials::parse("root:no-such-group"),
+            Err(CredentialError::UnknownGroup(group)) if group == "no-such-group"
+        ));
+    }
+}
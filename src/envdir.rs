@@ -0,0 +1,141 @@
+use std::ffi::OsString;
+use std::fs::{read, read_dir, read_to_string};
+use std::io;
+use std::io::ErrorKind;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Variables from a file of `KEY=VALUE` lines, set for every service below its `env` directory
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct EnvFile(pub Vec<(String, String)>);
+
+#[derive(Debug, Error)]
+pub enum EnvFileError {
+    #[error("Reading env file failed: {0}")]
+    Read(#[source] io::Error),
+    #[error("Line {0}: expected `KEY=VALUE`")]
+    Syntax(usize),
+}
+
+impl EnvFile {
+    /// Reads the file at `path`, failing on the first malformed line
+    ///
+    /// Empty lines and lines starting with `#` are skipped.
+    pub fn load(path: &Path) -> Result<Self, EnvFileError> {
+        read_to_string(path).map_err(EnvFileError::Read)?.parse()
+    }
+}
+
+impl FromStr for EnvFile {
+    type Err = EnvFileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut env = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+                    env.push((key.into(), value.into()));
+                }
+                _ => return Err(EnvFileError::Syntax(i + 1)),
+            }
+        }
+        Ok(Self(env))
+    }
+}
+
+/// Reads `<service_dir>/env` the way `envdir` does, `None` removes the variable
+///
+/// Each file names a variable and its first line is the value, with trailing blanks stripped and
+/// NULs turned into newlines. An empty file removes the variable. No directory means no changes.
+pub(crate) fn read_env(service_dir: &Path) -> io::Result<Vec<(OsString, Option<OsString>)>> {
+    let entries = match read_dir(service_dir.join("env")) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut env = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.as_encoded_bytes().starts_with(b".") || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let contents = read(entry.path())?;
+        if contents.is_empty() {
+            env.push((name, None));
+            continue;
+        }
+        let line = contents.split(|&b| b == b'\n').next().unwrap_or_default();
+        let line = line.trim_ascii_end();
+        let value: Vec<_> = line
+            .iter()
+            .map(|&b| if b == 0 { b'\n' } else { b })
+            .collect();
+        #[cfg(unix)]
+        let value = OsString::from_vec(value);
+        // Windows environments are UTF-16, values not in UTF-8 cannot be taken as they are
+        #[cfg(windows)]
+        let value = OsString::from(String::from_utf8_lossy(&value).into_owned());
+        env.push((name, Some(value)));
+    }
+    env.sort();
+    Ok(env)
+}
+
+/// Sets and removes the variables on top of the inherited environment
+pub(crate) fn apply_env(command: &mut Command, env: Vec<(OsString, Option<OsString>)>) {
+    for (name, value) in env {
+        match value {
+            Some(value) => command.env(name, value),
+            None => command.env_remove(name),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_env_test() {
+        assert_eq!(
+            read_env(Path::new("test_res/env/svc")).unwrap(),
+            [
+                ("EMPTY".into(), None),
+                ("FOO".into(), Some("foo bar".into()))
+            ]
+        );
+        assert!(read_env(Path::new("test_res/b")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn env_file_test() {
+        assert_eq!(
+            EnvFile::load(Path::new("test_res/config/global.env")).unwrap(),
+            EnvFile(vec![
+                ("GREETING".into(), "hello".into()),
+                ("FOO".into(), "global".into())
+            ])
+        );
+        assert_eq!(
+            "A=b=c\n".parse::<EnvFile>().unwrap(),
+            EnvFile(vec![("A".into(), "b=c".into())])
+        );
+        assert!(matches!(
+            "A=1\nB 2".parse::<EnvFile>(),
+            Err(EnvFileError::Syntax(2))
+        ));
+        assert!(matches!(
+            "=1".parse::<EnvFile>(),
+            Err(EnvFileError::Syntax(1))
+        ));
+    }
+}
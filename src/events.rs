@@ -0,0 +1,250 @@
+use crate::stepper::service_name;
+use crate::{Shash, StepError, Termination};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::mpsc::Sender;
+use tracing::info;
+
+/// A supervision event, as forwarded by [`ChannelSink`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(tag = "event", rename_all = "snake_case")
+)]
+pub enum Event {
+    Spawn {
+        shash: Shash,
+        pid: u32,
+    },
+    Exit {
+        shash: Shash,
+        #[cfg_attr(feature = "serde", serde(with = "exit_status"))]
+        status: ExitStatus,
+    },
+    Stale {
+        shash: Shash,
+    },
+    Kill {
+        shash: Shash,
+    },
+    /// The entry at `path` could not be spawned, with the error message
+    SpawnFailed {
+        path: PathBuf,
+        error: String,
+    },
+}
+
+/// Serializes an exit status as the [`Termination`] it stands for
+#[cfg(feature = "serde")]
+mod exit_status {
+    use crate::Termination;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::process::ExitStatus;
+
+    pub(super) fn serialize<S: Serializer>(status: &ExitStatus, s: S) -> Result<S::Ok, S::Error> {
+        Termination::from(*status).serialize(s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<ExitStatus, D::Error> {
+        let termination = Termination::deserialize(d)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            Ok(ExitStatus::from_raw(match termination {
+                Termination::Exited(code) => (code & 0xff) << 8,
+                Termination::Signaled(signal) => signal & 0x7f,
+            }))
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::ExitStatusExt;
+            Ok(ExitStatus::from_raw(match termination {
+                Termination::Exited(code) => code as u32,
+                Termination::Signaled(_) => 1,
+            }))
+        }
+    }
+}
+
+/// Receives supervision events from [`crate::Stepper`]
+pub trait EventSink: Send {
+    /// The service has been spawned as `pid`
+    fn on_spawn(&self, shash: &Shash, pid: u32);
+    /// The service exited
+    fn on_exit(&self, shash: &Shash, status: ExitStatus);
+    /// The service is no longer in the directory
+    fn on_stale(&self, shash: &Shash);
+    /// The service did not stop in time and got SIGKILL
+    fn on_kill(&self, shash: &Shash);
+    /// The entry at `path` could not be spawned, on every step it fails
+    fn on_spawn_failed(&self, path: &Path, err: &StepError);
+}
+
+/// Logs events with `tracing`, with the event name, the service name, the hash and the PID as
+/// fields
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TracingSink;
+
+/// The directory name of the service, for the `service` field
+fn name(shash: &Shash) -> String {
+    service_name(shash).map_or_else(String::new, |name| name.to_string_lossy().into())
+}
+
+impl EventSink for TracingSink {
+    fn on_spawn(&self, shash: &Shash, pid: u32) {
+        info!(event = "spawn", service = name(shash), %shash, pid, "spawn");
+    }
+
+    fn on_exit(&self, shash: &Shash, status: ExitStatus) {
+        let (exit_code, signal) = match Termination::from(status) {
+            Termination::Exited(code) => (Some(code), None),
+            Termination::Signaled(signal) => (None, Some(signal)),
+        };
+        let service = name(shash);
+        info!(event = "exit", service, %shash, %status, exit_code, signal, "dead");
+    }
+
+    fn on_stale(&self, shash: &Shash) {
+        info!(event = "stale", service = name(shash), %shash, "stale");
+    }
+
+    fn on_kill(&self, shash: &Shash) {
+        let service = name(shash);
+        info!(event = "kill", service, %shash, "did not stop in time, killing");
+    }
+
+    /// Left to the error log of the stepper, which holds back repeated errors
+    fn on_spawn_failed(&self, _: &Path, _: &StepError) {}
+}
+
+/// Forwards events over a channel, dropping them once the receiver is gone
+#[derive(Debug, Clone)]
+pub struct ChannelSink(pub Sender<Event>);
+
+impl ChannelSink {
+    fn send(&self, event: Event) {
+        let _ = self.0.send(event);
+    }
+}
+
+impl EventSink for ChannelSink {
+    fn on_spawn(&self, shash: &Shash, pid: u32) {
+        self.send(Event::Spawn {
+            shash: shash.clone(),
+            pid,
+        });
+    }
+
+    fn on_exit(&self, shash: &Shash, status: ExitStatus) {
+        self.send(Event::Exit {
+            shash: shash.clone(),
+            status,
+        });
+    }
+
+    fn on_stale(&self, shash: &Shash) {
+        self.send(Event::Stale {
+            shash: shash.clone(),
+        });
+    }
+
+    fn on_kill(&self, shash: &Shash) {
+        self.send(Event::Kill {
+            shash: shash.clone(),
+        });
+    }
+
+    fn on_spawn_failed(&self, path: &Path, err: &StepError) {
+        self.send(Event::SpawnFailed {
+            path: path.into(),
+            error: err.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_test() {
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let shash = Shash::try_from(Path::new("test_res/b/run")).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            TracingSink.on_spawn(&shash, 42);
+            #[cfg(unix)]
+            TracingSink.on_exit(
+                &shash,
+                std::os::unix::process::ExitStatusExt::from_raw(3 << 8),
+            );
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains(r#""message":"spawn""#), "{logs}");
+        assert!(logs.contains(r#""event":"spawn""#), "{logs}");
+        assert!(logs.contains(r#""service":"b""#), "{logs}");
+        assert!(logs.contains(r#""pid":42"#), "{logs}");
+        #[cfg(unix)]
+        assert!(logs.contains(r#""exit_code":3"#), "{logs}");
+    }
+
+    #[cfg(all(feature = "serde", unix))]
+    #[test]
+    fn event_serde_test() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let shash = Shash::try_from(Path::new("test_res/b/run")).unwrap();
+        for (status, termination) in [
+            (ExitStatus::from_raw(3 << 8), r#"{"exited":3}"#),
+            (ExitStatus::from_raw(9), r#"{"signaled":9}"#),
+        ] {
+            let event = Event::Exit {
+                shash: shash.clone(),
+                status,
+            };
+            let json = serde_json::to_string(&event).unwrap();
+            assert!(json.starts_with(r#"{"event":"exit","#), "{json}");
+            assert!(
+                json.ends_with(&format!(r#""status":{termination}}}"#)),
+                "{json}"
+            );
+            assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), event);
+        }
+        let failed = Event::SpawnFailed {
+            path: "test_res/b/run".into(),
+            error: "denied".into(),
+        };
+        let json = serde_json::to_string(&failed).unwrap();
+        assert_eq!(
+            json,
+            r#"{"event":"spawn_failed","path":"test_res/b/run","error":"denied"}"#
+        );
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), failed);
+    }
+}
@@ -1,5 +1,58 @@
+#[cfg(feature = "tokio")]
+mod async_stepper;
+#[cfg(all(feature = "cgroup", target_os = "linux"))]
+mod cgroup;
+mod clock;
+mod config;
+mod control;
+#[cfg(unix)]
+mod credentials;
+mod envdir;
+mod events;
+#[cfg(unix)]
+mod limits;
+mod log_mode;
+mod logger;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod pidfile;
+mod process;
+#[cfg(unix)]
+mod reaper;
+mod restart;
+mod sd_notify;
+mod service_config;
 mod shash;
+mod state;
+mod status;
 mod stepper;
+mod supervise;
+mod watcher;
 
-pub use shash::Shash;
-pub use stepper::{StepError, Stepper};
+#[cfg(feature = "tokio")]
+pub use async_stepper::AsyncStepper;
+#[cfg(all(feature = "cgroup", target_os = "linux"))]
+pub use cgroup::{CgroupError, CgroupSettings};
+pub use clock::{Clock, MonotonicClock, TestClock};
+pub use config::{Config, ConfigError, ServiceOverrides};
+pub use control::{ControlCommand, ControlRequest, ControlServer, ParseCommandError};
+#[cfg(unix)]
+pub use credentials::{CredentialError, Credentials};
+pub use envdir::{EnvFile, EnvFileError};
+pub use events::{ChannelSink, Event, EventSink, TracingSink};
+#[cfg(unix)]
+pub use limits::{Limits, LimitsError};
+pub use log_mode::LogMode;
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsServer;
+pub use pidfile::Pidfile;
+#[cfg(target_os = "linux")]
+pub use reaper::become_subreaper;
+pub use restart::{ParsePolicyError, RestartLimit, RestartPolicy};
+pub use sd_notify::{status_line, SdNotify};
+pub use service_config::{ServiceConfig, ServiceConfigError};
+pub use shash::{HashAlg, Identity, Shash};
+pub use state::{SavedService, SavedState};
+pub use status::{DesiredState, ServiceState, ServiceStatus, Termination};
+pub use stepper::{StepError, StepReport, Stepper, StepperBuilder};
+pub use watcher::Watcher;
@@ -0,0 +1,137 @@
+use nix::sys::resource::{setrlimit, Resource, RLIM_INFINITY};
+use std::fs::read_to_string;
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use thiserror::Error;
+
+/// Resource limits applied to a service before exec, unchanged if `None`
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Limits {
+    /// `as`, the address space in bytes
+    pub address_space: Option<u64>,
+    /// `nofile`, the number of open files
+    pub open_files: Option<u64>,
+    /// `cpu`, the CPU time in seconds
+    pub cpu: Option<u64>,
+}
+
+#[derive(Debug, Error)]
+pub enum LimitsError {
+    #[error("Reading limits failed: {0}")]
+    Read(#[source] io::Error),
+    #[error("Line {0}: expected `key value`")]
+    Syntax(usize),
+    #[error("Line {0}: unknown limit {1:?}")]
+    UnknownLimit(usize, String),
+    #[error("Line {0}: invalid value {1:?}")]
+    InvalidValue(usize, String),
+}
+
+impl Limits {
+    /// Reads `<service_dir>/limits`, one `key value` per line where the value is a number or
+    /// `unlimited`
+    ///
+    /// Returns `None` if there is no `limits` file. Empty lines and lines starting with `#` are
+    /// skipped.
+    pub fn read(service_dir: &Path) -> Result<Option<Self>, LimitsError> {
+        match read_to_string(service_dir.join("limits")) {
+            Ok(content) => content.parse().map(Some),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(LimitsError::Read(err)),
+        }
+    }
+
+    /// Takes the limits set in `self`, falling back to `other`
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            address_space: self.address_space.or(other.address_space),
+            open_files: self.open_files.or(other.open_files),
+            cpu: self.cpu.or(other.cpu),
+        }
+    }
+
+    /// Sets the soft and hard limits in the child right before exec
+    pub(crate) fn apply(self, command: &mut Command) {
+        let limits = [
+            (Resource::RLIMIT_AS, self.address_space),
+            (Resource::RLIMIT_NOFILE, self.open_files),
+            (Resource::RLIMIT_CPU, self.cpu),
+        ];
+        // SAFETY: setrlimit is async-signal-safe and nothing is allocated
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, limit) in limits {
+                    if let Some(limit) = limit {
+                        setrlimit(resource, limit, limit)?;
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+impl std::str::FromStr for Limits {
+    type Err = LimitsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = Self::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let n = i + 1;
+            let (key, value) = line
+                .split_once(char::is_whitespace)
+                .ok_or(LimitsError::Syntax(n))?;
+            let value = match value.trim() {
+                "unlimited" => RLIM_INFINITY,
+                value => value
+                    .parse()
+                    .map_err(|_| LimitsError::InvalidValue(n, value.into()))?,
+            };
+            let limit = match key {
+                "as" => &mut limits.address_space,
+                "nofile" => &mut limits.open_files,
+                "cpu" => &mut limits.cpu,
+                _ => return Err(LimitsError::UnknownLimit(n, key.into())),
+            };
+            *limit = Some(value);
+        }
+        Ok(limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_test() {
+        assert_eq!(Limits::read(Path::new("test_res/b")).unwrap(), None);
+        assert_eq!(
+            Limits::read(Path::new("test_res/limits/svc")).unwrap(),
+            Some(Limits {
+                address_space: Some(1 << 30),
+                open_files: Some(256),
+                cpu: Some(RLIM_INFINITY),
+            })
+        );
+        assert!(matches!(
+            "nofile".parse::<Limits>(),
+            Err(LimitsError::Syntax(1))
+        ));
+        assert!(matches!(
+            "\nstack 10".parse::<Limits>(),
+            Err(LimitsError::UnknownLimit(2, key)) if key == "stack"
+        ));
+        assert!(matches!(
+            "cpu lots".parse::<Limits>(),
+            Err(LimitsError::InvalidValue(1, value)) if value == "lots"
+        ));
+    }
+}
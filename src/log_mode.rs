@@ -0,0 +1,189 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::thread;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{dispatcher, info, warn, Dispatch, Level};
+
+/// Where the stdout and stderr of services go
+///
+/// The stdout of a service with an executable `log/run` goes to that logger in any mode.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogMode {
+    /// Discard the output
+    #[default]
+    Null,
+    /// Share the supervisor's stdout and stderr
+    Inherit,
+    /// Append the output to a per-service `current` file
+    File,
+    /// Like `File`, with each line prefixed by an RFC 3339 timestamp and the service name
+    Timestamped,
+    /// Forward each line to the supervisor's own log with the service name as a field, stdout at
+    /// the info and stderr at the warn level
+    Tracing,
+}
+
+impl LogMode {
+    /// Builds the stdout and stderr of the service living in `service_dir`
+    pub(crate) fn stdio(
+        self,
+        log_dir: Option<&Path>,
+        service_dir: &Path,
+    ) -> io::Result<(Stdio, Stdio)> {
+        match self {
+            Self::Null => Ok((Stdio::null(), Stdio::null())),
+            Self::Inherit => Ok((Stdio::inherit(), Stdio::inherit())),
+            Self::File => {
+                let file = open_log(log_dir, service_dir)?;
+                Ok((file.try_clone()?.into(), file.into()))
+            }
+            Self::Timestamped | Self::Tracing => Ok((Stdio::piped(), Stdio::piped())),
+        }
+    }
+
+    /// Starts the threads writing the piped output of a child to the log in `Timestamped` mode
+    /// or to `tracing` in `Tracing` mode
+    ///
+    /// The threads exit once every holder of the pipes, the child and anything it spawned, is
+    /// gone.
+    pub(crate) fn capture(
+        self,
+        log_dir: Option<&Path>,
+        service_dir: &Path,
+        stdout: Option<impl Read + Send + 'static>,
+        stderr: Option<impl Read + Send + 'static>,
+    ) -> io::Result<()> {
+        let name = service_dir
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        if self == Self::Tracing {
+            if let Some(stdout) = stdout {
+                trace(stdout, name.clone(), Level::INFO)?;
+            }
+            if let Some(stderr) = stderr {
+                trace(stderr, name, Level::WARN)?;
+            }
+            return Ok(());
+        }
+        if self != Self::Timestamped {
+            return Ok(());
+        }
+        let file = open_log(log_dir, service_dir)?;
+        if let Some(stdout) = stdout {
+            stamp(stdout, file.try_clone()?, name.clone())?;
+        }
+        if let Some(stderr) = stderr {
+            stamp(stderr, file, name)?;
+        }
+        Ok(())
+    }
+}
+
+fn open_log(log_dir: Option<&Path>, service_dir: &Path) -> io::Result<File> {
+    let path = log_file(log_dir, service_dir);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Copies `output` line by line to `file`, each line prefixed by the time and `name`
+fn stamp(output: impl Read + Send + 'static, mut file: File, name: String) -> io::Result<()> {
+    forward(output, name, move |name, line| {
+        let now = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let mut record = format!("{now} {name}: ").into_bytes();
+        record.extend_from_slice(line);
+        // A single write per line keeps the lines of stdout and stderr apart
+        file.write_all(&record)
+            .inspect_err(|err| warn!("writing log of {name} failed: {err}"))
+    })
+}
+
+/// Logs `output` line by line at `level`, with `name` as the `service` field
+fn trace(output: impl Read + Send + 'static, name: String, level: Level) -> io::Result<()> {
+    forward(output, name, move |name, line| {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\n');
+        if level == Level::WARN {
+            warn!(service = name, "{line}");
+        } else {
+            info!(service = name, "{line}");
+        }
+        Ok(())
+    })
+}
+
+/// Hands each line of `output` ending with a newline to `write` on a thread of its own, until
+/// the output ends or `write` fails
+///
+/// The thread logs to the subscriber of the caller.
+fn forward(
+    output: impl Read + Send + 'static,
+    name: String,
+    mut write: impl FnMut(&str, &[u8]) -> io::Result<()> + Send + 'static,
+) -> io::Result<()> {
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    thread::Builder::new()
+        .name(format!("log-{name}"))
+        .spawn(move || {
+            let _guard = dispatcher::set_default(&dispatch);
+            let mut output = BufReader::new(output);
+            let mut line = vec![];
+            loop {
+                line.clear();
+                match output.read_until(b'\n', &mut line) {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        warn!("reading output of {name} failed: {err}");
+                        break;
+                    }
+                }
+                if !line.ends_with(b"\n") {
+                    line.push(b'\n');
+                }
+                if write(&name, &line).is_err() {
+                    break;
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// `<log_dir>/<service>/current` if `log_dir` is set, `<service_dir>/log/current` otherwise
+pub(crate) fn log_file(log_dir: Option<&Path>, service_dir: &Path) -> PathBuf {
+    let mut path = match (log_dir, service_dir.file_name()) {
+        (Some(log_dir), Some(name)) => log_dir.join(name),
+        _ => service_dir.join("log"),
+    };
+    path.push("current");
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_file_test() {
+        let service_dir = Path::new("test_res/b");
+        assert_eq!(
+            log_file(None, service_dir),
+            PathBuf::from("test_res/b/log/current")
+        );
+        assert_eq!(
+            log_file(Some(Path::new("/var/log/sv")), service_dir),
+            PathBuf::from("/var/log/sv/b/current")
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use crate::process::{is_executable, run_script, terminate, ProcessGroup, Signal};
+use crate::Shash;
+use std::fs::metadata;
+use std::io;
+use std::io::{pipe, PipeReader, PipeWriter};
+use std::path::{absolute, Path, PathBuf};
+#[cfg(unix)]
+use std::process::ExitStatus;
+use std::process::{Child, Command, Stdio};
+use tracing::info;
+
+/// The `log/run` process of a service, reading the stdout of the service from a pipe
+///
+/// The supervisor holds both ends of the pipe, so restarting the service or the logger loses
+/// neither the pipe nor the output buffered in it.
+pub(crate) struct Logger {
+    hash: Shash,
+    child: Option<Child>,
+    reader: PipeReader,
+    writer: PipeWriter,
+}
+
+impl Logger {
+    /// The `log` directory of the service in `service_dir` if it has an executable `run`
+    pub(crate) fn dir(service_dir: &Path) -> Option<PathBuf> {
+        let dir = service_dir.join("log");
+        metadata(run_script(&dir))
+            .is_ok_and(|m| is_executable(&m))
+            .then_some(dir)
+    }
+
+    pub(crate) fn new(dir: &Path) -> io::Result<Self> {
+        let (reader, writer) = pipe()?;
+        Ok(Self {
+            hash: Shash::try_from(run_script(dir).as_path())?,
+            child: None,
+            reader,
+            writer,
+        })
+    }
+
+    /// The PID of the running logger
+    #[cfg(all(test, unix))]
+    pub(crate) fn pid(&self) -> Option<u32> {
+        self.child.as_ref().map(Child::id)
+    }
+
+    /// The stdout of a service writing to the logger
+    pub(crate) fn stdout(&self) -> io::Result<Stdio> {
+        Ok(self.writer.try_clone()?.into())
+    }
+
+    /// Spawns the logger from its `log` directory unless it is running
+    pub(crate) fn keep_running(&mut self, group: ProcessGroup) -> io::Result<()> {
+        if let Some(child) = &mut self.child {
+            match child.try_wait()? {
+                None => return Ok(()),
+                Some(status) => info!("logger {} exited with {status}", self.hash),
+            }
+        }
+        let run = absolute(self.hash.path())?;
+        let mut command = Command::new(&run);
+        if let Some(dir) = run.parent() {
+            command.current_dir(dir);
+        }
+        command.stdin(self.reader.try_clone()?);
+        group.configure(&mut command);
+        let child = command.spawn()?;
+        info!("logger {} spawned as {}", self.hash, child.id());
+        self.child = Some(child);
+        Ok(())
+    }
+
+    /// Takes the status of `pid` reaped outside of [`Child::try_wait`], returning whether it was
+    /// the logger, which is respawned by the next [`Logger::keep_running`]
+    #[cfg(unix)]
+    pub(crate) fn reaped(&mut self, pid: u32, status: ExitStatus) -> bool {
+        if self.child.as_ref().is_none_or(|child| child.id() != pid) {
+            return false;
+        }
+        info!("logger {} exited with {status}", self.hash);
+        self.child = None;
+        true
+    }
+
+    /// Sends the logger SIGTERM, returning the process to wait for
+    pub(crate) fn stop(self, group: ProcessGroup) -> Option<(Shash, Child)> {
+        let child = self.child?;
+        terminate(&self.hash, child.id(), Signal::SIGTERM, group);
+        Some((self.hash, child))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dir_test() {
+        assert_eq!(
+            Logger::dir(Path::new("test_res/logger/svc")),
+            Some(PathBuf::from("test_res/logger/svc/log"))
+        );
+        assert_eq!(Logger::dir(Path::new("test_res/b")), None);
+    }
+}
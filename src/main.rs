@@ -1,41 +1,434 @@
-use clap::Parser;
-use runsvdir::Stepper;
+use clap::{Parser, ValueEnum};
+#[cfg(unix)]
+use nix::libc::c_int;
+#[cfg(unix)]
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+#[cfg(feature = "metrics")]
+use runsvdir::MetricsServer;
+use runsvdir::{
+    status_line, Config, ConfigError, ControlServer, EnvFile, Pidfile, RestartLimit, SavedState,
+    SdNotify, Stepper, Watcher,
+};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{io, thread};
-use tracing::error;
+use tracing::{error, info, warn};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// The number of millis to wait between each check
-    #[clap(short, long, default_value = "1000")]
-    pause: u64,
-    /// The directory to store process states
-    dir: PathBuf,
+    /// A TOML file with defaults for the options below, which take precedence over it
+    #[clap(long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    settings: Config,
+    /// The format of the supervisor's own log records
+    #[clap(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+    /// Whether to run a single step and exit leaving the services running, failing if any service
+    /// failed to spawn
+    #[clap(long)]
+    oneshot: bool,
+    /// Whether to run a single step logging the services which would be spawned or stopped,
+    /// without touching any process
+    #[clap(long)]
+    dry_run: bool,
 }
 
-fn main() {
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
+    // Loaded ahead of the subscriber for the log level, errors are logged once it is set up
+    let loaded = load(&args);
 
-    FmtSubscriber::builder()
+    let level = loaded.as_ref().ok().and_then(|config| config.log_level);
+    let subscriber = FmtSubscriber::builder()
         .with_env_filter(
             EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
+                .with_default_directive(level.unwrap_or(LevelFilter::INFO).into())
                 .from_env_lossy(),
         )
-        .with_writer(io::stderr)
-        .init();
+        .with_writer(io::stderr);
+    match args.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    let config = match loaded {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(dir) = config.dir.clone() else {
+        error!("no service directory given");
+        return ExitCode::FAILURE;
+    };
+
+    let mut pause = configured_pause(&config);
+    let mut watch_interval = configured_watch_interval(&config);
+    let mut watcher = config
+        .watch
+        .unwrap_or_default()
+        .then(|| {
+            Watcher::new(&dir)
+                .inspect_err(|err| warn!("watching {dir:?} failed, polling: {err}"))
+                .ok()
+        })
+        .flatten();
+    let env_file = match &config.env_file {
+        None => EnvFile::default(),
+        Some(path) => match EnvFile::load(path) {
+            Ok(env_file) => env_file,
+            Err(err) => {
+                error!("loading {path:?} failed: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+    let mut stepper = Stepper::builder(dir)
+        .identity(config.identity.unwrap_or_default())
+        .env_file(env_file)
+        .dry_run(args.dry_run)
+        .reap_orphans(config.reap_orphans.unwrap_or(std::process::id() == 1))
+        .build();
+    configure(&mut stepper, &config);
+    // Once for the process rather than on every reload, orphans keep coming to it until it exits
+    #[cfg(target_os = "linux")]
+    if stepper.reap_orphans {
+        if let Err(err) = runsvdir::become_subreaper() {
+            warn!("becoming a subreaper failed, orphans of services go to PID 1: {err}");
+        }
+    }
+    if let Some(path) = &config.state_file {
+        stepper.restore_state(SavedState::load(path));
+    }
+
+    if args.oneshot || args.dry_run {
+        return oneshot(&mut stepper);
+    }
+
+    let _pidfile = match config.pidfile.map(Pidfile::create).transpose() {
+        Ok(pidfile) => pidfile,
+        Err(err) => {
+            error!("writing pidfile failed: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let control = config.control_socket.and_then(|path| {
+        ControlServer::bind(path.clone())
+            .inspect_err(|err| error!("binding control socket {path:?} failed: {err}"))
+            .ok()
+    });
+
+    #[cfg(feature = "metrics")]
+    let metrics = config.metrics_addr.and_then(|addr| {
+        MetricsServer::bind(addr)
+            .inspect_err(|err| error!("binding metrics server {addr} failed: {err}"))
+            .ok()
+    });
+    #[cfg(not(feature = "metrics"))]
+    if let Some(addr) = config.metrics_addr {
+        warn!("not serving metrics on {addr}, built without the metrics feature");
+    }
+
+    let mut notify = SdNotify::from_env()
+        .inspect_err(|err| warn!("connecting to NOTIFY_SOCKET failed: {err}"))
+        .ok()
+        .flatten();
+    let mut ready = false;
+    let mut saved = SavedState::default();
+
+    install_signal_handlers();
+
+    while !TERMINATE.load(Ordering::Relaxed) {
+        if RESCAN.swap(false, Ordering::Relaxed) {
+            info!("rescanning");
+            if args.config.is_some() {
+                match load(&args) {
+                    Ok(config) => {
+                        pause = configured_pause(&config);
+                        watch_interval = configured_watch_interval(&config);
+                        configure(&mut stepper, &config);
+                    }
+                    Err(err) => error!("reloading failed, keeping the old config: {err}"),
+                }
+            }
+        }
+        match stepper.invoke() {
+            Ok(_) if !ready => {
+                ready = true;
+                if let Some(notify) = &notify {
+                    notified(notify.ready());
+                }
+            }
+            Ok(_) => {}
+            Err(err) => error!("step failed: {err}"),
+        }
+        if let Some(notify) = &mut notify {
+            notified(notify.watchdog());
+            notified(notify.status(status_line(&stepper.status())));
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &metrics {
+            metrics.update(stepper.metrics());
+        }
+        // Kept current so a supervisor which died adopts the services still running
+        if let Some(path) = &config.state_file {
+            let state = stepper.saved_state();
+            if state != saved {
+                if let Err(err) = state.save(path) {
+                    error!("saving state to {path:?} failed: {err}");
+                }
+                saved = state;
+            }
+        }
+        let pause = match (&watcher, stepper.next_step()) {
+            // Changes wake the watcher and exits raise SIGCHLD, only deadlines need a timeout
+            (Some(_), None) => watch_interval,
+            (Some(_), Some(at)) => match at.saturating_duration_since(Instant::now()) {
+                Duration::ZERO => pause,
+                left => left.min(watch_interval),
+            },
+            (None, _) => stepper.next_check().map_or(pause, |at| {
+                pause.min(at.saturating_duration_since(Instant::now()))
+            }),
+        };
+        let pause = notify
+            .as_ref()
+            .and_then(SdNotify::watchdog_interval)
+            .map_or(pause, |interval| pause.min(interval));
+        sleep(pause, |slice| {
+            let handled = control
+                .as_ref()
+                .is_some_and(|control| serve_control(control, &mut stepper));
+            handled || wait(watcher.as_mut(), slice)
+        });
+    }
+
+    info!("shutting down");
+    if let Some(notify) = &notify {
+        notified(notify.stopping());
+    }
+    if let Some(path) = &config.state_file {
+        if let Err(err) = stepper.saved_state().save(path) {
+            error!("saving state to {path:?} failed: {err}");
+        }
+    }
+    stepper.shutdown();
+    ExitCode::SUCCESS
+}
+
+/// Merges the command line over the config file, if any
+fn load(args: &Args) -> Result<Config, ConfigError> {
+    match &args.config {
+        Some(path) => Ok(args.settings.clone().or(Config::load(path)?)),
+        None => Ok(args.settings.clone()),
+    }
+}
+
+fn configured_pause(config: &Config) -> Duration {
+    Duration::from_millis(config.pause.unwrap_or(1000))
+}
+
+fn configured_watch_interval(config: &Config) -> Duration {
+    Duration::from_millis(config.watch_interval.unwrap_or(60000))
+}
 
-    let pause = Duration::from_millis(args.pause);
-    let mut stepper = Stepper::new(args.dir);
+/// Applies the settings which can change while running, the directory, the identity and orphan
+/// reaping are fixed
+fn configure(stepper: &mut Stepper, config: &Config) {
+    let millis = |millis: Option<u64>, default| Duration::from_millis(millis.unwrap_or(default));
+    stepper.backoff_base = millis(config.backoff_base, 1000);
+    stepper.backoff_cap = millis(config.backoff_cap, 60000);
+    stepper.min_uptime = millis(config.min_uptime, 0);
+    stepper.shutdown_timeout = millis(config.shutdown_timeout, 7000);
+    stepper.kill_timeout = millis(config.kill_timeout, 7000);
+    stepper.finish_timeout = millis(config.finish_timeout, 5000);
+    stepper.scan_timeout = Some(millis(config.scan_timeout, 10000));
+    stepper.debounce = millis(config.debounce, 0);
+    stepper.ready_timeout = millis(config.ready_timeout, 10000);
+    stepper.error_cooldown = millis(config.error_cooldown, 60000);
+    stepper.health_interval = millis(config.health_interval, 10000);
+    stepper.health_failures = config.health_failures.unwrap_or(3);
+    stepper.kill_stale = config.kill_stale.unwrap_or(true);
+    stepper.log_mode = config.log_mode.unwrap_or_default();
+    stepper.log_dir.clone_from(&config.log_dir);
+    stepper.chdir = config.chdir.unwrap_or(true);
+    stepper.recursive = config.recursive.unwrap_or_default();
+    stepper.process_group = config.process_group.unwrap_or(true);
+    stepper.ignore = config.ignore.clone().unwrap_or_default();
+    stepper.cgroup_root.clone_from(&config.cgroup_root);
+    stepper.max_concurrent = config.max_concurrent;
+    stepper.max_stops = config.max_stops_per_tick;
+    stepper.empty_confirmations = config.empty_confirmations.unwrap_or_default();
+    stepper.restart_policy = config.restart.unwrap_or_default();
+    stepper.overrides.clone_from(&config.services);
+    stepper.restart_limit = config
+        .restart_limit
+        .filter(|&exits| exits > 0)
+        .map(|exits| RestartLimit {
+            exits,
+            window: millis(config.restart_window, 60000),
+        });
+    stepper.restart_dependents = config.restart_dependents.unwrap_or_default();
+    stepper.supervise = config.supervise.unwrap_or_default();
+    stepper.hash_alg = config.hash_alg.unwrap_or_default();
+    stepper.hash_limit = config.hash_limit;
+    stepper.hash_cache = config.hash_cache.unwrap_or(true);
+    stepper.hash_timeout = config.hash_timeout.map(Duration::from_millis);
+    if let Some(hash_threads) = config.hash_threads {
+        stepper.hash_threads = hash_threads;
+    }
+}
 
-    loop {
-        if let Err(err) = stepper.invoke() {
+fn oneshot(stepper: &mut Stepper) -> ExitCode {
+    match stepper.invoke() {
+        Ok(report) => {
+            for hash in &report.spawned {
+                info!("started {hash}");
+            }
+            if report.failures == 0 {
+                ExitCode::SUCCESS
+            } else {
+                error!("{} services failed to spawn", report.failures);
+                ExitCode::FAILURE
+            }
+        }
+        Err(err) => {
             error!("step failed: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Logs a failed notification to systemd, the supervisor goes on without it
+fn notified(result: io::Result<()>) {
+    if let Err(err) = result {
+        warn!("notifying systemd failed: {err}");
+    }
+}
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+static RESCAN: AtomicBool = AtomicBool::new(false);
+/// Set when a child exited, so a watching supervisor steps without waiting for the interval
+static CHILD: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_terminate(_: c_int) {
+    TERMINATE.store(true, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+extern "C" fn on_hangup(_: c_int) {
+    RESCAN.store(true, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+extern "C" fn on_child(_: c_int) {
+    CHILD.store(true, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+fn install_signal_handlers() {
+    let terminate = SigAction::new(
+        SigHandler::Handler(on_terminate),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    let hangup = SigAction::new(
+        SigHandler::Handler(on_hangup),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    let child = SigAction::new(
+        SigHandler::Handler(on_child),
+        SaFlags::SA_RESTART | SaFlags::SA_NOCLDSTOP,
+        SigSet::empty(),
+    );
+    for (signal, action) in [
+        (Signal::SIGINT, &terminate),
+        (Signal::SIGTERM, &terminate),
+        (Signal::SIGHUP, &hangup),
+        (Signal::SIGCHLD, &child),
+    ] {
+        // SAFETY: the handlers only store to an atomic
+        if let Err(err) = unsafe { sigaction(signal, action) } {
+            error!("installing {signal} handler failed: {err}");
         }
-        thread::sleep(pause);
     }
 }
+
+/// Terminates on Ctrl-C, Ctrl-Break and console close, there is no SIGHUP to rescan on
+#[cfg(windows)]
+fn install_signal_handlers() {
+    use windows_sys::Win32::Foundation::BOOL;
+    use windows_sys::Win32::System::Console::SetConsoleCtrlHandler;
+
+    unsafe extern "system" fn on_ctrl(_: u32) -> BOOL {
+        TERMINATE.store(true, Ordering::Relaxed);
+        1
+    }
+
+    // SAFETY: the handler only stores to an atomic
+    if unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) } == 0 {
+        error!(
+            "installing console handler failed: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Sleeps for `pause` in short slices, waking up early when termination or a rescan is requested,
+/// a child exited or `wait` returns `true`
+fn sleep(pause: Duration, mut wait: impl FnMut(Duration) -> bool) {
+    const SLICE: Duration = Duration::from_millis(50);
+    let deadline = Instant::now() + pause;
+    while !TERMINATE.load(Ordering::Relaxed) && !RESCAN.load(Ordering::Relaxed) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || CHILD.swap(false, Ordering::Relaxed) || wait(left.min(SLICE)) {
+            break;
+        }
+    }
+}
+
+/// Waits for `slice`, returning whether the watched directory changed
+fn wait(watcher: Option<&mut Watcher>, slice: Duration) -> bool {
+    match watcher.map(|w| w.wait(slice)) {
+        None => {
+            thread::sleep(slice);
+            false
+        }
+        Some(Ok(changed)) => changed,
+        Some(Err(err)) => {
+            error!("watching failed: {err}");
+            thread::sleep(slice);
+            false
+        }
+    }
+}
+
+/// Executes pending control commands, returning whether there were any
+fn serve_control(control: &ControlServer, stepper: &mut Stepper) -> bool {
+    let mut handled = false;
+    while let Some(request) = control.try_recv() {
+        let reply = stepper.control(&request.command);
+        request.reply(reply);
+        handled = true;
+    }
+    handled
+}
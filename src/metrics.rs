@@ -0,0 +1,248 @@
+//! A Prometheus exporter serving the text exposition format over plain HTTP.
+//!
+//! Every series is labelled with the directory name of its service. While a changed `run` replaces
+//! a running service both share the name, the one with a live process is exported then.
+
+use crate::stepper::service_name;
+use crate::{ServiceStatus, Termination};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// Serves the metrics last passed to [`MetricsServer::update`] on `GET /metrics`
+pub struct MetricsServer {
+    addr: SocketAddr,
+    metrics: Arc<Mutex<String>>,
+}
+
+impl MetricsServer {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let metrics = Arc::new(Mutex::new(String::new()));
+        thread::Builder::new().name("metrics".into()).spawn({
+            let metrics = metrics.clone();
+            move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(err) = serve(stream, &metrics) {
+                                warn!("serving metrics client failed: {err}");
+                            }
+                        }
+                        Err(err) => error!("accepting metrics client failed: {err}"),
+                    }
+                }
+            }
+        })?;
+        Ok(Self { addr, metrics })
+    }
+
+    /// The address listened on, with the port picked if 0 was asked for
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Replaces the metrics served
+    pub fn update(&self, metrics: String) {
+        *self.metrics.lock().unwrap_or_else(|err| err.into_inner()) = metrics;
+    }
+}
+
+fn serve(stream: TcpStream, metrics: &Mutex<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are of no interest, but are read to not reset the connection of the client
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => (
+            "200 OK",
+            metrics
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .clone(),
+        ),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    (&stream).write_all(response.as_bytes())
+}
+
+/// Renders the metrics of the services, the spawn errors counted for their directories and the
+/// duration of the last step
+pub(crate) fn render(
+    status: &[ServiceStatus],
+    spawn_errors: &HashMap<PathBuf, u64>,
+    last_step: Duration,
+) -> String {
+    let mut services: BTreeMap<String, &ServiceStatus> = BTreeMap::new();
+    for status in status {
+        let Some(name) = service_name(&status.shash) else {
+            continue;
+        };
+        let name = name.to_string_lossy().into_owned();
+        match services.get(&name) {
+            Some(other) if other.pid.is_some() => {}
+            _ => {
+                services.insert(name, status);
+            }
+        }
+    }
+    let errors: BTreeMap<_, _> = spawn_errors
+        .iter()
+        .filter_map(|(dir, count)| Some((dir.file_name()?.to_string_lossy(), *count)))
+        .collect();
+
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(&str, String)>| {
+        let _ = writeln!(out, "# HELP runsvdir_{name} {help}");
+        let _ = writeln!(out, "# TYPE runsvdir_{name} {kind}");
+        for (service, value) in samples {
+            let _ = writeln!(
+                out,
+                "runsvdir_{name}{{service=\"{}\"}} {value}",
+                escape(service)
+            );
+        }
+    };
+    let samples = |value: fn(&ServiceStatus) -> Option<String>| {
+        services
+            .iter()
+            .filter_map(|(name, status)| Some((name.as_str(), value(status)?)))
+            .collect()
+    };
+    family(
+        "up",
+        "gauge",
+        "Whether the service has a live process",
+        samples(|s| Some(u8::from(s.pid.is_some()).to_string())),
+    );
+    family(
+        "restarts_total",
+        "counter",
+        "How many times the service has been respawned",
+        samples(|s| Some(s.restarts.to_string())),
+    );
+    family(
+        "uptime_seconds",
+        "gauge",
+        "How long the live process of the service has been up",
+        samples(|s| Some(s.uptime?.as_secs_f64().to_string())),
+    );
+    family(
+        "last_exit_code",
+        "gauge",
+        "The exit code of the last process of the service, -1 if it was killed by a signal",
+        samples(|s| {
+            Some(match s.last_exit? {
+                Termination::Exited(code) => code.to_string(),
+                Termination::Signaled(_) => "-1".into(),
+            })
+        }),
+    );
+    family(
+        "spawn_errors_total",
+        "counter",
+        "How many steps failed to spawn the service",
+        errors
+            .iter()
+            .map(|(name, count)| (name.as_ref(), count.to_string()))
+            .collect(),
+    );
+    let _ = writeln!(
+        out,
+        "# HELP runsvdir_step_seconds How long the last step took"
+    );
+    let _ = writeln!(out, "# TYPE runsvdir_step_seconds gauge");
+    let _ = writeln!(out, "runsvdir_step_seconds {}", last_step.as_secs_f64());
+    out
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DesiredState, ServiceState, Shash};
+    use std::io::Read;
+    use std::path::Path;
+
+    fn status(run: &str, pid: Option<u32>) -> ServiceStatus {
+        ServiceStatus {
+            shash: Shash::try_from(Path::new(run)).unwrap(),
+            pid,
+            state: ServiceState::Running,
+            uptime: pid.map(|_| Duration::from_millis(1500)),
+            restarts: 2,
+            failed_starts: 0,
+            check_failures: 0,
+            last_exit: Some(Termination::Exited(3)),
+            desired: DesiredState::Up,
+        }
+    }
+
+    #[test]
+    fn render_test() {
+        let metrics = render(
+            &[
+                status("test_res/b/run", Some(42)),
+                status("test_res/d/run", None),
+            ],
+            &HashMap::from([(PathBuf::from("test_res/d"), 5)]),
+            Duration::from_millis(250),
+        );
+        for line in [
+            "# TYPE runsvdir_up gauge",
+            "runsvdir_up{service=\"b\"} 1",
+            "runsvdir_up{service=\"d\"} 0",
+            "runsvdir_restarts_total{service=\"b\"} 2",
+            "runsvdir_uptime_seconds{service=\"b\"} 1.5",
+            "runsvdir_last_exit_code{service=\"d\"} 3",
+            "runsvdir_spawn_errors_total{service=\"d\"} 5",
+            "runsvdir_step_seconds 0.25",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "{line} in {metrics}");
+        }
+        assert!(!metrics.contains("runsvdir_uptime_seconds{service=\"d\"}"));
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+
+    #[test]
+    fn server_test() {
+        let server = MetricsServer::bind("127.0.0.1:0").unwrap();
+        server.update("runsvdir_up{service=\"b\"} 1\n".into());
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nrunsvdir_up{service=\"b\"} 1\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
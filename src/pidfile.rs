@@ -0,0 +1,113 @@
+use crate::process::is_alive;
+use std::fs::{read_to_string, remove_file, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Holds the PID of the supervisor in a file, removing it on drop
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Writes the PID of this process to `path`, replacing a file left behind by a dead process
+    ///
+    /// Fails with [`ErrorKind::AlreadyExists`] if the file names another live process. A file
+    /// naming this process is stale too, as a container restarted with a kept `/run` gets the same
+    /// PID again.
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                if let Some(pid) = live_pid(&path) {
+                    return Err(io::Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("{path:?} names running process {pid}"),
+                    ));
+                }
+                remove_file(&path)?;
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)?
+            }
+            file => file?,
+        };
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { path })
+    }
+}
+
+/// Reads the PID in `path` if it names a live process other than this one
+fn live_pid(path: &Path) -> Option<u32> {
+    let pid = read_to_string(path).ok()?.trim().parse().ok()?;
+    (is_alive(pid) && pid != std::process::id()).then_some(pid)
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pidfile_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("runsvdir-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn pidfile_test() {
+        let path = pidfile_path("pidfile");
+        // A stale file naming a process which cannot exist
+        std::fs::write(&path, "2147483647\n").unwrap();
+        let pidfile = Pidfile::create(path.clone()).unwrap();
+        assert_eq!(
+            read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn own_pid_test() {
+        let path = pidfile_path("own-pid");
+        std::fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        let pidfile = Pidfile::create(path.clone()).unwrap();
+        assert_eq!(
+            read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn live_pid_test() {
+        let path = pidfile_path("live-pid");
+        #[cfg(unix)]
+        let mut child = std::process::Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .unwrap();
+        #[cfg(windows)]
+        let mut child = std::process::Command::new("ping")
+            .args(["-n", "60", "127.0.0.1"])
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        std::fs::write(&path, format!("{}\n", child.id())).unwrap();
+        assert_eq!(
+            Pidfile::create(path.clone()).unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+        // Left to the process it names
+        assert!(path.exists());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        remove_file(&path).unwrap();
+    }
+}
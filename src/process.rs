@@ -0,0 +1,321 @@
+//! Spawning services in their own process group and stopping them.
+//!
+//! On Unix a group is a process group, signalled as a whole. Windows has no signals: a group is a
+//! console process group asked to stop with CTRL_BREAK, and SIGKILL, or any signal to a service
+//! outside of its own group, calls `TerminateProcess` on the service alone.
+//!
+//! Everything else Unix-specific stays Unix-only: `uid`, `gid`, `limits`, `term-signal`,
+//! `reload-signal` and `notification-fd` files as well as `user`, `nice` and `umask` in
+//! `service.toml` are ignored on Windows, finish scripts always get signal 0, orphans are not
+//! reaped, there is no control socket and no SIGHUP to rescan on. A `run.cmd` batch script takes
+//! the place of `run` on Windows when both are there.
+
+use crate::Shash;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::thread;
+use std::time::Duration;
+use tracing::error;
+
+#[cfg(unix)]
+pub(crate) use nix::sys::signal::Signal;
+
+/// The signals the supervisor sends, both terminate the process on Windows
+#[cfg(windows)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Signal {
+    SIGTERM,
+    SIGKILL,
+}
+
+/// Whom a signal to a service reaches
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum ProcessGroup {
+    /// The service alone, it shares the group of the supervisor
+    Shared,
+    /// The service and everything it spawned, it leads a group of its own
+    Own,
+}
+
+impl ProcessGroup {
+    pub(crate) fn new(own: bool) -> Self {
+        if own {
+            Self::Own
+        } else {
+            Self::Shared
+        }
+    }
+
+    /// Makes `command` spawn a group leader if the group is `Own`
+    pub(crate) fn configure(self, command: &mut Command) {
+        if self == Self::Shared {
+            return;
+        }
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(command, 0);
+        #[cfg(windows)]
+        std::os::windows::process::CommandExt::creation_flags(
+            command,
+            windows_sys::Win32::System::Threading::CREATE_NEW_PROCESS_GROUP,
+        );
+    }
+}
+
+/// A supervised process, spawned by this supervisor or adopted from an earlier instance of it
+pub(crate) enum Process {
+    Child(Child),
+    /// A process which is not a child, told from a later one with the same PID by its start time
+    ///
+    /// Its exit status is lost unless it gets reaped as an orphan, it reports exiting with 255.
+    Adopted {
+        pid: u32,
+        started: u64,
+    },
+}
+
+impl Process {
+    /// Takes up the process `pid` if it still is the one which was started at `started`
+    pub(crate) fn adopt(pid: u32, started: u64) -> Option<Self> {
+        (start_time(pid) == Some(started)).then_some(Self::Adopted { pid, started })
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        match self {
+            Self::Child(child) => child.id(),
+            Self::Adopted { pid, .. } => *pid,
+        }
+    }
+
+    /// When the process started, in clock ticks since boot, `None` if it is unknown
+    pub(crate) fn started(&self) -> Option<u64> {
+        match self {
+            Self::Child(child) => start_time(child.id()),
+            Self::Adopted { started, .. } => Some(*started),
+        }
+    }
+
+    pub(crate) fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        match self {
+            Self::Child(child) => child.try_wait(),
+            Self::Adopted { pid, started } => {
+                Ok((start_time(*pid) != Some(*started)).then(lost_status))
+            }
+        }
+    }
+
+    pub(crate) fn wait(&mut self) -> io::Result<ExitStatus> {
+        match self {
+            Self::Child(child) => child.wait(),
+            Self::Adopted { .. } => loop {
+                if let Some(status) = self.try_wait()? {
+                    break Ok(status);
+                }
+                thread::sleep(Duration::from_millis(10));
+            },
+        }
+    }
+
+    /// Kills the process alone, the supervisor signals it with [`terminate`]
+    #[cfg(test)]
+    pub(crate) fn kill(&mut self) -> io::Result<()> {
+        match self {
+            Self::Child(child) => child.kill(),
+            #[cfg(unix)]
+            Self::Adopted { pid, .. } => {
+                nix::sys::signal::kill(nix::unistd::Pid::from_raw(*pid as i32), Signal::SIGKILL)
+                    .map_err(io::Error::from)
+            }
+            // Nothing is adopted without the start times of Linux
+            #[cfg(windows)]
+            Self::Adopted { .. } => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+impl From<Child> for Process {
+    fn from(child: Child) -> Self {
+        Self::Child(child)
+    }
+}
+
+/// The status of an adopted process whose exit status is unknown
+fn lost_status() -> ExitStatus {
+    #[cfg(unix)]
+    {
+        std::os::unix::process::ExitStatusExt::from_raw(255 << 8)
+    }
+    #[cfg(windows)]
+    {
+        std::os::windows::process::ExitStatusExt::from_raw(255)
+    }
+}
+
+/// When the process `pid` started in clock ticks since boot, from `/proc/<pid>/stat` on Linux
+///
+/// A zombie has no start time, it exited already.
+pub(crate) fn start_time(pid: u32) -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        // The command name in parentheses may contain spaces, the state is the 3rd field and the
+        // start time the 22nd
+        let (_, fields) = stat.rsplit_once(')')?;
+        let fields: Vec<_> = fields.split_whitespace().collect();
+        if matches!(fields.first(), Some(&("Z" | "X"))) {
+            return None;
+        }
+        fields.get(19)?.parse().ok()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// Sends `signal` to the service `pid`, or to its whole group, logging failures
+#[cfg(unix)]
+pub(crate) fn terminate(hash: &Shash, pid: u32, signal: Signal, group: ProcessGroup) {
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+
+    let pid = pid as i32;
+    let pid = match group {
+        ProcessGroup::Shared => pid,
+        ProcessGroup::Own => -pid,
+    };
+    if let Err(err) = kill(Pid::from_raw(pid), signal) {
+        error!("kill {hash} with {signal} failed: {err}");
+    }
+}
+
+/// Sends CTRL_BREAK to the group of the service `pid` or terminates the service, logging failures
+#[cfg(windows)]
+pub(crate) fn terminate(hash: &Shash, pid: u32, signal: Signal, group: ProcessGroup) {
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Console::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT};
+    use windows_sys::Win32::System::Threading::{OpenProcess, TerminateProcess, PROCESS_TERMINATE};
+
+    // SAFETY: the handle is checked and closed, the group id of a group leader is its PID
+    let done = unsafe {
+        if signal == Signal::SIGTERM && group == ProcessGroup::Own {
+            GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) != 0
+        } else {
+            let process = OpenProcess(PROCESS_TERMINATE, 0, pid);
+            let done = !process.is_null() && TerminateProcess(process, 1) != 0;
+            if !process.is_null() {
+                CloseHandle(process);
+            }
+            done
+        }
+    };
+    if !done {
+        let err = std::io::Error::last_os_error();
+        error!("kill {hash} with {signal:?} failed: {err}");
+    }
+}
+
+/// Whether a process with `pid` exists
+#[cfg(unix)]
+pub(crate) fn is_alive(pid: u32) -> bool {
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+
+    i32::try_from(pid).is_ok_and(|pid| pid > 0 && kill(Pid::from_raw(pid), None).is_ok())
+}
+
+/// Whether a process with `pid` exists
+#[cfg(windows)]
+pub(crate) fn is_alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    // SAFETY: the handle is checked and closed
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            return false;
+        }
+        let mut code = 0;
+        let alive = GetExitCodeProcess(process, &mut code) != 0 && code == STILL_ACTIVE as u32;
+        CloseHandle(process);
+        alive
+    }
+}
+
+/// The `run` script of the service in `service_dir`, `run.cmd` if it is a file on Windows
+pub(crate) fn run_script(service_dir: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        let cmd = service_dir.join("run.cmd");
+        if cmd.is_file() {
+            return cmd;
+        }
+    }
+    service_dir.join("run")
+}
+
+/// Whether `metadata` is of a file with an execute bit set, any file on Windows
+pub(crate) fn is_executable(metadata: &Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(windows)]
+    {
+        metadata.is_file()
+    }
+}
+
+/// The signal which killed the process, always `None` on Windows
+pub(crate) fn exit_signal(status: ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        std::os::unix::process::ExitStatusExt::signal(&status)
+    }
+    #[cfg(windows)]
+    {
+        let _ = status;
+        None
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn adopt_test() {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let started = start_time(child.id()).unwrap();
+        assert_eq!(
+            start_time(std::process::id()).map(|s| s <= started),
+            Some(true)
+        );
+        assert!(Process::adopt(child.id(), started + 1).is_none());
+        let mut adopted = Process::adopt(child.id(), started).unwrap();
+        assert_eq!(
+            (adopted.id(), adopted.started()),
+            (child.id(), Some(started))
+        );
+        assert!(adopted.try_wait().unwrap().is_none());
+        adopted.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(adopted.wait().unwrap().code(), Some(255));
+        assert_eq!(start_time(u32::MAX), None);
+    }
+
+    #[test]
+    fn is_alive_test() {
+        assert!(is_alive(std::process::id()));
+        assert!(!is_alive(0));
+        assert!(!is_alive(u32::MAX));
+    }
+}
@@ -0,0 +1,59 @@
+use nix::errno::Errno;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use tracing::error;
+
+/// Makes orphaned descendants of the supervisor its children, like they are of PID 1
+#[cfg(target_os = "linux")]
+pub fn become_subreaper() -> nix::Result<()> {
+    nix::sys::prctl::set_child_subreaper(true)
+}
+
+/// Reaps every exited child of the process without blocking
+pub(crate) fn reap_all() -> Vec<(Pid, ExitStatus)> {
+    let mut reaped = vec![];
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break reaped,
+            Ok(status) => reaped.extend(exit_status(status)),
+            Err(Errno::EINTR) => {}
+            Err(err) => {
+                error!("reaping children failed: {err}");
+                break reaped;
+            }
+        }
+    }
+}
+
+/// Converts a terminal [`WaitStatus`] back to the raw wait status
+fn exit_status(status: WaitStatus) -> Option<(Pid, ExitStatus)> {
+    match status {
+        WaitStatus::Exited(pid, code) => Some((pid, ExitStatus::from_raw((code & 0xff) << 8))),
+        WaitStatus::Signaled(pid, signal, core_dumped) => Some((
+            pid,
+            ExitStatus::from_raw(signal as i32 | if core_dumped { 0x80 } else { 0 }),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::Signal;
+
+    #[test]
+    fn exit_status_test() {
+        let pid = Pid::from_raw(42);
+        let (_, status) = exit_status(WaitStatus::Exited(pid, 3)).unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let (_, status) = exit_status(WaitStatus::Signaled(pid, Signal::SIGKILL, false)).unwrap();
+        assert_eq!(status.signal(), Some(9));
+        assert!(!status.core_dumped());
+
+        assert_eq!(exit_status(WaitStatus::StillAlive), None);
+    }
+}
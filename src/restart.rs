@@ -0,0 +1,143 @@
+use crate::Termination;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::warn;
+
+/// Whether a service is respawned after it exited on its own
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Respawn on every exit
+    #[default]
+    Always,
+    /// Respawn on a non-zero exit or a signal only
+    OnFailure,
+    /// Respawn only if killed by a signal, any exit code is final
+    OnAbnormal,
+    /// Run to completion once
+    Never,
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+#[error("Unknown restart policy {0:?}")]
+pub struct ParsePolicyError(String);
+
+impl FromStr for RestartPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "always" => Ok(Self::Always),
+            "on-failure" => Ok(Self::OnFailure),
+            "on-abnormal" => Ok(Self::OnAbnormal),
+            "never" => Ok(Self::Never),
+            s => Err(ParsePolicyError(s.into())),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Reads `<service_dir>/restart`, `always` if missing or invalid
+    pub fn read(service_dir: &Path) -> Self {
+        Self::read_or(service_dir, Self::default())
+    }
+
+    /// Reads `<service_dir>/restart`, `default` if missing or invalid
+    pub fn read_or(service_dir: &Path, default: Self) -> Self {
+        let path = service_dir.join("restart");
+        let Ok(policy) = read_to_string(&path) else {
+            return default;
+        };
+        policy.parse().unwrap_or_else(|err| {
+            warn!("ignoring {path:?}: {err}");
+            default
+        })
+    }
+
+    /// Whether a service which ended on its own with `termination` is done
+    pub fn completed(self, termination: Termination) -> bool {
+        match self {
+            Self::Always => false,
+            Self::OnFailure => termination.success(),
+            Self::OnAbnormal => matches!(termination, Termination::Exited(_)),
+            Self::Never => true,
+        }
+    }
+}
+
+/// Gives up a service which fails `exits` times within `window`, it completes instead of being
+/// respawned after yet another backoff
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RestartLimit {
+    pub exits: usize,
+    pub window: Duration,
+}
+
+impl RestartLimit {
+    /// Records a failure at `now` among the recent `failures`, returning whether the limit is
+    /// reached
+    pub(crate) fn exceeded(self, failures: &mut VecDeque<Instant>, now: Instant) -> bool {
+        while failures
+            .front()
+            .is_some_and(|&at| now.duration_since(at) >= self.window)
+        {
+            failures.pop_front();
+        }
+        failures.push_back(now);
+        failures.len() >= self.exits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_test() {
+        assert_eq!("never\n".parse(), Ok(RestartPolicy::Never));
+        assert_eq!("on-failure".parse(), Ok(RestartPolicy::OnFailure));
+        assert_eq!(
+            "sometimes".parse::<RestartPolicy>(),
+            Err(ParsePolicyError("sometimes".into()))
+        );
+        assert_eq!(
+            RestartPolicy::read(Path::new("test_res/b")),
+            RestartPolicy::Always
+        );
+        assert_eq!(
+            RestartPolicy::read(Path::new("test_res/restart/never")),
+            RestartPolicy::Never
+        );
+        assert_eq!(
+            RestartPolicy::read_or(Path::new("test_res/b"), RestartPolicy::OnFailure),
+            RestartPolicy::OnFailure
+        );
+        let (success, failure) = (Termination::Exited(0), Termination::Exited(1));
+        let signaled = Termination::Signaled(11);
+        assert!(!RestartPolicy::OnFailure.completed(failure));
+        assert!(!RestartPolicy::OnFailure.completed(signaled));
+        assert!(RestartPolicy::OnFailure.completed(success));
+        assert!(RestartPolicy::OnAbnormal.completed(failure));
+        assert!(!RestartPolicy::OnAbnormal.completed(signaled));
+    }
+
+    #[test]
+    fn limit_test() {
+        let limit = RestartLimit {
+            exits: 3,
+            window: Duration::from_secs(10),
+        };
+        let (now, mut failures) = (Instant::now(), VecDeque::new());
+        assert!(!limit.exceeded(&mut failures, now));
+        assert!(!limit.exceeded(&mut failures, now + Duration::from_secs(5)));
+        // The first failure is out of the window
+        assert!(!limit.exceeded(&mut failures, now + Duration::from_secs(10)));
+        assert_eq!(failures.len(), 2);
+        assert!(limit.exceeded(&mut failures, now + Duration::from_secs(11)));
+    }
+}
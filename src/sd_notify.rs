@@ -0,0 +1,173 @@
+//! Readiness, watchdog and status notifications to systemd over `NOTIFY_SOCKET`.
+
+use crate::ServiceStatus;
+#[cfg(unix)]
+use std::env::{var, var_os};
+#[cfg(unix)]
+use std::ffi::OsStr;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// The notification socket systemd passed in, for a `Type=notify` unit
+#[cfg(unix)]
+pub struct SdNotify {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// Half the watchdog timeout if the watchdog is enabled for the supervisor
+    watchdog: Option<Duration>,
+    /// The last status sent
+    status: String,
+}
+
+#[cfg(unix)]
+impl SdNotify {
+    /// Connects to `NOTIFY_SOCKET`, `None` if it is not set
+    ///
+    /// The watchdog is enabled by `WATCHDOG_USEC` unless `WATCHDOG_PID` names another process.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Some(path) = var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let ours = var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+        let watchdog = var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|&usec| usec > 0 && ours)
+            .map(|usec| Duration::from_micros(usec) / 2);
+        Self::connect(&path, watchdog).map(Some)
+    }
+
+    /// Prepares notifications to the socket at `path`, an abstract one if it starts with `@`
+    fn connect(path: &OsStr, watchdog: Option<Duration>) -> io::Result<Self> {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return Err(io::ErrorKind::Unsupported.into()),
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog,
+            status: String::new(),
+        })
+    }
+
+    /// How often [`SdNotify::watchdog`] has to be called, `None` if the watchdog is disabled
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Tells the services have been spawned for the first time
+    pub fn ready(&self) -> io::Result<()> {
+        self.send("READY=1")
+    }
+
+    /// Keeps the watchdog from restarting the supervisor, if it is enabled
+    pub fn watchdog(&self) -> io::Result<()> {
+        match self.watchdog {
+            Some(_) => self.send("WATCHDOG=1"),
+            None => Ok(()),
+        }
+    }
+
+    /// Tells the supervisor is stopping its services
+    pub fn stopping(&self) -> io::Result<()> {
+        self.send("STOPPING=1")
+    }
+
+    /// Shows `status` in `systemctl status`, if it changed
+    pub fn status(&mut self, status: String) -> io::Result<()> {
+        if status == self.status {
+            return Ok(());
+        }
+        self.send(&format!("STATUS={status}"))?;
+        self.status = status;
+        Ok(())
+    }
+
+    fn send(&self, message: &str) -> io::Result<()> {
+        self.socket
+            .send_to_addr(message.as_bytes(), &self.addr)
+            .map(|_| ())
+    }
+}
+
+/// There is no systemd, [`SdNotify::from_env`] always returns `None`
+#[cfg(not(unix))]
+pub struct SdNotify(());
+
+#[cfg(not(unix))]
+impl SdNotify {
+    pub fn from_env() -> io::Result<Option<Self>> {
+        Ok(None)
+    }
+
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    pub fn ready(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn watchdog(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn stopping(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn status(&mut self, _status: String) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A one-line summary of the services for [`SdNotify::status`]
+pub fn status_line(status: &[ServiceStatus]) -> String {
+    let up = status.iter().filter(|s| s.pid.is_some()).count();
+    format!("{up} of {} services up", status.len())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_test() {
+        let path = std::env::temp_dir().join(format!("runsvdir-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_nonblocking(true).unwrap();
+        let recv = || {
+            let mut buf = [0; 64];
+            let len = systemd.recv(&mut buf).ok()?;
+            Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+        };
+
+        let mut notify = SdNotify::connect(path.as_os_str(), None).unwrap();
+        notify.ready().unwrap();
+        notify.watchdog().unwrap();
+        notify.status("1 of 2 services up".into()).unwrap();
+        notify.status("1 of 2 services up".into()).unwrap();
+        notify.stopping().unwrap();
+        assert_eq!(recv().as_deref(), Some("READY=1"));
+        assert_eq!(recv().as_deref(), Some("STATUS=1 of 2 services up"));
+        assert_eq!(recv().as_deref(), Some("STOPPING=1"));
+        assert_eq!(recv(), None);
+
+        let notify = SdNotify::connect(path.as_os_str(), Some(Duration::from_secs(1))).unwrap();
+        notify.watchdog().unwrap();
+        assert_eq!(recv().as_deref(), Some("WATCHDOG=1"));
+        assert_eq!(status_line(&[]), "0 of 0 services up");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::RestartPolicy;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// The settings of a service from `<service_dir>/service.toml`
+///
+/// The single-purpose files of the service directory, such as `restart`, `uid` and `gid`, or
+/// `env`, take precedence over them.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// Arguments passed to `run`
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Variables set for the service, under its `env` directory
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// The working directory, relative to the service directory
+    pub cwd: Option<PathBuf>,
+    /// `user` or `user:group` to run as, numeric or names, Unix only
+    pub user: Option<String>,
+    pub restart: Option<RestartPolicy>,
+    /// The nice value to run at, Unix only
+    pub nice: Option<i32>,
+    /// The file mode creation mask, such as `0o027`, Unix only
+    pub umask: Option<u32>,
+}
+
+#[derive(Debug, Error)]
+pub enum ServiceConfigError {
+    #[error("Reading service.toml failed: {0}")]
+    Read(#[source] io::Error),
+    #[error("Parsing service.toml failed: {0}")]
+    Parse(#[source] Box<toml::de::Error>),
+}
+
+impl ServiceConfig {
+    /// Reads `<service_dir>/service.toml`, the defaults if there is none
+    pub fn read(service_dir: &Path) -> Result<Self, ServiceConfigError> {
+        match read_to_string(service_dir.join("service.toml")) {
+            Ok(config) => {
+                toml::from_str(&config).map_err(|err| ServiceConfigError::Parse(Box::new(err)))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(ServiceConfigError::Read(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_config_test() {
+        assert_eq!(
+            ServiceConfig::read(Path::new("test_res/b")).unwrap(),
+            ServiceConfig::default()
+        );
+        assert_eq!(
+            ServiceConfig::read(Path::new("test_res/svcconfig/svc")).unwrap(),
+            ServiceConfig {
+                args: vec!["a".into(), "b c".into()],
+                env: BTreeMap::from([("FOO".into(), "bar".into())]),
+                cwd: Some("..".into()),
+                user: None,
+                restart: Some(RestartPolicy::Never),
+                nice: Some(5),
+                umask: Some(0o027),
+            }
+        );
+        assert!(toml::from_str::<ServiceConfig>("nice = \"low\"").is_err());
+        assert!(toml::from_str::<ServiceConfig>("user = \"www:www\"\nshell = true").is_err());
+    }
+}
@@ -1,52 +1,232 @@
 use base64ct::{Base64Unpadded, Encoding};
-use nix::NixPath;
-use sha2::{Digest, Sha512_256};
+use clap::ValueEnum;
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha512_256};
 use std::fmt::{Display, Formatter};
-use std::fs::File;
+use std::fs::{canonicalize, metadata, symlink_metadata, File};
 use std::hash::Hash;
 use std::io;
-use std::io::{BufReader, ErrorKind};
+use std::io::{BufReader, ErrorKind, Read};
 use std::path::{Path, PathBuf};
 use uninit::extension_traits::AsOut;
 use uninit::read::ReadIntoUninit;
 use uninit::uninit_array;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(into = "SerdeShash", try_from = "SerdeShash")
+)]
 pub struct Shash {
     hash: [u8; 32],
     path: PathBuf,
+    /// Where `path` resolves to if it is a symlink
+    target: Option<PathBuf>,
 }
 
-impl Display for Shash {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{} [{:?}]",
-            Base64Unpadded::encode_string(&self.hash),
-            self.path
-        )
+/// How a [`Shash`] is serialized, with the digest as [`Shash::digest`] formats it
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct SerdeShash {
+    digest: String,
+    path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<PathBuf>,
+}
+
+#[cfg(feature = "serde")]
+impl From<Shash> for SerdeShash {
+    fn from(shash: Shash) -> Self {
+        Self {
+            digest: shash.digest(),
+            path: shash.path,
+            target: shash.target,
+        }
     }
 }
 
-impl TryFrom<&Path> for Shash {
-    type Error = io::Error;
+#[cfg(feature = "serde")]
+impl TryFrom<SerdeShash> for Shash {
+    type Error = &'static str;
 
-    fn try_from(path: &Path) -> io::Result<Self> {
-        let mut hasher = Sha512_256::new();
+    fn try_from(shash: SerdeShash) -> Result<Self, Self::Error> {
+        let mut hash = [0; 32];
+        match Base64Unpadded::decode(&shash.digest, &mut hash).map(<[u8]>::len) {
+            Ok(len) if len == hash.len() => Ok(Self {
+                hash,
+                path: shash.path,
+                target: shash.target,
+            }),
+            _ => Err("digest is not 32 bytes in unpadded base64"),
+        }
+    }
+}
+
+/// What makes up the identity of a service
+///
+/// A service directory which is a symlink is identified through the directory it points to, so
+/// renaming the symlink keeps the service running.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Identity {
+    /// The path and the contents of `run`, so editing `run` restarts the service
+    #[default]
+    Content,
+    /// The path of `run` only
+    Path,
+    /// The canonical path of `run`, with every symlink on the way resolved
+    Target,
+}
+
+impl Identity {
+    /// Hashes `path` with `alg`, reading up to `limit` bytes of the contents if set
+    pub fn shash(self, path: &Path, alg: HashAlg, limit: Option<u64>) -> io::Result<Shash> {
+        match self {
+            Self::Content => Shash::hash(path, &resolve_parent(path)?, alg, limit),
+            Self::Path => Shash::hash_path(path, &resolve_parent(path)?, alg),
+            Self::Target => Shash::hash_path(path, &canonicalize(path)?, alg),
+        }
+    }
+}
+
+/// The digest behind [`Shash`]
+///
+/// The default stays SHA-512/256 for the identities of services to survive upgrades, BLAKE3 is
+/// faster on large `run` files and needs the `blake3` feature.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlg {
+    #[default]
+    #[value(name = "sha512_256")]
+    Sha512_256,
+    Sha256,
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+enum Hasher {
+    Sha512_256(Sha512_256),
+    Sha256(Sha256),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(alg: HashAlg) -> Self {
+        match alg {
+            HashAlg::Sha512_256 => Self::Sha512_256(Sha512_256::new()),
+            HashAlg::Sha256 => Self::Sha256(Sha256::new()),
+            #[cfg(feature = "blake3")]
+            HashAlg::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    fn update(&mut self, data: impl AsRef<[u8]>) {
+        let data = data.as_ref();
+        match self {
+            Self::Sha512_256(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        match self {
+            Self::Sha512_256(hasher) => hasher.finalize().into(),
+            Self::Sha256(hasher) => hasher.finalize().into(),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => hasher.finalize().into(),
+        }
+    }
+
+    /// Starts with the path, so the same contents at different paths hash differently
+    fn with_path(alg: HashAlg, path: &Path) -> Self {
+        let mut hasher = Self::new(alg);
         hasher.update(path.as_os_str().as_encoded_bytes());
         hasher.update([0u8]);
-        hasher.update(path.len().to_le_bytes());
+        hasher.update(path.as_os_str().len().to_le_bytes());
         hasher.update([0u8]);
-        let mut file = BufReader::new(File::open(path)?);
+        hasher
+    }
+}
+
+impl Shash {
+    /// The path of the hashed file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The digest in unpadded base64
+    pub fn digest(&self) -> String {
+        Base64Unpadded::encode_string(&self.hash)
+    }
+
+    /// The resolved target of the hashed file if it is a symlink
+    pub fn target(&self) -> Option<&Path> {
+        self.target.as_deref()
+    }
+
+    /// Whether both have the same digest, as a service reached through a renamed symlink does
+    pub fn same_identity(&self, other: &Self) -> bool {
+        self.hash == other.hash
+    }
+
+    /// Hashes the path only, ignoring the contents of the file
+    pub fn from_path_only(path: &Path, alg: HashAlg) -> io::Result<Self> {
+        Self::hash_path(path, path, alg)
+    }
+
+    /// Hashes `hashed`, the path `path` is identified by
+    fn hash_path(path: &Path, hashed: &Path, alg: HashAlg) -> io::Result<Self> {
+        metadata(path)?;
+        Ok(Self {
+            path: path.into(),
+            hash: Hasher::with_path(alg, hashed).finalize(),
+            target: target(path)?,
+        })
+    }
+
+    /// Hashes the path and the contents of the file with `alg`
+    pub fn with_alg(path: &Path, alg: HashAlg) -> io::Result<Self> {
+        Self::with_alg_limit(path, alg, None)
+    }
+
+    /// Hashes the path and the first `limit` bytes of the file
+    ///
+    /// The size of the whole file is hashed as well, so truncating or growing it changes the hash.
+    pub fn with_prefix_limit(path: &Path, limit: u64) -> io::Result<Self> {
+        Self::with_alg_limit(path, HashAlg::default(), Some(limit))
+    }
+
+    /// Hashes the path and up to `limit` bytes of the file with `alg`, the whole file if `None`
+    pub fn with_alg_limit(path: &Path, alg: HashAlg, limit: Option<u64>) -> io::Result<Self> {
+        Self::hash(path, path, alg, limit)
+    }
+
+    /// Hashes `hashed`, the path `path` is identified by, and up to `limit` bytes of `path`
+    fn hash(path: &Path, hashed: &Path, alg: HashAlg, limit: Option<u64>) -> io::Result<Self> {
+        let mut hasher = Hasher::with_path(alg, hashed);
+        let file = File::open(path)?;
+        let size = limit
+            .map(|_| file.metadata().map(|m| m.len() as usize))
+            .transpose()?;
+        let mut file = BufReader::new(file.take(limit.unwrap_or(u64::MAX)));
         let mut total_len = 0usize;
         loop {
             match file.read_into_uninit(uninit_array![u8; 1024].as_out()) {
                 Ok([]) => {
                     hasher.update([0u8]);
-                    hasher.update(total_len.to_le_bytes());
+                    hasher.update(size.unwrap_or(total_len).to_le_bytes());
                     break Ok(Self {
                         path: path.into(),
-                        hash: hasher.finalize().into(),
+                        hash: hasher.finalize(),
+                        target: target(path)?,
                     });
                 }
                 Ok(buf) => {
@@ -60,6 +240,45 @@ impl TryFrom<&Path> for Shash {
     }
 }
 
+/// `path` in the directory its parent points to if the parent is a symlink, `path` otherwise
+fn resolve_parent(path: &Path) -> io::Result<PathBuf> {
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name))
+            if !dir.as_os_str().is_empty() && symlink_metadata(dir)?.is_symlink() =>
+        {
+            Ok(canonicalize(dir)?.join(name))
+        }
+        _ => Ok(path.into()),
+    }
+}
+
+/// Resolves `path` if it is a symlink
+fn target(path: &Path) -> io::Result<Option<PathBuf>> {
+    if symlink_metadata(path)?.is_symlink() {
+        canonicalize(path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+impl Display for Shash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hash = self.digest();
+        match &self.target {
+            Some(target) => write!(f, "{hash} [{:?} -> {target:?}]", self.path),
+            None => write!(f, "{hash} [{:?}]", self.path),
+        }
+    }
+}
+
+impl TryFrom<&Path> for Shash {
+    type Error = io::Error;
+
+    fn try_from(path: &Path) -> io::Result<Self> {
+        Self::with_alg(path, HashAlg::default())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,7 +293,8 @@ mod tests {
                     27, 71, 26, 187, 63, 147, 245, 247, 19, 51, 76, 49, 61, 10, 111, 254, 80, 125,
                     141, 73, 195, 219, 77, 157, 188, 235, 73, 136, 149, 249, 104, 111
                 ],
-                path
+                path,
+                target: None
             }
         );
     }
@@ -96,4 +316,156 @@ mod tests {
             "G0cauz+T9fcTM0wxPQpv/lB9jUnD202dvOtJiJX5aG8 [\"test_res/c/run\"]"
         );
     }
+
+    #[test]
+    fn shash_path_only_test() {
+        let b = Path::new("test_res/b/run");
+        let d = Path::new("test_res/d/run");
+        let alg = HashAlg::default();
+        assert_eq!(
+            Shash::from_path_only(b, alg).unwrap(),
+            Identity::Path.shash(b, alg, None).unwrap()
+        );
+        assert_ne!(
+            Shash::from_path_only(b, alg).unwrap(),
+            Shash::try_from(b).unwrap()
+        );
+        assert_ne!(
+            Shash::from_path_only(b, alg).unwrap(),
+            Shash::from_path_only(d, alg).unwrap()
+        );
+        assert_eq!(
+            Shash::from_path_only(Path::new("test_res/non_existent"), alg)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn shash_alg_test() {
+        let path = Path::new("test_res/c/run");
+        assert_eq!(
+            Shash::with_alg(path, HashAlg::Sha512_256).unwrap(),
+            Shash::try_from(path).unwrap()
+        );
+        let sha256 = Shash::with_alg(path, HashAlg::Sha256).unwrap();
+        assert_ne!(sha256, Shash::try_from(path).unwrap());
+        assert!(sha256.to_string().ends_with(" [\"test_res/c/run\"]"));
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn shash_blake3_test() {
+        let path = Path::new("test_res/c/run");
+        let sha256 = Shash::with_alg(path, HashAlg::Sha256).unwrap();
+        let blake3 = Shash::with_alg(path, HashAlg::Blake3).unwrap();
+        assert_ne!(blake3, Shash::try_from(path).unwrap());
+        assert_ne!(sha256, blake3);
+        assert_eq!(blake3, Shash::with_alg(path, HashAlg::Blake3).unwrap());
+        assert!(blake3.to_string().ends_with(" [\"test_res/c/run\"]"));
+    }
+
+    #[test]
+    fn shash_prefix_limit_test() {
+        let path = std::env::temp_dir().join(format!("runsvdir-prefix-{}", std::process::id()));
+        let mut contents = vec![b'a'; 4096];
+        std::fs::write(&path, &contents).unwrap();
+        let full = Shash::try_from(path.as_path()).unwrap();
+        let prefix = Shash::with_prefix_limit(&path, 1024).unwrap();
+        assert_ne!(prefix, full);
+        assert_eq!(Shash::with_prefix_limit(&path, 4096).unwrap(), full);
+        assert_eq!(
+            Identity::Content
+                .shash(&path, HashAlg::default(), Some(1024))
+                .unwrap(),
+            prefix
+        );
+
+        contents[2048] = b'b';
+        std::fs::write(&path, &contents).unwrap();
+        assert_eq!(Shash::with_prefix_limit(&path, 1024).unwrap(), prefix);
+        assert_ne!(Shash::try_from(path.as_path()).unwrap(), full);
+
+        std::fs::write(&path, &contents[..2048]).unwrap();
+        assert_ne!(Shash::with_prefix_limit(&path, 1024).unwrap(), prefix);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shash_symlink_test() {
+        let path = Path::new("test_res/runkind/link/run");
+        let script = Path::new("test_res/runkind/link/script");
+        let shash = Shash::try_from(path).unwrap();
+        assert_eq!(
+            shash.target(),
+            Some(canonicalize(script).unwrap().as_path())
+        );
+        assert_eq!(Shash::try_from(script).unwrap().target(), None);
+        assert!(shash.to_string().ends_with(&format!(
+            " [{path:?} -> {:?}]",
+            canonicalize(script).unwrap()
+        )));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_symlink_test() {
+        let dir = std::env::temp_dir().join(format!("runsvdir-identity-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sv/svc")).unwrap();
+        std::fs::write(dir.join("sv/svc/run"), "#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("sv/svc"), dir.join("a")).unwrap();
+        std::os::unix::fs::symlink(dir.join("sv/svc"), dir.join("b")).unwrap();
+        let (a, b) = (dir.join("a/run"), dir.join("b/run"));
+        let alg = HashAlg::default();
+        for identity in [Identity::Content, Identity::Path, Identity::Target] {
+            let shash = identity.shash(&a, alg, None).unwrap();
+            assert_eq!(shash.path(), a);
+            assert!(shash.same_identity(&identity.shash(&b, alg, None).unwrap()));
+            assert_ne!(shash, identity.shash(&b, alg, None).unwrap());
+        }
+        assert!(!Identity::Content
+            .shash(&a, alg, None)
+            .unwrap()
+            .same_identity(&Identity::Path.shash(&a, alg, None).unwrap()));
+        assert!(Identity::Content
+            .shash(&dir.join("sv/svc/run"), alg, None)
+            .unwrap()
+            .same_identity(&Shash::try_from(dir.join("sv/svc/run").as_path()).unwrap()));
+
+        std::os::unix::fs::symlink("run", dir.join("sv/svc/link")).unwrap();
+        let link = dir.join("sv/svc/link");
+        assert!(!Identity::Path
+            .shash(&link, alg, None)
+            .unwrap()
+            .same_identity(
+                &Identity::Path
+                    .shash(&dir.join("sv/svc/run"), alg, None)
+                    .unwrap()
+            ));
+        assert!(Identity::Target
+            .shash(&link, alg, None)
+            .unwrap()
+            .same_identity(&Identity::Target.shash(&a, alg, None).unwrap()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn shash_serde_test() {
+        let shash = Shash::try_from(Path::new("test_res/c/run")).unwrap();
+        let json = serde_json::to_string(&shash).unwrap();
+        assert_eq!(
+            json,
+            r#"{"digest":"G0cauz+T9fcTM0wxPQpv/lB9jUnD202dvOtJiJX5aG8","path":"test_res/c/run"}"#
+        );
+        assert_eq!(serde_json::from_str::<Shash>(&json).unwrap(), shash);
+        let link = Shash::try_from(Path::new("test_res/runkind/link/run")).unwrap();
+        let json = serde_json::to_string(&link).unwrap();
+        assert!(json.contains(r#""target":"#));
+        assert_eq!(serde_json::from_str::<Shash>(&json).unwrap(), link);
+        assert!(serde_json::from_str::<Shash>(r#"{"digest":"G0cauz","path":"run"}"#).is_err());
+    }
 }
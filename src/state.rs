@@ -0,0 +1,108 @@
+use crate::Termination;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, rename, write};
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use tracing::warn;
+
+/// The bookkeeping of services kept across restarts of the supervisor
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    /// The services by the base64 digest of their [`crate::Shash`]
+    pub services: BTreeMap<String, SavedService>,
+}
+
+/// The history of a single service and the process it had running
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SavedService {
+    pub restarts: u32,
+    /// The consecutive failures counting towards the backoff
+    pub failures: u32,
+    pub failed_starts: u32,
+    pub last_exit: Option<Termination>,
+    /// The end of the backoff in millis since the Unix epoch, if it was backing off
+    pub respawn_at: Option<u64>,
+    /// The PID of the running process, adopted on start if it still runs
+    pub pid: Option<u32>,
+    /// When the running process started in clock ticks since boot, to tell it from a later
+    /// process with the same PID, Linux only
+    pub started: Option<u64>,
+}
+
+impl SavedState {
+    /// Reads a JSON state file, starting fresh if it is missing or unreadable
+    pub fn load(path: &Path) -> Self {
+        let state = match read_to_string(path) {
+            Ok(state) => state,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Self::default(),
+            Err(err) => {
+                warn!("reading state {path:?} failed, starting fresh: {err}");
+                return Self::default();
+            }
+        };
+        serde_json::from_str(&state).unwrap_or_else(|err| {
+            warn!("parsing state {path:?} failed, starting fresh: {err}");
+            Self::default()
+        })
+    }
+
+    /// Writes the state as JSON, replacing `path` at once so a crash leaves the old state
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        write(&tmp, serde_json::to_string_pretty(self)?)?;
+        rename(tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let path = std::env::temp_dir().join(format!("runsvdir-state-{}", std::process::id()));
+        let state = SavedState {
+            services: BTreeMap::from([
+                (
+                    "1esUWBUJa/9KawVHMeO8mbKEHG4evnJI3Sav5+xQaTk".into(),
+                    SavedService {
+                        restarts: 3,
+                        failures: 2,
+                        failed_starts: 1,
+                        last_exit: Some(Termination::Signaled(11)),
+                        respawn_at: Some(1_700_000_000_000),
+                        pid: Some(42),
+                        started: Some(1234),
+                    },
+                ),
+                (
+                    "M7KLBpstPr29BPj8jjPbwrz1lnPXpXIY++T/nQUvaWU".into(),
+                    SavedService {
+                        last_exit: Some(Termination::Exited(0)),
+                        ..SavedService::default()
+                    },
+                ),
+            ]),
+        };
+        state.save(&path).unwrap();
+        assert_eq!(SavedState::load(&path), state);
+
+        // Files written before processes were saved have no PIDs
+        write(&path, r#"{"services": {"a": {"restarts": 1, "failures": 0, "failed_starts": 0, "last_exit": null, "respawn_at": null}}}"#).unwrap();
+        assert_eq!(
+            SavedState::load(&path).services["a"],
+            SavedService {
+                restarts: 1,
+                ..SavedService::default()
+            }
+        );
+
+        write(&path, "{\"services\": [").unwrap();
+        assert_eq!(SavedState::load(&path), SavedState::default());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(SavedState::load(&path), SavedState::default());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::process::exit_signal;
+use crate::Shash;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::process::ExitStatus;
+use std::time::{Duration, SystemTime};
+
+/// A snapshot of a supervised service
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ServiceStatus {
+    pub shash: Shash,
+    /// The PID of the running process
+    pub pid: Option<u32>,
+    pub state: ServiceState,
+    /// How long the running process has been up
+    pub uptime: Option<Duration>,
+    /// How many times the service has been respawned
+    pub restarts: u32,
+    /// How many times the service exited within the minimum uptime
+    pub failed_starts: u32,
+    /// The consecutive failures of its `check` script
+    pub check_failures: u32,
+    /// How the last process ended, kept while a respawned one runs
+    pub last_exit: Option<Termination>,
+    pub desired: DesiredState,
+}
+
+/// Whether the supervisor wants the service running
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum DesiredState {
+    Up,
+    /// It is disabled with `down`, has a down file or its `run` is gone, so it is being stopped
+    Down,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ServiceState {
+    /// The process is alive, the service has neither `check-port` nor `notification-fd`
+    Running,
+    /// The process is alive, its `check-port` does not accept connections yet or it did not write
+    /// to its `notification-fd` yet
+    Starting,
+    /// The process is alive and its `check-port` accepted a connection or it notified being ready
+    Ready,
+    /// The service was not ready within the ready timeout or closed its `notification-fd` first,
+    /// or its last `check` failed
+    Unhealthy,
+    /// The process got its term signal and did not exit yet
+    Stopping,
+    /// The process is gone and its `finish` script runs, the service is not respawned before it ends
+    Finishing(Termination),
+    /// The process failed and is respawned once its backoff delay ends at `until`
+    Backoff { until: SystemTime },
+    /// The process is gone and the service is disabled with `down`, it is not respawned before
+    /// `up`
+    Down,
+    /// The process is gone
+    Exited(Termination),
+    /// The process is gone for good, by its restart policy, `once` or its restart limit, it is not
+    /// respawned
+    Completed(Termination),
+}
+
+impl Display for ServiceState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Running => f.write_str("running"),
+            Self::Starting => f.write_str("starting"),
+            Self::Ready => f.write_str("ready"),
+            Self::Unhealthy => f.write_str("unhealthy"),
+            Self::Stopping => f.write_str("stopping"),
+            Self::Finishing(termination) => write!(f, "finishing, {termination}"),
+            Self::Backoff { .. } => f.write_str("backoff"),
+            Self::Down => f.write_str("down"),
+            Self::Exited(termination) => termination.fmt(f),
+            Self::Completed(termination) => write!(f, "completed, {termination}"),
+        }
+    }
+}
+
+/// How a process ended
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Termination {
+    /// It exited on its own with the code
+    Exited(i32),
+    /// It was killed by the signal with the number
+    Signaled(i32),
+}
+
+impl Termination {
+    /// Whether the process exited with code 0
+    pub fn success(self) -> bool {
+        self == Self::Exited(0)
+    }
+}
+
+impl From<ExitStatus> for Termination {
+    fn from(status: ExitStatus) -> Self {
+        match (status.code(), exit_signal(status)) {
+            (_, Some(signal)) => Self::Signaled(signal),
+            (code, None) => Self::Exited(code.unwrap_or(-1)),
+        }
+    }
+}
+
+impl Display for Termination {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "exited {code}"),
+            Self::Signaled(signal) => write!(f, "killed {signal}"),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn termination_test() {
+        let exited = Termination::from(ExitStatus::from_raw(3 << 8));
+        assert_eq!(exited, Termination::Exited(3));
+        assert!(!exited.success());
+        assert!(Termination::from(ExitStatus::from_raw(0)).success());
+        let signaled = Termination::from(ExitStatus::from_raw(11));
+        assert_eq!(signaled, Termination::Signaled(11));
+        assert_eq!(signaled.to_string(), "killed 11");
+        assert_eq!(ServiceState::Exited(signaled).to_string(), "killed 11");
+        assert_eq!(ServiceState::Starting.to_string(), "starting");
+        assert_eq!(ServiceState::Down.to_string(), "down");
+        let until = SystemTime::UNIX_EPOCH;
+        assert_eq!(ServiceState::Backoff { until }.to_string(), "backoff");
+        assert_eq!(
+            ServiceState::Finishing(exited).to_string(),
+            "finishing, exited 3"
+        );
+        assert_eq!(
+            ServiceState::Completed(exited).to_string(),
+            "completed, exited 3"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn status_serde_test() {
+        let status = ServiceStatus {
+            shash: Shash::try_from(std::path::Path::new("test_res/b/run")).unwrap(),
+            pid: None,
+            state: ServiceState::Finishing(Termination::Signaled(15)),
+            uptime: None,
+            restarts: 1,
+            failed_starts: 0,
+            check_failures: 0,
+            last_exit: Some(Termination::Signaled(15)),
+            desired: DesiredState::Down,
+        };
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(
+            json["state"],
+            serde_json::json!({"finishing": {"signaled": 15}})
+        );
+        assert_eq!(json["desired"], "down");
+        assert_eq!(
+            serde_json::from_value::<ServiceStatus>(json).unwrap(),
+            status
+        );
+    }
+}
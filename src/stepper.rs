@@ -1,77 +1,782 @@
-use crate::Shash;
-use nix::sys::signal::{kill, Signal};
+#[cfg(all(feature = "cgroup", target_os = "linux"))]
+use crate::cgroup::{Cgroup, CgroupError};
+#[cfg(unix)]
+use crate::credentials::{CredentialError, Credentials};
+use crate::envdir::{apply_env, read_env};
+#[cfg(unix)]
+use crate::limits::{Limits, LimitsError};
+use crate::logger::Logger;
+use crate::process::{is_executable, run_script, terminate, Process, ProcessGroup, Signal};
+#[cfg(unix)]
+use crate::reaper::reap_all;
+use crate::supervise;
+#[cfg(unix)]
+use crate::supervise::Control;
+use crate::supervise::Supervise;
+use crate::{
+    Clock, ControlCommand, DesiredState, EnvFile, EventSink, HashAlg, Identity, LogMode,
+    MonotonicClock, RestartLimit, RestartPolicy, SavedService, SavedState, ServiceConfig,
+    ServiceConfigError, ServiceOverrides, ServiceState, ServiceStatus, Shash, Termination,
+    TracingSink,
+};
+use glob::Pattern;
+#[cfg(unix)]
 use nix::unistd::Pid;
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
-use std::fs::read_dir;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs::{metadata, read_dir, read_to_string, symlink_metadata, DirEntry};
 use std::io;
-use std::path::PathBuf;
-use std::process::{Child, Command, Stdio};
+use std::io::ErrorKind;
+#[cfg(unix)]
+use std::io::PipeReader;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::path::{absolute, Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
 pub struct Stepper {
     dir: PathBuf,
-    running: HashMap<Shash, Child>,
+    identity: Identity,
+    running: HashMap<Shash, Service>,
+    /// Finish scripts and stopped loggers with the instants they started being waited for
+    finishing: Vec<(Shash, Child, Instant)>,
+    /// The `log/run` processes, keyed by the directory of their service
+    loggers: HashMap<PathBuf, Logger>,
+    /// The `supervise` directories, keyed by the directory of their service
+    supervised: HashMap<PathBuf, Supervise>,
+    disabled: HashSet<OsString>,
+    /// The services to run once, they complete when they exit
+    once: HashSet<OsString>,
+    /// The hashes of `run` files by path, reused while their metadata is unchanged
+    hashes: HashMap<PathBuf, CachedHash>,
+    /// Services with an `interval`, keyed by their directory, with the last hash
+    checks: HashMap<PathBuf, (Instant, Shash)>,
+    /// `run` files already warned about lacking the execute permission
+    not_executable: HashSet<PathBuf>,
+    /// Saved histories of services not found in the directory yet, by digest
+    restored: HashMap<String, SavedService>,
+    /// The last error of each entry failing tick after tick, to log repeats once per cooldown
+    failing: HashMap<PathBuf, Failing>,
+    /// The read of the directory which did not complete within the scan timeout
+    pending_scan: Option<Receiver<Scan>>,
+    /// The hashing of the entries which did not complete within the hash timeout
+    pending_hashes: HashMap<PathBuf, Receiver<Result<Option<Candidate>, StepError>>>,
+    /// How long the last step took
+    last_step: Duration,
+    /// The number of failed spawns of each service directory, for the metrics
+    #[cfg(feature = "metrics")]
+    spawn_errors: HashMap<PathBuf, u64>,
+    /// The number of consecutive scans finding no entries while services were supervised
+    empty_scans: u32,
+    /// Whether the last step left spawns or stops to a later one without a deadline for them
+    again: bool,
+    /// The delay before the first respawn of a failed service
+    pub backoff_base: Duration,
+    /// The upper bound of the respawn delay
+    pub backoff_cap: Duration,
+    /// The uptime after which a service is considered healthy again
+    pub backoff_reset: Duration,
+    /// The uptime below which an exit counts as a failed start and backs off even on success
+    pub min_uptime: Duration,
+    /// The time to wait for services to exit on shutdown before killing them
+    pub shutdown_timeout: Duration,
+    /// The time to wait for a service to exit after its term signal before killing it, whether
+    /// it is stale, unhealthy, restarted or a dependent
+    pub kill_timeout: Duration,
+    /// The time a `finish` script may run before it is killed and the service may restart
+    pub finish_timeout: Duration,
+    /// How long `run` has to stay unmodified before a changed hash replaces the running service
+    pub debounce: Duration,
+    /// How long a service with a `check-port` or a `notification-fd` may take to be ready before it
+    /// is unhealthy
+    pub ready_timeout: Duration,
+    /// How long the same error of an entry is not logged again, only counted
+    pub error_cooldown: Duration,
+    /// How often the `check` script of a service runs, it is killed and failed if it takes longer
+    pub health_interval: Duration,
+    /// The number of consecutive failures of `check` restarting the service
+    pub health_failures: u32,
+    /// The number of consecutive empty scans taken for a directory caught mid-swap while services
+    /// are supervised, only the next one stops them
+    pub empty_confirmations: u32,
+    /// The maximum number of stale services sent their term signal per step, in the order of their
+    /// paths, the others wait for later steps
+    pub max_stops: Option<usize>,
+    /// The policy of services without a `restart` file
+    pub restart_policy: RestartPolicy,
+    /// The settings of single services by their directory names overriding the global ones
+    pub overrides: HashMap<String, ServiceOverrides>,
+    /// When a service failing over and over is given up, never if `None`
+    pub restart_limit: Option<RestartLimit>,
+    /// Whether the running services which need a service that exited are stopped, to start again
+    /// once it is up
+    pub restart_dependents: bool,
+    /// Whether to stop stale services, otherwise they are tracked until they exit on their own
+    pub kill_stale: bool,
+    /// Whether to keep a runit `<service>/supervise` directory for `sv`, executing the commands
+    /// written to its `control` and adopting the live service its `pid` names instead of spawning
+    /// another, adopting is Linux only
+    pub supervise: bool,
+    /// Where the output of services goes
+    pub log_mode: LogMode,
+    /// The directory for per-service log files, the service directory if unset
+    pub log_dir: Option<PathBuf>,
+    /// Whether to run services from their own directory
+    pub chdir: bool,
+    /// Whether to reap exited children the supervisor does not track, see [`crate::become_subreaper`]
+    /// to make orphans of the services its children on Linux
+    pub reap_orphans: bool,
+    /// Whether to look for services in subdirectories without `run`, not following symlinks
+    pub recursive: bool,
+    /// Directory entries with a matching name are skipped without looking into them
+    pub ignore: Vec<Pattern>,
+    /// Whether to run each service in its own process group and signal the whole group
+    pub process_group: bool,
+    /// The number of threads hashing `run` files
+    pub hash_threads: usize,
+    /// How long reading the directory may take before the step fails, leaving the services as
+    /// they are, it is read on the stepping thread without a timeout if `None`
+    ///
+    /// A read which timed out is waited for by the next steps, it is not started again before it
+    /// completes.
+    pub scan_timeout: Option<Duration>,
+    /// The maximum number of running services, unlimited if `None`
+    pub max_concurrent: Option<usize>,
+    /// The digest identifying services
+    pub hash_alg: HashAlg,
+    /// The number of leading bytes of `run` hashed, the whole file if `None`
+    pub hash_limit: Option<u64>,
+    /// Whether to reuse the hash of a `run` whose size, modification time and inode are unchanged,
+    /// otherwise every step reads every `run`
+    pub hash_cache: bool,
+    /// How long hashing the entries may take, each on a thread of its own, the entries still being
+    /// hashed then are deferred to a later step and their services kept as they are
+    ///
+    /// The entries are hashed on [`Stepper::hash_threads`] threads waited for if `None`.
+    pub hash_timeout: Option<Duration>,
+    /// Whether to only log the services which would be spawned or stopped
+    pub dry_run: bool,
+    /// Variables set for every service, its `env` directory overrides them
+    pub env_file: EnvFile,
+    /// Resource limits of every service, its `limits` file overrides them
+    #[cfg(unix)]
+    pub limits: Limits,
+    /// The cgroup v2 directory every service gets a group of its own in, ignored without the
+    /// `cgroup` feature or on other systems than Linux
+    pub cgroup_root: Option<PathBuf>,
+    /// Receives spawn, exit, stale and kill events
+    pub events: Box<dyn EventSink>,
+    /// The time of the deadlines checked in steps, shutdown uses real time
+    pub clock: Box<dyn Clock>,
+}
+
+struct Service {
+    child: Option<Process>,
+    spawned: Instant,
+    respawn_at: Instant,
+    failures: u32,
+    term_sent: Option<Instant>,
+    killed: bool,
+    reaped: Option<ExitStatus>,
+    last_exit: Option<Termination>,
+    restarts: u32,
+    /// Exits within `min_uptime` of the spawn
+    failed_starts: u32,
+    /// Exited for good according to its restart policy, `once` or its restart limit
+    completed: bool,
+    /// The failed exits backed off from within the window of the restart limit
+    crashes: VecDeque<Instant>,
+    /// Asks the service to stop
+    term_signal: Signal,
+    /// Its entry of [`Stepper::overrides`]
+    overrides: ServiceOverrides,
+    /// The local TCP port which has to accept a connection for the service to be ready
+    check_port: Option<u16>,
+    /// The pipe the service writes a newline to once it is ready, until it did
+    #[cfg(unix)]
+    notification: Option<PipeReader>,
+    /// Whether the service has a `notification-fd`
+    notifies: bool,
+    ready: bool,
+    /// Whether `check_port` stayed closed for the ready timeout
+    unhealthy: bool,
+    /// Stopped by `sv pause` until `sv cont`
+    paused: bool,
+    health: HealthCheck,
+}
+
+impl Service {
+    fn new(child: Process, now: Instant) -> Self {
+        Self {
+            child: Some(child),
+            spawned: now,
+            respawn_at: now,
+            failures: 0,
+            term_sent: None,
+            killed: false,
+            reaped: None,
+            last_exit: None,
+            restarts: 0,
+            failed_starts: 0,
+            completed: false,
+            crashes: VecDeque::new(),
+            term_signal: Signal::SIGTERM,
+            overrides: ServiceOverrides::default(),
+            check_port: None,
+            #[cfg(unix)]
+            notification: None,
+            notifies: false,
+            ready: false,
+            unhealthy: false,
+            paused: false,
+            health: HealthCheck::new(now),
+        }
+    }
+
+    /// Whether the service tells when it is ready, by `check-port` or `notification-fd`
+    fn checks_ready(&self) -> bool {
+        self.check_port.is_some() || self.notifies
+    }
+
+    /// Takes up the saved history of a service, adopting its saved process if it still runs
+    fn restored(saved: SavedService, now: Instant, wall: SystemTime) -> Self {
+        let backoff = saved.respawn_at.map_or(Duration::ZERO, |at| {
+            (UNIX_EPOCH + Duration::from_millis(at))
+                .duration_since(wall)
+                .unwrap_or_default()
+        });
+        let child = saved
+            .pid
+            .zip(saved.started)
+            .and_then(|(pid, started)| Process::adopt(pid, started));
+        Self {
+            child,
+            spawned: now,
+            respawn_at: now + backoff,
+            failures: saved.failures,
+            term_sent: None,
+            killed: false,
+            reaped: None,
+            last_exit: saved.last_exit,
+            restarts: saved.restarts,
+            failed_starts: saved.failed_starts,
+            completed: false,
+            crashes: VecDeque::new(),
+            term_signal: Signal::SIGTERM,
+            overrides: ServiceOverrides::default(),
+            check_port: None,
+            #[cfg(unix)]
+            notification: None,
+            notifies: false,
+            ready: false,
+            unhealthy: false,
+            paused: false,
+            health: HealthCheck::new(now),
+        }
+    }
+
+    fn saved(&self, now: Instant, wall: SystemTime) -> SavedService {
+        let backoff = self.respawn_at.saturating_duration_since(now);
+        SavedService {
+            restarts: self.restarts,
+            failures: self.failures,
+            failed_starts: self.failed_starts,
+            last_exit: self.last_exit,
+            respawn_at: (!backoff.is_zero())
+                .then(|| (wall + backoff).duration_since(UNIX_EPOCH).ok())
+                .flatten()
+                .map(|at| at.as_millis() as u64),
+            pid: self.child.as_ref().map(Process::id),
+            started: self.child.as_ref().and_then(Process::started),
+        }
+    }
+
+    fn respawned(&mut self, child: Child, now: Instant) {
+        self.child = Some(child.into());
+        self.spawned = now;
+        self.term_sent = None;
+        self.killed = false;
+        self.ready = false;
+        self.unhealthy = false;
+        self.paused = false;
+        self.health.stop();
+        self.health = HealthCheck::new(now);
+        self.restarts = self.restarts.saturating_add(1);
+    }
+}
+
+/// The `check` script of a service
+struct HealthCheck {
+    /// The running script
+    child: Option<Child>,
+    /// The exit status of the running script if it got reaped as an orphan
+    reaped: Option<ExitStatus>,
+    /// When the next check is due, or the running one times out
+    at: Instant,
+    /// The consecutive failures
+    failures: u32,
+}
+
+impl HealthCheck {
+    fn new(now: Instant) -> Self {
+        Self {
+            child: None,
+            reaped: None,
+            at: now,
+            failures: 0,
+        }
+    }
+
+    /// Runs the `check` script of the service of `hash` every `interval`, returning whether it
+    /// passed once it exited
+    fn run(&mut self, hash: &Shash, now: Instant, interval: Duration) -> Option<bool> {
+        if let Some(check) = &mut self.child {
+            let status = match self.reaped.take() {
+                Some(status) => Ok(Some(status)),
+                None => check.try_wait(),
+            };
+            let passed = match status {
+                Ok(None) if now <= self.at => return None,
+                Ok(None) => {
+                    warn!("{hash} check timed out after {interval:?}");
+                    self.stop();
+                    false
+                }
+                Ok(Some(status)) => status.success(),
+                Err(err) => {
+                    error!("get check exit status for {hash} failed: {err}");
+                    false
+                }
+            };
+            self.child = None;
+            return Some(passed);
+        }
+        let service_dir = hash.path().parent()?;
+        let check = absolute(service_dir.join("check")).ok()?;
+        if now < self.at || !metadata(&check).is_ok_and(|m| is_executable(&m)) {
+            return None;
+        }
+        self.at = now + interval;
+        let spawned = Command::new(check)
+            .current_dir(service_dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        match spawned {
+            Ok(check) => {
+                self.child = Some(check);
+                None
+            }
+            Err(err) => {
+                error!("spawn check for {hash} failed: {err}");
+                Some(false)
+            }
+        }
+    }
+
+    /// Kills the running script and waits for it
+    fn stop(&mut self) {
+        if let Some(mut check) = self.child.take() {
+            // Its PID may belong to another process already
+            if self.reaped.take().is_some() {
+                return;
+            }
+            if let Err(err) = check.kill().and_then(|_| check.wait()) {
+                error!("stop check {} failed: {err}", check.id());
+            }
+        }
+    }
 }
 
 impl Stepper {
     pub fn new(dir: PathBuf) -> Self {
+        Self::with_identity(dir, Identity::default())
+    }
+
+    /// Starts building a stepper for `dir` with the default settings
+    pub fn builder(dir: PathBuf) -> StepperBuilder {
+        StepperBuilder::new(dir)
+    }
+
+    pub fn with_identity(dir: PathBuf, identity: Identity) -> Self {
         Self {
             dir,
+            identity,
             running: HashMap::new(),
+            finishing: vec![],
+            loggers: HashMap::new(),
+            supervised: HashMap::new(),
+            disabled: HashSet::new(),
+            once: HashSet::new(),
+            hashes: HashMap::new(),
+            checks: HashMap::new(),
+            not_executable: HashSet::new(),
+            restored: HashMap::new(),
+            failing: HashMap::new(),
+            #[cfg(feature = "metrics")]
+            spawn_errors: HashMap::new(),
+            empty_scans: 0,
+            backoff_base: Duration::from_secs(1),
+            backoff_cap: Duration::from_secs(60),
+            backoff_reset: Duration::from_secs(10),
+            min_uptime: Duration::ZERO,
+            shutdown_timeout: Duration::from_secs(7),
+            kill_timeout: Duration::from_secs(7),
+            finish_timeout: Duration::from_secs(5),
+            debounce: Duration::ZERO,
+            ready_timeout: Duration::from_secs(10),
+            error_cooldown: Duration::from_secs(60),
+            health_interval: Duration::from_secs(10),
+            health_failures: 3,
+            kill_stale: true,
+            max_stops: None,
+            restart_policy: RestartPolicy::default(),
+            overrides: HashMap::new(),
+            restart_limit: None,
+            restart_dependents: false,
+            supervise: false,
+            empty_confirmations: 0,
+            log_mode: LogMode::default(),
+            log_dir: None,
+            chdir: true,
+            reap_orphans: false,
+            recursive: false,
+            ignore: vec![],
+            process_group: true,
+            hash_threads: thread::available_parallelism().map_or(1, |n| n.get().min(8)),
+            scan_timeout: None,
+            pending_scan: None,
+            hash_timeout: None,
+            pending_hashes: HashMap::new(),
+            last_step: Duration::ZERO,
+            again: false,
+            max_concurrent: None,
+            hash_alg: HashAlg::default(),
+            hash_limit: None,
+            hash_cache: true,
+            dry_run: false,
+            env_file: EnvFile::default(),
+            #[cfg(unix)]
+            limits: Limits::default(),
+            cgroup_root: None,
+            events: Box::new(TracingSink),
+            clock: Box::new(MonotonicClock),
         }
     }
 
-    pub fn invoke(&mut self) -> Result<(), StepError> {
+    pub fn invoke(&mut self) -> Result<StepReport, StepError> {
+        let started = Instant::now();
+        let report = self.step();
+        self.last_step = started.elapsed();
+        report.map(|report| StepReport {
+            elapsed: self.last_step,
+            ..report
+        })
+    }
+
+    /// How long the last [`Stepper::invoke`] took, failed or not
+    pub fn last_step(&self) -> Duration {
+        self.last_step
+    }
+
+    fn step(&mut self) -> Result<StepReport, StepError> {
+        let mut report = StepReport::default();
         let mut cur = HashSet::new();
-        let dir = self.dir.as_path();
+        let mut exited = vec![];
+        self.again = false;
 
-        read_dir(dir)
-            .map_err(|err| StepError::ReadDir(dir.into(), err))?
-            .map(|d| {
-                let d = d.map_err(|err| StepError::ReadDirEntry(dir.into(), err))?;
-                let mut p = d.path();
-                p.push("run");
-
-                let hash: Shash = p
-                    .as_path()
-                    .try_into()
-                    .map_err(|err| StepError::Shash(p.clone(), err))?;
-                if let Entry::Vacant(e) = self.running.entry(hash.clone()) {
-                    info!("spawn {hash}");
-                    e.insert(
-                        Command::new(p.as_os_str())
-                            .stdin(Stdio::null())
-                            .stdout(Stdio::null())
-                            .stderr(Stdio::null())
-                            .spawn()
-                            .map_err(|err| StepError::Spawn(hash.clone(), err))?,
-                    );
-                } else {
-                    info!("{hash} is already running");
+        #[cfg(unix)]
+        if self.supervise {
+            self.read_controls();
+        }
+        let now = self.clock.now();
+        let (finish_timeout, group) = (self.finish_timeout, self.process_group);
+        self.finishing
+            .retain_mut(|(hash, child, started)| match child.try_wait() {
+                Ok(None) if now.duration_since(*started) > finish_timeout => {
+                    warn!("{hash} still running after {finish_timeout:?}, killing");
+                    send_signal(hash, child.id(), Signal::SIGKILL, group);
+                    let _ = child.wait();
+                    false
                 }
-                cur.insert(hash);
-                Ok(())
-            })
-            .flat_map(Result::err)
-            .for_each(|err: StepError| error!("skipping entry, err: {err}"));
+                Ok(None) => true,
+                Ok(Some(status)) => {
+                    info!("{hash} finished with {status}");
+                    false
+                }
+                Err(err) => {
+                    error!("get finish exit status for {hash} failed: {err}");
+                    false
+                }
+            });
 
-        self.running.retain(|hash, child| {
-            if !cur.contains(hash) {
-                info!("{hash} stale");
-                if let Err(err) = kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM) {
-                    error!("kill {hash} failed: {err}");
+        let (mut entries, errors) = self.scan_bounded()?;
+        report.errors = errors;
+
+        for err in &report.errors {
+            self.log_failure(err, now);
+        }
+        if !entries.is_empty() || self.running.is_empty() {
+            self.empty_scans = 0;
+        } else if self.empty_scans < self.empty_confirmations {
+            self.empty_scans += 1;
+            warn!(
+                "{:?} is empty, keeping services until confirmed ({}/{})",
+                self.dir, self.empty_scans, self.empty_confirmations
+            );
+            cur.extend(self.running.keys().cloned());
+        }
+        let paths: HashSet<_> = entries.iter().map(DirEntry::path).collect();
+        self.checks.retain(|dir, _| paths.contains(dir));
+        entries.retain(|d| match self.checks.get(&d.path()) {
+            Some((at, hash)) if now < *at => {
+                cur.insert(hash.clone());
+                false
+            }
+            _ => true,
+        });
+
+        // Services are started in the order of their paths when the concurrency is limited
+        entries.sort_by_key(DirEntry::path);
+        let hashed = match self.hash_timeout {
+            None => self.candidates(&entries),
+            Some(timeout) => {
+                let (hashed, deferred) = self.candidates_bounded(&entries, timeout);
+                for dir in deferred {
+                    let run = run_script(&dir);
+                    cur.extend(self.running.keys().filter(|h| h.path() == run).cloned());
+                }
+                hashed
+            }
+        };
+        let mut candidates = vec![];
+        for candidate in hashed {
+            match candidate {
+                Ok(candidate) => candidates.extend(candidate),
+                Err(err) => {
+                    self.log_failure(&err, now);
+                    report.errors.push(err);
+                }
+            }
+        }
+        if self.hash_cache {
+            self.hashes = candidates
+                .iter()
+                .filter_map(|c| Some((c.run.clone(), c.cached.clone()?)))
+                .collect();
+        }
+        let candidates = self.distinct(candidates);
+        let (candidates, cyclic) = order_by_needs(candidates);
+        if !cyclic.is_empty() {
+            // Running services stay as they are, only spawns are skipped
+            cur.extend(cyclic.iter().map(|c| c.hash.clone()));
+            let err = StepError::DependencyCycle(cyclic.into_iter().map(|c| c.dir).collect());
+            self.log_failure(&err, now);
+            report.errors.push(err);
+        }
+        for candidate in candidates {
+            #[cfg(feature = "metrics")]
+            let dir = candidate.dir.clone();
+            match self.step_candidate(candidate, &mut cur) {
+                Ok(spawned) => report.spawned.extend(spawned),
+                Err(err) => {
+                    report.failures += 1;
+                    #[cfg(feature = "metrics")]
+                    {
+                        *self.spawn_errors.entry(dir).or_default() += 1;
+                    }
+                    self.events.on_spawn_failed(err.path(), &err);
+                    self.log_failure(&err, now);
+                    report.errors.push(err);
+                }
+            }
+        }
+        let failed: HashSet<_> = report.errors.iter().map(StepError::path).collect();
+        self.failing
+            .retain(|path, _| failed.contains(path.as_path()));
+
+        let (base, cap, reset) = (self.backoff_base, self.backoff_cap, self.backoff_reset);
+        let (min_uptime, ready_timeout) = (self.min_uptime, self.ready_timeout);
+        let (kill_timeout, kill_stale, group) =
+            (self.kill_timeout, self.kill_stale, self.process_group);
+        let dry_run = self.dry_run;
+        let cgroup_root = self.cgroup_root.as_deref();
+        let (health_interval, health_failures) = (self.health_interval, self.health_failures);
+        let (restart_policy, restart_limit) = (self.restart_policy, self.restart_limit);
+        let mut stops: Vec<_> = self
+            .running
+            .iter()
+            .filter(|(hash, service)| {
+                service.child.is_some() && service.term_sent.is_none() && !cur.contains(*hash)
+            })
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        stops.sort_unstable_by(|a, b| a.path().cmp(b.path()));
+        stops.truncate(self.max_stops.unwrap_or(usize::MAX));
+        let stops: HashSet<_> = stops.into_iter().collect();
+        let events = &*self.events;
+        let (once, disabled) = (&self.once, &self.disabled);
+        let mut postponed = false;
+        self.running.retain(|hash, service| {
+            let stale = !cur.contains(hash);
+            // One disabled with `down` stays, reported down until `up`
+            let down = stale
+                && hash.path().exists()
+                && service_name(hash).is_some_and(|name| disabled.contains(name));
+            let Some(child) = service.child.as_mut() else {
+                if stale && !down {
+                    events.on_stale(hash);
+                }
+                return !stale || down;
+            };
+            if stale && dry_run {
+                info!("{hash} stale, would be stopped");
+            } else if stale && !kill_stale {
+                info!("{hash} stale, left running");
+            } else if stale && service.term_sent.is_none() {
+                if stops.contains(hash) {
+                    events.on_stale(hash);
+                    send_signal(hash, child.id(), service.term_signal, group);
+                    service.term_sent = Some(now);
+                    resume(&mut service.paused, hash, child.id(), group);
+                } else {
+                    debug!("{hash} stale, stop postponed");
+                    postponed = true;
+                }
+            }
+            // Whatever it was stopped for, the service is killed if it outlives the kill timeout
+            let kill_timeout = service
+                .overrides
+                .kill_timeout
+                .map_or(kill_timeout, Duration::from_millis);
+            match service.term_sent {
+                Some(sent) if !service.killed && now.duration_since(sent) > kill_timeout => {
+                    events.on_kill(hash);
+                    if !kill_cgroup(cgroup_root, hash) {
+                        send_signal(hash, child.id(), Signal::SIGKILL, group);
+                    }
+                    service.killed = true;
                 }
+                _ => {}
             }
-            match child.try_wait() {
+            let status = match service.reaped.take() {
+                Some(status) => Ok(Some(status)),
+                None => child.try_wait(),
+            };
+            match status {
                 Ok(None) => {
                     info!("{hash} alive");
+                    #[cfg(unix)]
+                    if let Some(notified) = service
+                        .notification
+                        .as_mut()
+                        .filter(|_| !stale)
+                        .and_then(notified)
+                    {
+                        service.notification = None;
+                        if notified {
+                            info!("{hash} ready");
+                            service.ready = true;
+                        } else if !service.unhealthy {
+                            warn!(
+                                "{hash} unhealthy, closed its notification fd before it was ready"
+                            );
+                            service.unhealthy = true;
+                        }
+                    }
+                    let checking = !service.ready && !service.unhealthy && !stale;
+                    let waited = now.duration_since(service.spawned);
+                    if let Some(port) = service.check_port.filter(|_| checking) {
+                        if port_open(port) {
+                            info!("{hash} ready");
+                            service.ready = true;
+                        } else if waited >= ready_timeout {
+                            warn!("{hash} unhealthy, port {port} closed for {ready_timeout:?}");
+                            service.unhealthy = true;
+                        }
+                    } else if checking && service.notifies && waited >= ready_timeout {
+                        warn!("{hash} unhealthy, not notified ready for {ready_timeout:?}");
+                        service.unhealthy = true;
+                    }
+                    let check = match (stale, service.term_sent) {
+                        (false, None) => service.health.run(hash, now, health_interval),
+                        _ => None,
+                    };
+                    match check {
+                        Some(true) if service.health.failures > 0 => {
+                            info!("{hash} healthy again");
+                            service.health.failures = 0;
+                        }
+                        Some(false) => {
+                            service.health.failures = service.health.failures.saturating_add(1);
+                            let failures = service.health.failures;
+                            warn!("{hash} check failed ({failures}/{health_failures})");
+                            if failures >= health_failures {
+                                warn!("{hash} unhealthy, restarting");
+                                send_signal(hash, child.id(), service.term_signal, group);
+                                service.term_sent = Some(now);
+                            }
+                        }
+                        _ => {}
+                    }
                     true
                 }
                 Ok(Some(status)) => {
-                    info!("{hash} dead with {status}");
-                    false
+                    events.on_exit(hash, status);
+                    remove_cgroup(cgroup_root, hash);
+                    service.health.stop();
+                    service.child = None;
+                    let termination = Termination::from(status);
+                    service.last_exit = Some(termination);
+                    exited.push((hash.clone(), termination));
+                    let uptime = now.duration_since(service.spawned);
+                    if uptime >= reset {
+                        service.failures = 0;
+                    }
+                    let failed_start = uptime < min_uptime && service.term_sent.is_none();
+                    if failed_start {
+                        info!("{hash} failed to start, up for {uptime:?}");
+                        service.failed_starts = service.failed_starts.saturating_add(1);
+                    }
+                    let policy = hash.path().parent().map(|dir| {
+                        let config = ServiceConfig::read(dir).ok().and_then(|c| c.restart);
+                        let default = config.or(service.overrides.restart);
+                        RestartPolicy::read_or(dir, default.unwrap_or(restart_policy))
+                    });
+                    if service_name(hash).is_some_and(|name| once.contains(name)) {
+                        info!("{hash} ran once");
+                        service.completed = true;
+                    } else if service.term_sent.is_none()
+                        && policy.is_some_and(|policy| policy.completed(termination))
+                    {
+                        info!("{hash} completed");
+                        service.completed = true;
+                    } else if (termination.success() && !failed_start)
+                        || service.term_sent.is_some()
+                    {
+                        service.respawn_at = now;
+                    } else if let Some(limit) =
+                        restart_limit.filter(|limit| limit.exceeded(&mut service.crashes, now))
+                    {
+                        warn!(
+                            "{hash} {termination}, given up after {} failures within {:?}",
+                            limit.exits, limit.window
+                        );
+                        service.completed = true;
+                    } else {
+                        let delay = backoff(base, cap, service.failures);
+                        info!("{hash} {termination}, respawn delayed for {delay:?}");
+                        service.respawn_at = now + delay;
+                        service.failures = service.failures.saturating_add(1);
+                    }
+                    !stale || down
                 }
                 Err(err) => {
                     error!("get exit status for {hash} failed: {err}");
@@ -79,42 +784,4319 @@ impl Stepper {
                 }
             }
         });
+
+        self.again |= postponed || !report.errors.is_empty();
+
+        if self.restart_dependents {
+            self.stop_dependents(&exited, now);
+        }
+        for (hash, status) in exited {
+            if let Err(err) = self.finish(hash, status) {
+                error!("finish failed: {err}");
+            }
+        }
+        self.keep_loggers();
+        if self.supervise {
+            self.update_supervise(&paths);
+        }
+
+        #[cfg(unix)]
+        if self.reap_orphans {
+            for (pid, status) in reap_all() {
+                self.reaped(pid, status);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Hashes the entries on up to [`Stepper::hash_threads`] threads, keeping their order
+    fn candidates(&self, entries: &[DirEntry]) -> Vec<Result<Option<Candidate>, StepError>> {
+        let (identity, alg, limit) = (self.identity, self.hash_alg, self.hash_limit);
+        let (disabled, overrides) = (&self.disabled, &self.overrides);
+        let cache = self.hash_cache.then_some(&self.hashes);
+        let threads = self.hash_threads.clamp(1, entries.len().max(1));
+        if threads == 1 {
+            return entries
+                .iter()
+                .map(|d| Candidate::new(d.path(), identity, alg, limit, disabled, overrides, cache))
+                .collect();
+        }
+        thread::scope(|scope| {
+            entries
+                .chunks(entries.len().div_ceil(threads))
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|d| {
+                                let dir = d.path();
+                                Candidate::new(
+                                    dir, identity, alg, limit, disabled, overrides, cache,
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("hashing thread panicked"))
+                .collect()
+        })
+    }
+
+    /// Hashes every entry on a thread of its own, returning the entries not hashed within
+    /// `timeout` apart, a later step waits for the same threads
+    fn candidates_bounded(
+        &mut self,
+        entries: &[DirEntry],
+        timeout: Duration,
+    ) -> (Vec<Result<Option<Candidate>, StepError>>, Vec<PathBuf>) {
+        let (identity, alg, limit) = (self.identity, self.hash_alg, self.hash_limit);
+        let disabled = Arc::new(self.disabled.clone());
+        let overrides = Arc::new(self.overrides.clone());
+        let cache = self.hash_cache.then(|| Arc::new(self.hashes.clone()));
+        let dirs: HashSet<_> = entries.iter().map(DirEntry::path).collect();
+        // Entries gone from the directory are not waited for anymore
+        self.pending_hashes.retain(|dir, _| dirs.contains(dir));
+        let hashing: Vec<_> = entries
+            .iter()
+            .map(|d| {
+                let dir = d.path();
+                let hashed = self.pending_hashes.remove(&dir).unwrap_or_else(|| {
+                    let (tx, rx) = channel();
+                    let (dir, cache) = (dir.clone(), cache.clone());
+                    let (disabled, overrides) = (disabled.clone(), overrides.clone());
+                    // A failed spawn drops the sender, which is handled as a crashed thread
+                    let _ = thread::Builder::new().name("hash".into()).spawn(move || {
+                        let cache = cache.as_deref();
+                        let candidate =
+                            Candidate::new(dir, identity, alg, limit, &disabled, &overrides, cache);
+                        let _ = tx.send(candidate);
+                    });
+                    rx
+                });
+                (dir, hashed)
+            })
+            .collect();
+
+        let deadline = Instant::now() + timeout;
+        let (mut candidates, mut deferred) = (vec![], vec![]);
+        for (dir, hashed) in hashing {
+            match hashed.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(candidate) => candidates.push(candidate),
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!("hashing {dir:?} takes longer than {timeout:?}, deferred");
+                    self.pending_hashes.insert(dir.clone(), hashed);
+                    deferred.push(dir);
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let err = io::Error::other("hashing thread failed");
+                    candidates.push(Err(StepError::Shash(run_script(&dir), err)));
+                }
+            }
+        }
+        (candidates, deferred)
+    }
+
+    fn step_candidate(
+        &mut self,
+        Candidate {
+            dir: service_dir,
+            run: p,
+            hash,
+            interval,
+            needs,
+            overrides,
+            ..
+        }: Candidate,
+        cur: &mut HashSet<Shash>,
+    ) -> Result<Option<Shash>, StepError> {
+        self.moved(&hash);
+        if let Some(old) = self.unsettled(&p, &hash) {
+            info!("{hash} changed recently, keeping {old}");
+            cur.insert(old);
+            self.again = true;
+            return Ok(None);
+        }
+        self.reload(&service_dir, &p, &hash);
+        let now = self.clock.now();
+        if let Some(saved) = self.restored.remove(&hash.digest()) {
+            let service = Service::restored(saved, now, SystemTime::now());
+            self.running.entry(hash.clone()).or_insert(service);
+        }
+        if self.supervise && !self.running.contains_key(&hash) {
+            if let Some(process) = supervise::adopt(&service_dir) {
+                info!("{hash} adopted from supervise/pid as {}", process.id());
+                self.running
+                    .insert(hash.clone(), Service::new(process, now));
+            }
+        }
+        match interval {
+            Some(interval) => {
+                self.checks
+                    .insert(service_dir.clone(), (now + interval, hash.clone()));
+            }
+            None => {
+                self.checks.remove(&service_dir);
+            }
+        }
+        if let Some(service) = self.running.get_mut(&hash) {
+            service.overrides = overrides;
+        }
+        let due = match self.running.get(&hash) {
+            None => true,
+            Some(service) if service.child.is_some() => {
+                info!("{hash} is already running");
+                false
+            }
+            Some(service) if service.completed => {
+                info!("{hash} is completed");
+                false
+            }
+            Some(_) if self.finishing.iter().any(|(h, ..)| h == &hash) => {
+                info!("{hash} is finishing");
+                false
+            }
+            Some(service) if now < service.respawn_at => {
+                info!("{hash} is backing off");
+                false
+            }
+            Some(_) => true,
+        };
+        cur.insert(hash.clone());
+        if !due || !self.executable(&p) {
+            return Ok(None);
+        }
+        if let Some(old) = self.replacing(&p, &hash) {
+            info!("{hash} waits for {old} to stop");
+            self.again = true;
+            return Ok(None);
+        }
+        if let Some(need) = needs.iter().find(|need| !self.is_up(need)) {
+            info!("{hash} waits for {need:?}");
+            self.again = true;
+            return Ok(None);
+        }
+        if let Some(max) = self.max_concurrent {
+            if self.running.values().filter(|s| s.child.is_some()).count() >= max {
+                info!("{hash} deferred, {max} services are running");
+                self.again = true;
+                return Ok(None);
+            }
+        }
+        let config = ServiceConfig::read(&service_dir)
+            .map_err(|err| StepError::ServiceConfig(service_dir.clone(), err))?;
+        let mut command = self.command(&service_dir, &config, &p, &hash)?;
+        command.args(&config.args);
+        if self.dry_run {
+            info!("{hash} would be spawned");
+            return Ok(None);
+        }
+        if let Some(stdout) = self.logger(&service_dir)? {
+            command.stdout(stdout);
+        }
+        #[cfg(unix)]
+        let notification = read_notification_fd(&service_dir)
+            .map(|fd| notification_pipe(&mut command, fd))
+            .transpose()
+            .map_err(|err| StepError::Spawn(hash.clone(), err))?;
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                // Removed since it was hashed, the next step will notice
+                debug!("{hash} vanished before spawning: {err}");
+                return Ok(None);
+            }
+            Err(err) => return Err(StepError::Spawn(hash, err)),
+        };
+        if let Err(err) = self.log_mode.capture(
+            self.log_dir.as_deref(),
+            &service_dir,
+            child.stdout.take(),
+            child.stderr.take(),
+        ) {
+            error!("{}", StepError::Log(service_dir.clone(), err));
+        }
+        self.events.on_spawn(&hash, child.id());
+        let service = match self.running.entry(hash.clone()) {
+            Entry::Vacant(e) => e.insert(Service::new(child.into(), now)),
+            Entry::Occupied(e) => {
+                let service = e.into_mut();
+                service.respawned(child, now);
+                service
+            }
+        };
+        service.term_signal = read_term_signal(&service_dir);
+        service.overrides = overrides;
+        service.check_port = read_check_port(&service_dir);
+        #[cfg(unix)]
+        {
+            service.notifies = notification.is_some();
+            service.notification = notification;
+        }
+        Ok(Some(hash))
+    }
+
+    /// Runs the `finish` script of an exited service, if it is executable
+    ///
+    /// The script gets the exit code (-1 if killed by a signal) and the signal (0 if exited) as
+    /// arguments, like in runit.
+    fn finish(&mut self, hash: Shash, termination: Termination) -> Result<(), StepError> {
+        let Some(service_dir) = hash.path().parent() else {
+            return Ok(());
+        };
+        let finish = service_dir.join("finish");
+        match finish.metadata() {
+            Ok(metadata) if is_executable(&metadata) => {}
+            _ => return Ok(()),
+        }
+
+        let (code, signal) = match termination {
+            Termination::Exited(code) => (code, 0),
+            Termination::Signaled(signal) => (-1, signal),
+        };
+        let config = ServiceConfig::read(service_dir)
+            .map_err(|err| StepError::ServiceConfig(service_dir.into(), err))?;
+        info!("finish {hash}");
+        let child = self
+            .command(service_dir, &config, &finish, &hash)?
+            .arg(code.to_string())
+            .arg(signal.to_string())
+            .spawn()
+            .map_err(|err| StepError::Spawn(hash.clone(), err))?;
+        self.finishing.push((hash, child, self.clock.now()));
         Ok(())
     }
-}
 
-#[derive(Debug, Error)]
-pub enum StepError {
-    #[error("Reading dir {0:?} failed: {1}")]
-    ReadDir(PathBuf, #[source] io::Error),
-    #[error("Reading dir entry on {0:?} failed: {1}")]
-    ReadDirEntry(PathBuf, #[source] io::Error),
-    #[error("Hashing on {0:?} failed: {1}")]
-    Shash(PathBuf, #[source] io::Error),
-    #[error("Spawn process {0} failed: {1}")]
-    Spawn(Shash, #[source] io::Error),
-}
+    /// Scans the directory, on a worker thread waited for up to the scan timeout if there is one
+    fn scan_bounded(&mut self) -> Scan {
+        let Some(timeout) = self.scan_timeout else {
+            return scan_all(&self.dir, &self.ignore, self.recursive);
+        };
+        let scan = match self.pending_scan.take() {
+            Some(scan) => scan,
+            None => {
+                let (dir, ignore, recursive) =
+                    (self.dir.clone(), self.ignore.clone(), self.recursive);
+                let (tx, rx) = channel();
+                thread::Builder::new()
+                    .name("scan".into())
+                    .spawn(move || {
+                        let _ = tx.send(scan_all(&dir, &ignore, recursive));
+                    })
+                    .map_err(|err| StepError::ReadDir(self.dir.clone(), err))?;
+                rx
+            }
+        };
+        match scan.recv_timeout(timeout) {
+            Ok(scan) => scan,
+            Err(RecvTimeoutError::Timeout) => {
+                self.pending_scan = Some(scan);
+                Err(StepError::ScanTimeout(self.dir.clone(), timeout))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(StepError::ReadDir(
+                self.dir.clone(),
+                io::Error::other("scan thread panicked"),
+            )),
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::path::Path;
+    /// Logs `err` unless the entry failed the same way within the cooldown, then it is counted
+    fn log_failure(&mut self, err: &StepError, now: Instant) {
+        let message = err.to_string();
+        let path = err.path();
+        match self.failing.get_mut(path) {
+            Some(failing) if failing.message == message => {
+                failing.count = failing.count.saturating_add(1);
+                if now.duration_since(failing.logged) < self.error_cooldown {
+                    debug!("{path:?} failed again, err: {err}");
+                } else {
+                    failing.logged = now;
+                    let count = failing.count;
+                    error!("{path:?} still failing ({count} occurrences), err: {err}");
+                }
+            }
+            _ => {
+                error!("skipping entry, err: {err}");
+                let failing = Failing {
+                    message,
+                    logged: now,
+                    count: 1,
+                };
+                self.failing.insert(path.into(), failing);
+            }
+        }
+    }
 
-    #[test]
-    fn step_test() {
-        let mut stepper = Stepper::new(PathBuf::from("test_res"));
-        stepper.invoke().unwrap();
+    /// Whether the service in the directory named `name` has a running child, which is ready if
+    /// it tells when it is
+    fn is_up(&self, name: &OsStr) -> bool {
+        self.running.iter().any(|(hash, service)| {
+            service.child.is_some()
+                && (service.ready || !service.checks_ready())
+                && service_name(hash) == Some(name)
+        })
+    }
 
-        for child in stepper.running.values_mut() {
-            let _ = child.kill();
+    /// Stops the running services which need one of the `exited` ones
+    fn stop_dependents(&mut self, exited: &[(Shash, Termination)], now: Instant) {
+        let names: HashSet<_> = exited.iter().filter_map(|(h, _)| service_name(h)).collect();
+        if names.is_empty() {
+            return;
+        }
+        for (hash, service) in &mut self.running {
+            let Some(child) = service
+                .child
+                .as_ref()
+                .filter(|_| service.term_sent.is_none())
+            else {
+                continue;
+            };
+            let needs = hash.path().parent().map(read_needs).unwrap_or_default();
+            if let Some(need) = needs.iter().find(|need| names.contains(need.as_os_str())) {
+                info!("{hash} restarts, {need:?} exited");
+                send_signal(hash, child.id(), service.term_signal, self.process_group);
+                service.term_sent = Some(now);
+            }
         }
+    }
 
-        assert_eq!(
-            stepper.running.keys().collect::<HashSet<_>>(),
-            HashSet::from([
-                &Shash::try_from(Path::new("test_res/b/run")).unwrap(),
-                &Shash::try_from(Path::new("test_res/d/run")).unwrap()
-            ])
+    /// Supervises the service with the identity of `hash` as `hash` if it was found at another
+    /// path, the symlink leading to its directory was renamed
+    fn moved(&mut self, hash: &Shash) {
+        let Some(old) = self
+            .running
+            .keys()
+            .find(|old| old.same_identity(hash) && old.path() != hash.path())
+            .cloned()
+        else {
+            return;
+        };
+        info!("{old} moved to {hash}");
+        if let Some(service) = self.running.remove(&old) {
+            self.running.insert(hash.clone(), service);
+        }
+        for (finishing, ..) in &mut self.finishing {
+            if *finishing == old {
+                *finishing = hash.clone();
+            }
+        }
+    }
+
+    /// Drops the candidates with the identity of another one, such as two symlinks to the same
+    /// service directory, the one supervised already or else the first by path is kept
+    fn distinct(&self, mut candidates: Vec<Candidate>) -> Vec<Candidate> {
+        candidates.sort_by_key(|c| (!self.running.contains_key(&c.hash), c.dir.clone()));
+        let mut kept: Vec<Candidate> = Vec::with_capacity(candidates.len());
+        for candidate in candidates {
+            match kept.iter().find(|k| k.hash.same_identity(&candidate.hash)) {
+                Some(other) => warn!(
+                    "{:?} is the same service as {:?}, skipped",
+                    candidate.dir, other.dir
+                ),
+                None => kept.push(candidate),
+            }
+        }
+        kept.sort_by(|a, b| a.dir.cmp(&b.dir));
+        kept
+    }
+
+    /// Returns the hash still supervised for `run` if it changed to `hash` within `debounce`
+    fn unsettled(&self, run: &Path, hash: &Shash) -> Option<Shash> {
+        if self.debounce.is_zero() || self.running.contains_key(hash) {
+            return None;
+        }
+        let old = self.running.keys().find(|old| old.path() == run)?;
+        let modified = metadata(run).and_then(|m| m.modified()).ok()?;
+        let settled = modified.elapsed().is_ok_and(|quiet| quiet >= self.debounce);
+        (!settled).then(|| old.clone())
+    }
+
+    /// Returns the hash of the old instance of `run`, changed to `hash`, which is still running
+    ///
+    /// Being stale the old instance gets stopped, the changed one starts once it exited so both
+    /// never run at the same time. Old instances left running or kept by a dry run do not count.
+    fn replacing(&self, run: &Path, hash: &Shash) -> Option<Shash> {
+        if !self.kill_stale || self.dry_run {
+            return None;
+        }
+        self.running
+            .iter()
+            .find(|(old, service)| old.path() == run && *old != hash && service.child.is_some())
+            .map(|(old, _)| old.clone())
+    }
+
+    /// Writes the status of the services in `dirs` to their `supervise` directories, closing
+    /// those of the directories gone
+    fn update_supervise(&mut self, dirs: &HashSet<PathBuf>) {
+        let mut status: HashMap<_, _> = dirs
+            .iter()
+            .filter(|dir| run_script(dir).exists())
+            .map(|dir| {
+                // Once is wanted down, as by runit, even while it runs
+                let wanted_down = dir
+                    .file_name()
+                    .is_some_and(|name| self.disabled.contains(name) || self.once.contains(name));
+                let down = supervise::Status {
+                    pid: None,
+                    state: supervise::State::Down,
+                    want_up: !wanted_down && !dir.join("down").exists(),
+                    term: false,
+                    paused: false,
+                };
+                (dir.clone(), down)
+            })
+            .collect();
+        for (hash, service) in &self.running {
+            let Some(current) = hash.path().parent().and_then(|dir| status.get_mut(dir)) else {
+                continue;
+            };
+            // The instance with a live process speaks for a changed service
+            if current.pid.is_some() {
+                continue;
+            }
+            current.state = if service.child.is_some() {
+                supervise::State::Run
+            } else if self.finishing.iter().any(|(h, ..)| h == hash) {
+                supervise::State::Finish
+            } else {
+                supervise::State::Down
+            };
+            current.pid = service.child.as_ref().map(Process::id);
+            current.want_up &= !service.completed;
+            current.term = service.child.is_some() && service.term_sent.is_some();
+            current.paused = service.paused;
+        }
+        self.supervised.retain(|dir, _| status.contains_key(dir));
+        for (dir, status) in status {
+            let supervise = self
+                .supervised
+                .entry(dir)
+                .or_insert_with_key(|dir| Supervise::new(dir));
+            if let Err(err) = supervise.update(status) {
+                warn!("writing {:?} failed: {err}", supervise.dir());
+            }
+        }
+    }
+
+    /// Executes the commands `sv` wrote to the `control` FIFOs of the services
+    #[cfg(unix)]
+    fn read_controls(&mut self) {
+        let commands: Vec<_> = self
+            .supervised
+            .iter_mut()
+            .filter_map(|(dir, supervise)| {
+                let name = dir.file_name()?.to_string_lossy().into_owned();
+                Some((name, supervise.commands()))
+            })
+            .collect();
+        for (name, commands) in commands {
+            for command in commands {
+                info!("supervise control {command:?} for {name}");
+                let reply = match command {
+                    Control::Up => self.control(&ControlCommand::Up(name.clone())),
+                    Control::Down => self.control(&ControlCommand::Down(name.clone())),
+                    Control::Once => self.control(&ControlCommand::Once(name.clone())),
+                    Control::Signal(signal @ (Signal::SIGTERM | Signal::SIGKILL)) => {
+                        self.signal_service(&name, Some(signal))
+                    }
+                    Control::Signal(signal) => self.signal_running(&name, signal),
+                };
+                debug!("{}", reply.trim_end());
+            }
+        }
+    }
+
+    /// Keeps the logger of the service in `service_dir` running, returning the stdout leading to it
+    fn logger(&mut self, service_dir: &Path) -> Result<Option<Stdio>, StepError> {
+        let Some(dir) = Logger::dir(service_dir) else {
+            return Ok(None);
+        };
+        let err = |err| StepError::Logger(service_dir.into(), err);
+        let logger = match self.loggers.entry(service_dir.into()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(Logger::new(&dir).map_err(err)?),
+        };
+        logger
+            .keep_running(ProcessGroup::new(self.process_group))
+            .map_err(err)?;
+        logger.stdout().map(Some).map_err(err)
+    }
+
+    /// Respawns exited loggers and stops the loggers of services no longer supervised
+    fn keep_loggers(&mut self) {
+        let group = ProcessGroup::new(self.process_group);
+        let services: HashSet<_> = self
+            .running
+            .keys()
+            .filter_map(|h| h.path().parent())
+            .collect();
+        let gone: Vec<_> = self
+            .loggers
+            .keys()
+            .filter(|dir| !services.contains(dir.as_path()))
+            .cloned()
+            .collect();
+        for dir in gone {
+            if let Some((hash, child)) = self.loggers.remove(&dir).and_then(|l| l.stop(group)) {
+                self.finishing.push((hash, child, self.clock.now()));
+            }
+        }
+        for (dir, logger) in &mut self.loggers {
+            if let Err(err) = logger.keep_running(group) {
+                error!("{}", StepError::Logger(dir.clone(), err));
+            }
+        }
+    }
+
+    /// Moves the running service of `run` over to the changed `hash` if its directory has a
+    /// `reload-signal`, sending it the signal instead of respawning it
+    fn reload(&mut self, service_dir: &Path, run: &Path, hash: &Shash) {
+        if self.running.contains_key(hash) {
+            return;
+        }
+        let Some(old) = self
+            .running
+            .iter()
+            .find(|(old, service)| {
+                old.path() == run && service.child.is_some() && service.term_sent.is_none()
+            })
+            .map(|(old, _)| old.clone())
+        else {
+            return;
+        };
+        let Some(signal) = read_reload_signal(service_dir) else {
+            return;
+        };
+        let Some(service) = self.running.remove(&old) else {
+            return;
+        };
+        info!("{old} changed to {hash}, reloading with {signal:?}");
+        if let Some(child) = &service.child {
+            send_signal(hash, child.id(), signal, self.process_group);
+        }
+        self.running.insert(hash.clone(), service);
+    }
+
+    /// Checks that `run` has an execute bit set, warning once per path if it does not
+    fn executable(&mut self, run: &Path) -> bool {
+        let executable = !run
+            .metadata()
+            .is_ok_and(|m| m.is_file() && !is_executable(&m));
+        if executable {
+            self.not_executable.remove(run);
+        } else if self.not_executable.insert(run.into()) {
+            warn!("{run:?}: run not executable, skipping");
+        }
+        executable
+    }
+
+    /// Builds the command running `program` of the service living in `service_dir`
+    fn command(
+        &self,
+        service_dir: &Path,
+        config: &ServiceConfig,
+        program: &Path,
+        hash: &Shash,
+    ) -> Result<Command, StepError> {
+        let (stdout, stderr) = self
+            .log_mode
+            .stdio(self.log_dir.as_deref(), service_dir)
+            .map_err(|err| StepError::Log(service_dir.into(), err))?;
+        #[cfg(unix)]
+        let credentials = match Credentials::read(service_dir) {
+            Ok(None) => config.user.as_deref().map(Credentials::parse).transpose(),
+            credentials => credentials,
+        }
+        .map_err(|err| StepError::Credential(service_dir.into(), err))?;
+        let env = read_env(service_dir).map_err(|err| StepError::Env(service_dir.into(), err))?;
+        #[cfg(unix)]
+        let limits = Limits::read(service_dir)
+            .map_err(|err| StepError::Limits(service_dir.into(), err))?
+            .unwrap_or_default()
+            .or(self.limits);
+
+        let cwd = match &config.cwd {
+            Some(cwd) => Some(service_dir.join(cwd)),
+            None => self.chdir.then(|| service_dir.into()),
+        };
+        let mut command = match cwd {
+            Some(cwd) => {
+                let program =
+                    absolute(program).map_err(|err| StepError::Spawn(hash.clone(), err))?;
+                let mut command = Command::new(program);
+                command.current_dir(cwd);
+                command
+            }
+            None => Command::new(program),
+        };
+        command.stdin(Stdio::null()).stdout(stdout).stderr(stderr);
+        command.envs(self.env_file.0.iter().map(|(key, value)| (key, value)));
+        command.envs(&config.env);
+        apply_env(&mut command, env);
+        ProcessGroup::new(self.process_group).configure(&mut command);
+        #[cfg(all(feature = "cgroup", target_os = "linux"))]
+        if let Some(cgroup) = self.cgroup(hash).filter(|_| !self.dry_run) {
+            cgroup
+                .prepare(service_dir, &mut command)
+                .map_err(|err| StepError::Cgroup(service_dir.into(), err))?;
+        }
+        #[cfg(unix)]
+        {
+            // The nice value and the limits go first, lowering the one and raising a hard limit
+            // takes the privileges dropped afterwards
+            if let Some(nice) = config.nice {
+                set_nice(&mut command, nice);
+            }
+            if let Some(umask) = config.umask {
+                set_umask(&mut command, umask);
+            }
+            if limits != Limits::default() {
+                limits.apply(&mut command);
+            }
+            if let Some(credentials) = credentials {
+                credentials.apply(&mut command);
+            }
+        }
+        Ok(command)
+    }
+
+    /// The group of the service of `hash` if there is a cgroup root
+    #[cfg(all(feature = "cgroup", target_os = "linux"))]
+    fn cgroup(&self, hash: &Shash) -> Option<Cgroup> {
+        Cgroup::new(self.cgroup_root.as_deref()?, hash)
+    }
+
+    /// The nearest instant a service with an `interval` is due to be checked
+    pub fn next_check(&self) -> Option<Instant> {
+        self.checks.values().map(|(at, _)| *at).min()
+    }
+
+    /// The instant the next step has something to do at, `None` if nothing is due until a
+    /// service exits or the directory changes
+    ///
+    /// It is now while anything has to be polled for: readiness, adopted processes which exit
+    /// without a `SIGCHLD`, `supervise` controls, pending scans and hashes or work the last step
+    /// left to a later one.
+    pub fn next_step(&self) -> Option<Instant> {
+        let now = self.clock.now();
+        let polled = self.running.values().any(|service| {
+            let checking = !service.ready
+                && !service.unhealthy
+                && (service.check_port.is_some() || service.notifies);
+            let adopted = matches!(service.child, Some(Process::Adopted { .. }));
+            service.reaped.is_some()
+                || (service.child.is_some()
+                    && (adopted || (checking && service.term_sent.is_none())))
+        });
+        if self.again
+            || self.supervise
+            || polled
+            || self.pending_scan.is_some()
+            || !self.pending_hashes.is_empty()
+            || self.empty_scans > 0
+        {
+            return Some(now);
+        }
+        let services = self.running.iter().filter_map(|(hash, service)| {
+            if service.child.is_none() {
+                return (!service.completed && service.respawn_at > now)
+                    .then_some(service.respawn_at);
+            }
+            match service.term_sent {
+                Some(_) if service.killed => None,
+                Some(sent) => Some(
+                    sent + service
+                        .overrides
+                        .kill_timeout
+                        .map_or(self.kill_timeout, Duration::from_millis),
+                ),
+                None => {
+                    let check = hash.path().parent().map(|dir| dir.join("check"));
+                    let checked = service.health.child.is_some()
+                        || check.is_some_and(|c| metadata(c).is_ok_and(|m| is_executable(&m)));
+                    checked.then_some(service.health.at)
+                }
+            }
+        });
+        let finishing = self
+            .finishing
+            .iter()
+            .map(|(.., started)| *started + self.finish_timeout);
+        services.chain(finishing).chain(self.next_check()).min()
+    }
+
+    /// Returns the PIDs of the live children ordered by their hashes
+    pub fn running_pids(&self) -> Vec<(Shash, u32)> {
+        let mut pids: Vec<_> = self
+            .running
+            .iter()
+            .filter(|(_, service)| service.reaped.is_none())
+            .flat_map(|(hash, service)| Some((hash.clone(), service.child.as_ref()?.id())))
+            .collect();
+        pids.sort_unstable();
+        pids
+    }
+
+    /// Returns the states of all supervised services ordered by their hashes
+    pub fn status(&self) -> Vec<ServiceStatus> {
+        let (now, wall) = (self.clock.now(), SystemTime::now());
+        let mut status: Vec<_> = self
+            .running
+            .iter()
+            .map(|(hash, service)| ServiceStatus {
+                shash: hash.clone(),
+                pid: service.child.as_ref().map(Process::id),
+                state: match (&service.child, service.last_exit) {
+                    (None, Some(exit)) if self.finishing.iter().any(|(h, ..)| h == hash) => {
+                        ServiceState::Finishing(exit)
+                    }
+                    (None, Some(exit)) if service.completed => ServiceState::Completed(exit),
+                    (None, _) if service_name(hash).is_some_and(|n| self.disabled.contains(n)) => {
+                        ServiceState::Down
+                    }
+                    (None, _) if service.respawn_at > now => ServiceState::Backoff {
+                        until: wall + (service.respawn_at - now),
+                    },
+                    (None, Some(exit)) => ServiceState::Exited(exit),
+                    _ if service.term_sent.is_some() => ServiceState::Stopping,
+                    _ if service.health.failures > 0 => ServiceState::Unhealthy,
+                    _ if !service.checks_ready() => ServiceState::Running,
+                    _ if service.ready => ServiceState::Ready,
+                    _ if service.unhealthy => ServiceState::Unhealthy,
+                    _ => ServiceState::Starting,
+                },
+                uptime: service
+                    .child
+                    .as_ref()
+                    .map(|_| now.duration_since(service.spawned)),
+                restarts: service.restarts,
+                failed_starts: service.failed_starts,
+                check_failures: service.health.failures,
+                last_exit: service.last_exit,
+                desired: self.desired(hash),
+            })
+            .collect();
+        status.sort_unstable_by(|a, b| a.shash.cmp(&b.shash));
+        status
+    }
+
+    /// Renders the metrics of all supervised services in the Prometheus text format
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> String {
+        crate::metrics::render(&self.status(), &self.spawn_errors, self.last_step)
+    }
+
+    fn desired(&self, hash: &Shash) -> DesiredState {
+        let disabled = service_name(hash).is_some_and(|name| self.disabled.contains(name));
+        let down = hash
+            .path()
+            .parent()
+            .is_some_and(|dir| dir.join("down").exists());
+        if disabled || down || !hash.path().exists() {
+            DesiredState::Down
+        } else {
+            DesiredState::Up
+        }
+    }
+
+    /// Returns the history of all services, to be restored after a restart of the supervisor
+    pub fn saved_state(&self) -> SavedState {
+        let (now, wall) = (self.clock.now(), SystemTime::now());
+        let mut services: BTreeMap<_, _> = self.restored.clone().into_iter().collect();
+        for (hash, service) in &self.running {
+            services.insert(hash.digest(), service.saved(now, wall));
+        }
+        SavedState { services }
+    }
+
+    /// Takes up the history saved by [`Stepper::saved_state`], applied to each service once it is
+    /// found in the directory
+    pub fn restore_state(&mut self, state: SavedState) {
+        self.restored = state.services.into_iter().collect();
+    }
+
+    /// Executes a command received on the control socket, returning the reply
+    pub fn control(&mut self, command: &ControlCommand) -> String {
+        match command {
+            ControlCommand::Restart(name) => self.signal_service(name, None),
+            ControlCommand::Term(name) => self.signal_service(name, Some(Signal::SIGTERM)),
+            ControlCommand::Kill(name) => self.signal_service(name, Some(Signal::SIGKILL)),
+            ControlCommand::Down(name) => {
+                info!("down {name}");
+                self.disabled.insert(name.into());
+                self.once.remove(OsStr::new(name));
+                "ok\n".into()
+            }
+            ControlCommand::Up(name) => {
+                info!("up {name}");
+                self.disabled.remove(OsStr::new(name));
+                self.once.remove(OsStr::new(name));
+                self.uncomplete(name);
+                "ok\n".into()
+            }
+            ControlCommand::Once(name) => {
+                info!("once {name}");
+                self.disabled.remove(OsStr::new(name));
+                self.once.insert(name.into());
+                self.uncomplete(name);
+                "ok\n".into()
+            }
+            ControlCommand::Status(only) => {
+                let only = only.as_deref().map(OsStr::new);
+                let mut reply = String::new();
+                let mut down = HashSet::new();
+                for status in self.status() {
+                    let name = service_name(&status.shash).unwrap_or_default();
+                    if only.is_some_and(|only| only != name) {
+                        continue;
+                    }
+                    if status.state == ServiceState::Down {
+                        down.insert(name.to_owned());
+                    }
+                    let name = name.to_string_lossy();
+                    match status.pid {
+                        Some(pid) => reply += &format!("{name} {} {pid}\n", status.state),
+                        None => reply += &format!("{name} {}\n", status.state),
+                    }
+                }
+                for name in &self.disabled {
+                    if only.is_none_or(|only| only == name) && !down.contains(name.as_os_str()) {
+                        reply += &format!("{} down\n", name.to_string_lossy());
+                    }
+                }
+                match only {
+                    Some(only) if reply.is_empty() => format!("error: no such service {only:?}\n"),
+                    _ => reply,
+                }
+            }
+        }
+    }
+
+    /// Lets the completed service `name` be spawned again
+    fn uncomplete(&mut self, name: &str) {
+        for (hash, service) in &mut self.running {
+            if service_name(hash) == Some(OsStr::new(name)) {
+                service.completed = false;
+                service.crashes.clear();
+            }
+        }
+    }
+
+    /// Sends the running service `name` `signal`, or its term signal if `None`, the exit does
+    /// not count as a failure
+    fn signal_service(&mut self, name: &str, signal: Option<Signal>) -> String {
+        let now = self.clock.now();
+        let mut found = false;
+        for (hash, service) in &mut self.running {
+            if service_name(hash) != Some(OsStr::new(name)) {
+                continue;
+            }
+            found = true;
+            if let Some(child) = &service.child {
+                let signal = signal.unwrap_or(service.term_signal);
+                info!("signal {hash} with {signal:?}");
+                send_signal(hash, child.id(), signal, self.process_group);
+                service.term_sent = Some(now);
+                service.killed |= signal == Signal::SIGKILL;
+                resume(&mut service.paused, hash, child.id(), self.process_group);
+            }
+        }
+        if found {
+            "ok\n".into()
+        } else {
+            format!("error: no such service {name:?}\n")
+        }
+    }
+
+    /// Sends the running service `name` `signal` without stopping it, SIGSTOP pauses it until
+    /// SIGCONT
+    #[cfg(unix)]
+    fn signal_running(&mut self, name: &str, signal: Signal) -> String {
+        let mut found = false;
+        for (hash, service) in &mut self.running {
+            if service_name(hash) != Some(OsStr::new(name)) {
+                continue;
+            }
+            found = true;
+            if let Some(child) = &service.child {
+                info!("signal {hash} with {signal:?}");
+                send_signal(hash, child.id(), signal, self.process_group);
+                match signal {
+                    Signal::SIGSTOP => service.paused = true,
+                    Signal::SIGCONT => service.paused = false,
+                    _ => {}
+                }
+            }
+        }
+        if found {
+            "ok\n".into()
+        } else {
+            format!("error: no such service {name:?}\n")
+        }
+    }
+
+    /// Records the exit status of a child reaped outside of [`Child::try_wait`]
+    #[cfg(unix)]
+    fn reaped(&mut self, pid: Pid, status: ExitStatus) {
+        let id = pid.as_raw() as u32;
+        if let Some(service) = self
+            .running
+            .values_mut()
+            .find(|service| service.child.as_ref().is_some_and(|child| child.id() == id))
+        {
+            service.reaped = Some(status);
+        } else if let Some(health) =
+            self.running.values_mut().map(|s| &mut s.health).find(|h| {
+                h.reaped.is_none() && h.child.as_ref().is_some_and(|child| child.id() == id)
+            })
+        {
+            health.reaped = Some(status);
+        } else if let Some(i) = self
+            .finishing
+            .iter()
+            .position(|(_, child, _)| child.id() == id)
+        {
+            let (hash, ..) = self.finishing.swap_remove(i);
+            info!("{hash} finished with {status}");
+        } else if !self
+            .loggers
+            .values_mut()
+            .any(|logger| logger.reaped(id, status))
+        {
+            info!("reaped orphan {pid} with {status}");
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        let mut children: Vec<_> = self
+            .running
+            .drain()
+            .flat_map(|(hash, service)| match service.reaped {
+                Some(status) => {
+                    info!("{hash} stopped with {status}");
+                    None
+                }
+                None => {
+                    let child = service.child?;
+                    info!("stop {hash}");
+                    send_signal(&hash, child.id(), service.term_signal, self.process_group);
+                    Some((hash, child))
+                }
+            })
+            .collect();
+        // Finish scripts are not signalled but may complete within the timeout
+        children.extend(
+            self.finishing
+                .drain(..)
+                .map(|(hash, child, _)| (hash, child.into())),
+        );
+        self.wait_or_kill(children, self.cgroup_root.as_deref());
+
+        // Loggers go last, to read what the services wrote until they exited
+        let group = ProcessGroup::new(self.process_group);
+        let loggers = self
+            .loggers
+            .drain()
+            .flat_map(|(_, l)| l.stop(group))
+            .map(|(hash, child)| (hash, child.into()))
+            .collect();
+        self.wait_or_kill(loggers, None);
+    }
+
+    /// Waits for `children` up to the shutdown timeout and kills those still running
+    fn wait_or_kill(&self, mut children: Vec<(Shash, Process)>, cgroup_root: Option<&Path>) {
+        let deadline = Instant::now() + self.shutdown_timeout;
+        loop {
+            children.retain_mut(|(hash, child)| match child.try_wait() {
+                Ok(None) => true,
+                Ok(Some(status)) => {
+                    self.events.on_exit(hash, status);
+                    remove_cgroup(cgroup_root, hash);
+                    false
+                }
+                Err(err) => {
+                    error!("get exit status for {hash} failed: {err}");
+                    false
+                }
+            });
+            if children.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        for (hash, mut child) in children {
+            self.events.on_kill(&hash);
+            if !kill_cgroup(cgroup_root, &hash) {
+                send_signal(&hash, child.id(), Signal::SIGKILL, self.process_group);
+            }
+            if let Err(err) = child.wait() {
+                error!("wait for {hash} failed: {err}");
+            }
+            remove_cgroup(cgroup_root, &hash);
+        }
+    }
+}
+
+/// Builds a [`Stepper`], every setting not set keeps the default of [`Stepper::new`]
+pub struct StepperBuilder {
+    stepper: Stepper,
+}
+
+impl StepperBuilder {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            stepper: Stepper::new(dir),
+        }
+    }
+
+    /// Sets what makes up the identity of a service
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.stepper.identity = identity;
+        self
+    }
+
+    /// Sets [`Stepper::backoff_base`]
+    pub fn backoff_base(mut self, backoff_base: Duration) -> Self {
+        self.stepper.backoff_base = backoff_base;
+        self
+    }
+
+    /// Sets [`Stepper::backoff_cap`]
+    pub fn backoff_cap(mut self, backoff_cap: Duration) -> Self {
+        self.stepper.backoff_cap = backoff_cap;
+        self
+    }
+
+    /// Sets [`Stepper::backoff_reset`]
+    pub fn backoff_reset(mut self, backoff_reset: Duration) -> Self {
+        self.stepper.backoff_reset = backoff_reset;
+        self
+    }
+
+    /// Sets [`Stepper::min_uptime`]
+    pub fn min_uptime(mut self, min_uptime: Duration) -> Self {
+        self.stepper.min_uptime = min_uptime;
+        self
+    }
+
+    /// Sets [`Stepper::shutdown_timeout`]
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.stepper.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Sets [`Stepper::kill_timeout`]
+    pub fn kill_timeout(mut self, kill_timeout: Duration) -> Self {
+        self.stepper.kill_timeout = kill_timeout;
+        self
+    }
+
+    /// Sets [`Stepper::finish_timeout`]
+    pub fn finish_timeout(mut self, finish_timeout: Duration) -> Self {
+        self.stepper.finish_timeout = finish_timeout;
+        self
+    }
+
+    /// Sets [`Stepper::debounce`]
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.stepper.debounce = debounce;
+        self
+    }
+
+    /// Sets [`Stepper::ready_timeout`]
+    pub fn ready_timeout(mut self, ready_timeout: Duration) -> Self {
+        self.stepper.ready_timeout = ready_timeout;
+        self
+    }
+
+    /// Sets [`Stepper::health_interval`]
+    pub fn health_interval(mut self, health_interval: Duration) -> Self {
+        self.stepper.health_interval = health_interval;
+        self
+    }
+
+    /// Sets [`Stepper::health_failures`]
+    pub fn health_failures(mut self, health_failures: u32) -> Self {
+        self.stepper.health_failures = health_failures;
+        self
+    }
+
+    /// Sets [`Stepper::error_cooldown`]
+    pub fn error_cooldown(mut self, error_cooldown: Duration) -> Self {
+        self.stepper.error_cooldown = error_cooldown;
+        self
+    }
+
+    /// Sets [`Stepper::empty_confirmations`]
+    pub fn empty_confirmations(mut self, empty_confirmations: u32) -> Self {
+        self.stepper.empty_confirmations = empty_confirmations;
+        self
+    }
+
+    /// Sets [`Stepper::max_stops`]
+    pub fn max_stops(mut self, max_stops: usize) -> Self {
+        self.stepper.max_stops = Some(max_stops);
+        self
+    }
+
+    /// Sets [`Stepper::restart_limit`]
+    pub fn restart_limit(mut self, restart_limit: RestartLimit) -> Self {
+        self.stepper.restart_limit = Some(restart_limit);
+        self
+    }
+
+    /// Sets [`Stepper::restart_policy`]
+    pub fn restart_policy(mut self, restart_policy: RestartPolicy) -> Self {
+        self.stepper.restart_policy = restart_policy;
+        self
+    }
+
+    /// Sets [`Stepper::overrides`]
+    pub fn overrides(mut self, overrides: HashMap<String, ServiceOverrides>) -> Self {
+        self.stepper.overrides = overrides;
+        self
+    }
+
+    /// Sets [`Stepper::restart_dependents`]
+    pub fn restart_dependents(mut self, restart_dependents: bool) -> Self {
+        self.stepper.restart_dependents = restart_dependents;
+        self
+    }
+
+    /// Sets [`Stepper::supervise`]
+    pub fn supervise(mut self, supervise: bool) -> Self {
+        self.stepper.supervise = supervise;
+        self
+    }
+
+    /// Sets [`Stepper::kill_stale`]
+    pub fn kill_stale(mut self, kill_stale: bool) -> Self {
+        self.stepper.kill_stale = kill_stale;
+        self
+    }
+
+    /// Sets [`Stepper::log_mode`]
+    pub fn log_mode(mut self, log_mode: LogMode) -> Self {
+        self.stepper.log_mode = log_mode;
+        self
+    }
+
+    /// Sets [`Stepper::log_dir`]
+    pub fn log_dir(mut self, log_dir: PathBuf) -> Self {
+        self.stepper.log_dir = Some(log_dir);
+        self
+    }
+
+    /// Sets [`Stepper::chdir`]
+    pub fn chdir(mut self, chdir: bool) -> Self {
+        self.stepper.chdir = chdir;
+        self
+    }
+
+    /// Sets [`Stepper::reap_orphans`]
+    pub fn reap_orphans(mut self, reap_orphans: bool) -> Self {
+        self.stepper.reap_orphans = reap_orphans;
+        self
+    }
+
+    /// Sets [`Stepper::recursive`]
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.stepper.recursive = recursive;
+        self
+    }
+
+    /// Sets [`Stepper::ignore`]
+    pub fn ignore(mut self, ignore: Vec<Pattern>) -> Self {
+        self.stepper.ignore = ignore;
+        self
+    }
+
+    /// Sets [`Stepper::process_group`]
+    pub fn process_group(mut self, process_group: bool) -> Self {
+        self.stepper.process_group = process_group;
+        self
+    }
+
+    /// Sets [`Stepper::scan_timeout`]
+    pub fn scan_timeout(mut self, scan_timeout: Duration) -> Self {
+        self.stepper.scan_timeout = Some(scan_timeout);
+        self
+    }
+
+    /// Sets [`Stepper::hash_timeout`]
+    pub fn hash_timeout(mut self, hash_timeout: Duration) -> Self {
+        self.stepper.hash_timeout = Some(hash_timeout);
+        self
+    }
+
+    /// Sets [`Stepper::hash_threads`]
+    pub fn hash_threads(mut self, hash_threads: usize) -> Self {
+        self.stepper.hash_threads = hash_threads;
+        self
+    }
+
+    /// Sets [`Stepper::max_concurrent`]
+    pub fn max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.stepper.max_concurrent = Some(max_concurrent);
+        self
+    }
+
+    /// Sets [`Stepper::hash_alg`]
+    pub fn hash_alg(mut self, hash_alg: HashAlg) -> Self {
+        self.stepper.hash_alg = hash_alg;
+        self
+    }
+
+    /// Sets [`Stepper::hash_limit`]
+    pub fn hash_limit(mut self, hash_limit: u64) -> Self {
+        self.stepper.hash_limit = Some(hash_limit);
+        self
+    }
+
+    /// Sets [`Stepper::hash_cache`]
+    pub fn hash_cache(mut self, hash_cache: bool) -> Self {
+        self.stepper.hash_cache = hash_cache;
+        self
+    }
+
+    /// Sets [`Stepper::dry_run`]
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.stepper.dry_run = dry_run;
+        self
+    }
+
+    /// Sets [`Stepper::env_file`]
+    pub fn env_file(mut self, env_file: EnvFile) -> Self {
+        self.stepper.env_file = env_file;
+        self
+    }
+
+    /// Sets [`Stepper::limits`]
+    #[cfg(unix)]
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.stepper.limits = limits;
+        self
+    }
+
+    /// Sets [`Stepper::cgroup_root`]
+    pub fn cgroup_root(mut self, cgroup_root: PathBuf) -> Self {
+        self.stepper.cgroup_root = Some(cgroup_root);
+        self
+    }
+
+    /// Sets [`Stepper::events`]
+    pub fn event_sink(mut self, events: impl EventSink + 'static) -> Self {
+        self.stepper.events = Box::new(events);
+        self
+    }
+
+    /// Sets [`Stepper::clock`]
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.stepper.clock = Box::new(clock);
+        self
+    }
+
+    pub fn build(self) -> Stepper {
+        self.stepper
+    }
+}
+
+/// The last error of an entry and how often it repeated
+struct Failing {
+    message: String,
+    /// When the error was logged last
+    logged: Instant,
+    count: u32,
+}
+
+/// A hash of `run` and the metadata of the file it was computed for
+#[derive(Clone)]
+struct CachedHash {
+    stamp: Stamp,
+    hash: Shash,
+    /// When the metadata was read before hashing
+    hashed: SystemTime,
+    alg: HashAlg,
+    limit: Option<u64>,
+}
+
+impl CachedHash {
+    /// Whether the hash still holds for a file with `stamp` hashed with `alg` up to `limit`
+    ///
+    /// Modifications within a second of hashing may keep the modification time on filesystems with
+    /// a coarse clock, such a hash is not trusted.
+    fn holds(&self, stamp: &Stamp, alg: HashAlg, limit: Option<u64>) -> bool {
+        self.stamp == *stamp
+            && (self.alg, self.limit) == (alg, limit)
+            && self
+                .hashed
+                .duration_since(stamp.modified)
+                .is_ok_and(|age| age >= Duration::from_secs(1))
+    }
+}
+
+/// The metadata of a file which changes along with its contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamp {
+    modified: SystemTime,
+    len: u64,
+    #[cfg(unix)]
+    dev: u64,
+    #[cfg(unix)]
+    ino: u64,
+}
+
+impl Stamp {
+    fn new(metadata: &std::fs::Metadata) -> Option<Self> {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        Some(Self {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+            #[cfg(unix)]
+            dev: metadata.dev(),
+            #[cfg(unix)]
+            ino: metadata.ino(),
+        })
+    }
+}
+
+/// A directory entry that is about to be supervised
+struct Candidate {
+    dir: PathBuf,
+    run: PathBuf,
+    hash: Shash,
+    interval: Option<Duration>,
+    /// The names of the services which have to be running first
+    needs: Vec<OsString>,
+    overrides: ServiceOverrides,
+    /// The hash with the metadata it was computed for, `None` if the metadata is unknown
+    cached: Option<CachedHash>,
+}
+
+impl Candidate {
+    /// Hashes `run` of the entry at `dir`, returning `None` if the service is down or there is no
+    /// `run`
+    fn new(
+        dir: PathBuf,
+        identity: Identity,
+        alg: HashAlg,
+        limit: Option<u64>,
+        disabled: &HashSet<OsString>,
+        overrides: &HashMap<String, ServiceOverrides>,
+        cache: Option<&HashMap<PathBuf, CachedHash>>,
+    ) -> Result<Option<Self>, StepError> {
+        let down = dir.file_name().is_some_and(|name| disabled.contains(name));
+        if down || dir.join("down").exists() {
+            info!("{dir:?} is down");
+            return Ok(None);
+        }
+        let run = run_script(&dir);
+        let hashed = SystemTime::now();
+        let stamp = match metadata(&run) {
+            Ok(m) if !m.is_file() => return Err(StepError::RunNotFile(run)),
+            Ok(m) => cache.and_then(|_| Stamp::new(&m)),
+            Err(_) => None,
+        };
+        let cached = stamp.as_ref().and_then(|stamp| {
+            cache?
+                .get(&run)
+                .filter(|cached| cached.holds(stamp, alg, limit))
+                .cloned()
+        });
+        let hash = match &cached {
+            Some(cached) => Ok(cached.hash.clone()),
+            None => identity.shash(&run, alg, limit),
+        };
+        let hash = match hash {
+            Ok(hash) => hash,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!("{dir:?} has no run, skipping");
+                return Ok(None);
+            }
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                return Err(StepError::RunNotReadable(run, err))
+            }
+            Err(err) => return Err(StepError::Shash(run, err)),
+        };
+        let interval = read_interval(&dir);
+        let needs = read_needs(&dir);
+        let overrides = dir
+            .file_name()
+            .and_then(OsStr::to_str)
+            .and_then(|name| overrides.get(name))
+            .copied()
+            .unwrap_or_default();
+        let cached = cached.or_else(|| {
+            stamp.map(|stamp| CachedHash {
+                stamp,
+                hash: hash.clone(),
+                hashed,
+                alg,
+                limit,
+            })
+        });
+        Ok(Some(Self {
+            dir,
+            run,
+            hash,
+            interval,
+            needs,
+            overrides,
+            cached,
+        }))
+    }
+}
+
+/// Reads `<service_dir>/needs`, the whitespace separated names of other service directories
+fn read_needs(service_dir: &Path) -> Vec<OsString> {
+    read_to_string(service_dir.join("needs"))
+        .map(|needs| needs.split_whitespace().map(OsString::from).collect())
+        .unwrap_or_default()
+}
+
+/// Orders the candidates so that services come after the ones they need, keeping the order
+/// otherwise, and returns the ones in or behind a cycle separately
+///
+/// Needs which are not among the candidates do not constrain the order.
+fn order_by_needs(mut pending: Vec<Candidate>) -> (Vec<Candidate>, Vec<Candidate>) {
+    let name = |c: &Candidate| c.dir.file_name().map(OsStr::to_os_string);
+    let mut ordered: Vec<Candidate> = Vec::with_capacity(pending.len());
+    loop {
+        let names: HashSet<_> = pending.iter().flat_map(name).collect();
+        let Some(i) = pending
+            .iter()
+            .position(|c| c.needs.iter().all(|need| !names.contains(need)))
+        else {
+            break;
+        };
+        ordered.push(pending.remove(i));
+    }
+    (ordered, pending)
+}
+
+/// Reads `<service_dir>/interval`, the number of millis between checks of the service
+fn read_interval(service_dir: &Path) -> Option<Duration> {
+    let path = service_dir.join("interval");
+    let interval = read_to_string(&path).ok()?;
+    match interval.trim().parse() {
+        Ok(millis) => Some(Duration::from_millis(millis)),
+        Err(err) => {
+            warn!("ignoring {path:?}: {err}");
+            None
+        }
+    }
+}
+
+/// Reads `<service_dir>/term-signal`, the signal asking the service to stop, SIGTERM if missing
+/// or invalid
+#[cfg(unix)]
+fn read_term_signal(service_dir: &Path) -> Signal {
+    let path = service_dir.join("term-signal");
+    let Ok(signal) = read_to_string(&path) else {
+        return Signal::SIGTERM;
+    };
+    parse_signal(signal.trim()).unwrap_or_else(|| {
+        warn!("ignoring {path:?}: unknown signal {:?}", signal.trim());
+        Signal::SIGTERM
+    })
+}
+
+/// Windows has no signals to choose from
+#[cfg(windows)]
+fn read_term_signal(_service_dir: &Path) -> Signal {
+    Signal::SIGTERM
+}
+
+/// Reads `<service_dir>/reload-signal`, the signal making the service pick up a changed `run`,
+/// `None` if missing or invalid so the service restarts
+#[cfg(unix)]
+fn read_reload_signal(service_dir: &Path) -> Option<Signal> {
+    let path = service_dir.join("reload-signal");
+    let signal = read_to_string(&path).ok()?;
+    let parsed = parse_signal(signal.trim());
+    if parsed.is_none() {
+        warn!("ignoring {path:?}: unknown signal {:?}", signal.trim());
+    }
+    parsed
+}
+
+/// Windows has no signals to reload with
+#[cfg(windows)]
+fn read_reload_signal(_service_dir: &Path) -> Option<Signal> {
+    None
+}
+
+/// Reads the port of the `check-port` file, warning about an invalid one
+fn read_check_port(service_dir: &Path) -> Option<u16> {
+    let path = service_dir.join("check-port");
+    let port = read_to_string(&path).ok()?;
+    port.trim()
+        .parse()
+        .inspect_err(|err| warn!("ignoring {path:?}: {err}"))
+        .ok()
+}
+
+/// Reads `<service_dir>/notification-fd`, the descriptor the service writes a newline to once it
+/// is ready, like in s6
+#[cfg(unix)]
+fn read_notification_fd(service_dir: &Path) -> Option<i32> {
+    let path = service_dir.join("notification-fd");
+    let fd = read_to_string(&path).ok()?;
+    fd.trim().parse().ok().filter(|fd| *fd > 2).or_else(|| {
+        warn!("ignoring {path:?}, expected a descriptor above 2");
+        None
+    })
+}
+
+/// Makes `command` inherit the writing end of a pipe as `fd`, returning the non-blocking reading
+/// end
+#[cfg(unix)]
+fn notification_pipe(command: &mut Command, fd: i32) -> io::Result<PipeReader> {
+    use nix::libc::{dup2, fcntl, F_GETFL, F_SETFD, F_SETFL, O_NONBLOCK};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let (reader, writer) = io::pipe()?;
+    let raw = reader.as_raw_fd();
+    // SAFETY: the descriptor is owned by `reader`
+    if unsafe { fcntl(raw, F_SETFL, fcntl(raw, F_GETFL) | O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: dup2 and fcntl are async-signal-safe, `writer` lives as long as the closure
+    unsafe {
+        command.pre_exec(move || {
+            let raw = writer.as_raw_fd();
+            // The duplicate does not inherit close-on-exec, the original has to clear it
+            let res = if raw == fd {
+                fcntl(raw, F_SETFD, 0)
+            } else {
+                dup2(raw, fd)
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(reader)
+}
+
+/// Whether the service wrote the newline, `false` if it closed the pipe without, `None` if it did
+/// neither yet
+#[cfg(unix)]
+fn notified(reader: &mut PipeReader) -> Option<bool> {
+    let mut buf = [0; 64];
+    loop {
+        match io::Read::read(reader, &mut buf) {
+            Ok(0) => return Some(false),
+            Ok(n) if buf[..n].contains(&b'\n') => return Some(true),
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return None,
+            Err(err) => {
+                warn!("reading notification failed: {err}");
+                return Some(false);
+            }
+        }
+    }
+}
+
+/// Whether `127.0.0.1:port` accepts a connection
+fn port_open(port: u16) -> bool {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    TcpStream::connect_timeout(&addr, Duration::from_millis(100)).is_ok()
+}
+
+/// Parses a signal number or name, with or without the `SIG` prefix
+#[cfg(unix)]
+fn parse_signal(signal: &str) -> Option<Signal> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+    let signal = signal.to_ascii_uppercase();
+    match signal.strip_prefix("SIG") {
+        Some(_) => signal.parse().ok(),
+        None => format!("SIG{signal}").parse().ok(),
+    }
+}
+
+/// The name of the service directory of `run`
+pub(crate) fn service_name(hash: &Shash) -> Option<&OsStr> {
+    hash.path().parent()?.file_name()
+}
+
+/// The entries of a scanned directory and the errors of the ones which could not be read
+type Scan = Result<(Vec<DirEntry>, Vec<StepError>), StepError>;
+
+fn scan_all(dir: &Path, ignore: &[Pattern], recursive: bool) -> Scan {
+    let (mut entries, mut errors) = (vec![], vec![]);
+    scan(dir, ignore, recursive, &mut entries, &mut errors)?;
+    Ok((entries, errors))
+}
+
+/// Collects the entries of `dir`, descending into directories without `run` if `recursive`
+fn scan(
+    dir: &Path,
+    ignore: &[Pattern],
+    recursive: bool,
+    entries: &mut Vec<DirEntry>,
+    errors: &mut Vec<StepError>,
+) -> Result<(), StepError> {
+    read_dir(dir)
+        .map_err(|err| StepError::ReadDir(dir.into(), err))?
+        .map(|d| {
+            let d = d.map_err(|err| StepError::ReadDirEntry(dir.into(), err))?;
+            let name = d.file_name();
+            if ignore.iter().any(|p| p.matches(&name.to_string_lossy())) {
+                debug!("{:?} is ignored", d.path());
+                return Ok(());
+            }
+            let is_group = recursive
+                && d.file_type().is_ok_and(|t| t.is_dir())
+                && symlink_metadata(run_script(&d.path())).is_err();
+            if is_group {
+                scan(&d.path(), ignore, recursive, entries, errors)
+            } else {
+                entries.push(d);
+                Ok(())
+            }
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .flat_map(Result::err)
+        .for_each(|err: StepError| errors.push(err));
+    Ok(())
+}
+
+/// Signals the child `pid`, or its whole process group if `group` is set
+fn send_signal(hash: &Shash, pid: u32, signal: Signal, group: bool) {
+    terminate(hash, pid, signal, ProcessGroup::new(group));
+}
+
+/// Continues the child `pid` right after its term signal if it is `paused`, so it gets to handle
+/// the signal
+fn resume(paused: &mut bool, hash: &Shash, pid: u32, group: bool) {
+    if std::mem::take(paused) {
+        #[cfg(unix)]
+        send_signal(hash, pid, Signal::SIGCONT, group);
+        #[cfg(not(unix))]
+        let _ = (hash, pid, group);
+    }
+}
+
+/// Sets the nice value of the child right before exec
+#[cfg(unix)]
+fn set_nice(command: &mut Command, nice: i32) {
+    use nix::libc::{setpriority, PRIO_PROCESS};
+    use std::os::unix::process::CommandExt;
+
+    // SAFETY: setpriority is async-signal-safe and nothing is allocated
+    unsafe {
+        command.pre_exec(move || {
+            if setpriority(PRIO_PROCESS, 0, nice) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Sets the file mode creation mask of the child right before exec
+#[cfg(unix)]
+fn set_umask(command: &mut Command, umask: u32) {
+    use nix::libc::{mode_t, umask as set};
+    use std::os::unix::process::CommandExt;
+
+    // SAFETY: umask is async-signal-safe and cannot fail
+    unsafe {
+        command.pre_exec(move || {
+            set(umask as mode_t);
+            Ok(())
+        });
+    }
+}
+
+/// Kills everything in the cgroup of the service of `hash`, returning whether it could
+fn kill_cgroup(cgroup_root: Option<&Path>, hash: &Shash) -> bool {
+    #[cfg(all(feature = "cgroup", target_os = "linux"))]
+    {
+        cgroup_root
+            .and_then(|root| Cgroup::new(root, hash))
+            .is_some_and(|cgroup| cgroup.kill())
+    }
+    #[cfg(not(all(feature = "cgroup", target_os = "linux")))]
+    {
+        let _ = (cgroup_root, hash);
+        false
+    }
+}
+
+/// Removes the cgroup of the exited service of `hash`, if it is empty
+fn remove_cgroup(cgroup_root: Option<&Path>, hash: &Shash) {
+    #[cfg(all(feature = "cgroup", target_os = "linux"))]
+    if let Some(cgroup) = cgroup_root.and_then(|root| Cgroup::new(root, hash)) {
+        cgroup.remove();
+    }
+    #[cfg(not(all(feature = "cgroup", target_os = "linux")))]
+    let _ = (cgroup_root, hash);
+}
+
+fn backoff(base: Duration, cap: Duration, failures: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(failures)).min(cap)
+}
+
+/// What a single [`Stepper::invoke`] did
+#[derive(Debug, Default)]
+pub struct StepReport {
+    /// The services spawned in this step
+    pub spawned: Vec<Shash>,
+    /// The number of services which failed to spawn
+    pub failures: usize,
+    /// The errors of single entries, which were skipped, in addition to being logged
+    pub errors: Vec<StepError>,
+    /// How long the step took
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Error)]
+pub enum StepError {
+    #[error("Reading dir {0:?} failed: {1}")]
+    ReadDir(PathBuf, #[source] io::Error),
+    #[error("Reading dir {0:?} took longer than {1:?}")]
+    ScanTimeout(PathBuf, Duration),
+    #[error("Reading dir entry on {0:?} failed: {1}")]
+    ReadDirEntry(PathBuf, #[source] io::Error),
+    #[error("Hashing on {0:?} failed: {1}")]
+    Shash(PathBuf, #[source] io::Error),
+    #[error("Service {0:?} is not readable: {1}")]
+    RunNotReadable(PathBuf, #[source] io::Error),
+    #[error("Service {0:?} is not a regular file")]
+    RunNotFile(PathBuf),
+    #[error("Spawn process {0} failed: {1}")]
+    Spawn(Shash, #[source] io::Error),
+    #[error("Dependency cycle among {0:?}")]
+    DependencyCycle(Vec<PathBuf>),
+    #[cfg(unix)]
+    #[error("Invalid limits of {0:?}: {1}")]
+    Limits(PathBuf, #[source] LimitsError),
+    #[error("Invalid service.toml of {0:?}: {1}")]
+    ServiceConfig(PathBuf, #[source] ServiceConfigError),
+    #[error("Reading env of {0:?} failed: {1}")]
+    Env(PathBuf, #[source] io::Error),
+    #[error("Opening log for {0:?} failed: {1}")]
+    Log(PathBuf, #[source] io::Error),
+    #[error("Starting the logger of {0:?} failed: {1}")]
+    Logger(PathBuf, #[source] io::Error),
+    #[cfg(unix)]
+    #[error("Resolving credentials for {0:?} failed: {1}")]
+    Credential(PathBuf, #[source] CredentialError),
+    #[cfg(all(feature = "cgroup", target_os = "linux"))]
+    #[error("Setting up the cgroup of {0:?} failed: {1}")]
+    Cgroup(PathBuf, #[source] CgroupError),
+}
+
+impl StepError {
+    /// The entry the error is about, the first one of a cycle
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::ReadDir(path, _)
+            | Self::ReadDirEntry(path, _)
+            | Self::Shash(path, _)
+            | Self::RunNotReadable(path, _)
+            | Self::ScanTimeout(path, _)
+            | Self::RunNotFile(path)
+            | Self::ServiceConfig(path, _)
+            | Self::Env(path, _)
+            | Self::Log(path, _)
+            | Self::Logger(path, _) => path,
+            #[cfg(unix)]
+            Self::Limits(path, _) | Self::Credential(path, _) => path,
+            #[cfg(all(feature = "cgroup", target_os = "linux"))]
+            Self::Cgroup(path, _) => path,
+            Self::Spawn(hash, _) => hash.path(),
+            Self::DependencyCycle(dirs) => dirs.first().map_or(Path::new(""), PathBuf::as_path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::TestClock;
+    use crate::{ChannelSink, Event};
+    #[cfg(unix)]
+    use nix::sys::signal::kill;
+    #[cfg(unix)]
+    use nix::sys::wait::{waitpid, WaitStatus};
+    #[cfg(unix)]
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    #[cfg(unix)]
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::mpsc;
+    #[cfg(unix)]
+    use time::format_description::well_known::Rfc3339;
+    #[cfg(unix)]
+    use time::OffsetDateTime;
+
+    #[test]
+    fn step_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res"));
+        stepper.invoke().unwrap();
+
+        for child in stepper.running.values_mut().flat_map(|s| s.child.as_mut()) {
+            let _ = child.kill();
+        }
+
+        // Windows runs the `run.cmd` next to `run`
+        assert_eq!(
+            stepper.running.keys().collect::<HashSet<_>>(),
+            HashSet::from([
+                &Shash::try_from(run_script(Path::new("test_res/b")).as_path()).unwrap(),
+                &Shash::try_from(run_script(Path::new("test_res/d")).as_path()).unwrap()
+            ])
+        );
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[test]
+    fn backoff_test() {
+        let base = Duration::from_secs(1);
+        let cap = Duration::from_secs(5);
+        assert_eq!(backoff(base, cap, 0), Duration::from_secs(1));
+        assert_eq!(backoff(base, cap, 2), Duration::from_secs(4));
+        assert_eq!(backoff(base, cap, 3), cap);
+        assert_eq!(backoff(base, cap, u32::MAX), cap);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backoff_respawn_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/backoff"));
+        stepper.backoff_base = Duration::from_secs(60);
+        let hash = Shash::try_from(Path::new("test_res/backoff/fail/run")).unwrap();
+
+        stepper.invoke().unwrap();
+        for _ in 0..100 {
+            if stepper.running[&hash].child.is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            stepper.invoke().unwrap();
+        }
+        stepper.invoke().unwrap();
+
+        let service = &stepper.running[&hash];
+        assert!(service.child.is_none());
+        assert_eq!(service.failures, 1);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restart_limit_test() {
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/backoff"))
+            .backoff_base(Duration::ZERO)
+            .restart_limit(RestartLimit {
+                exits: 3,
+                window: Duration::from_secs(60),
+            })
+            .build();
+        let hash = Shash::try_from(Path::new("test_res/backoff/fail/run")).unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper
+                .running
+                .get(&hash)
+                .is_some_and(|s| s.completed)
+                .then_some(())
+        });
+        // Given up on the third failure, not respawned for it
+        assert_eq!(stepper.status()[0].restarts, 2);
+        assert_eq!(
+            stepper.status()[0].state,
+            ServiceState::Completed(Termination::Exited(1))
+        );
+        assert!(stepper.invoke().unwrap().spawned.is_empty());
+
+        assert_eq!(stepper.control(&ControlCommand::Up("fail".into())), "ok\n");
+        assert_eq!(stepper.invoke().unwrap().spawned, [hash]);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn overrides_test() {
+        let never = ServiceOverrides {
+            restart: Some(RestartPolicy::Never),
+            ..ServiceOverrides::default()
+        };
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/backoff"))
+            .backoff_base(Duration::ZERO)
+            .overrides(HashMap::from([("fail".into(), never)]))
+            .build();
+        let hash = Shash::try_from(Path::new("test_res/backoff/fail/run")).unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper
+                .running
+                .get(&hash)
+                .is_some_and(|s| s.completed)
+                .then_some(())
+        });
+        // The global policy restarts it on failure, its own one doesn't
+        assert_eq!(stepper.status()[0].restarts, 0);
+        assert_eq!(
+            stepper.status()[0].state,
+            ServiceState::Completed(Termination::Exited(1))
+        );
+        assert!(stepper.invoke().unwrap().spawned.is_empty());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn next_step_test() {
+        let dir = empty_dir("next_step");
+        service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let mut stepper = Stepper::builder(dir.clone())
+            .backoff_base(Duration::from_secs(60))
+            .build();
+        let hash = stepper.invoke().unwrap().spawned.remove(0);
+        // Nothing is due while it runs, its exit wakes the supervisor
+        assert_eq!(stepper.next_step(), None);
+
+        let pid = stepper.running_pids()[0].1;
+        kill(Pid::from_raw(pid as i32), Signal::SIGKILL).unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&hash].child.is_none().then_some(())
+        });
+        assert_eq!(stepper.next_step(), Some(stepper.running[&hash].respawn_at));
+        assert!(stepper.next_step().unwrap() > Instant::now() + Duration::from_secs(30));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shutdown_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res"));
+        stepper.shutdown_timeout = Duration::from_secs(5);
+        stepper.invoke().unwrap();
+
+        let pids: Vec<_> = stepper
+            .running
+            .values()
+            .flat_map(|s| s.child.as_ref())
+            .map(|child| Pid::from_raw(child.id() as i32))
+            .collect();
+        assert!(!pids.is_empty());
+
+        stepper.shutdown();
+
+        assert!(stepper.running.is_empty());
+        for pid in pids {
+            assert!(kill(pid, None).is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kill_timeout_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        stepper.kill_timeout = Duration::from_millis(200);
+        let hash = Shash::try_from(Path::new("test_res/stubborn/svc/run")).unwrap();
+        stepper.invoke().unwrap();
+        // Let the service install its SIGTERM trap
+        thread::sleep(Duration::from_millis(200));
+
+        stepper.dir = empty_dir("kill_timeout");
+        stepper.invoke().unwrap();
+        let sent = stepper.running[&hash].term_sent.unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stepper.invoke().unwrap();
+        let service = &stepper.running[&hash];
+        assert_eq!(service.term_sent, Some(sent));
+        assert!(!service.killed);
+
+        thread::sleep(Duration::from_millis(200));
+        stepper.invoke().unwrap();
+        assert!(stepper.running.get(&hash).is_none_or(|s| s.killed));
+        for _ in 0..100 {
+            if stepper.running.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            stepper.invoke().unwrap();
+        }
+        assert!(stepper.running.is_empty());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    /// Builds the candidate for the service at `dir`
+    fn candidate(dir: &Path) -> Result<Option<Candidate>, StepError> {
+        Candidate::new(
+            dir.into(),
+            Identity::default(),
+            HashAlg::default(),
+            None,
+            &HashSet::new(),
+            &HashMap::new(),
+            None,
+        )
+    }
+
+    fn empty_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("runsvdir-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates the service `name` in `dir` running `script`, returning its directory
+    fn service(dir: &Path, name: &str, script: &str) -> PathBuf {
+        let service = dir.join(name);
+        std::fs::create_dir_all(&service).unwrap();
+        let run = service.join("run");
+        std::fs::write(&run, script).unwrap();
+        #[cfg(unix)]
+        std::fs::set_permissions(&run, std::fs::Permissions::from_mode(0o755)).unwrap();
+        service
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn log_file_test() {
+        let log_dir = empty_dir("log_file");
+        let log = log_dir.join("echo/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/logging"));
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+        stepper.invoke().unwrap();
+
+        let hash = Shash::try_from(Path::new("test_res/logging/echo/run")).unwrap();
+        if let Some(child) = stepper.running.get_mut(&hash).unwrap().child.as_mut() {
+            child.wait().unwrap();
+        }
+
+        assert_eq!(std::fs::read_to_string(log).unwrap(), "hello\n");
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[test]
+    fn down_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/down"));
+        stepper.invoke().unwrap();
+
+        assert!(stepper.running.is_empty());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn down_running_test() {
+        let dir = empty_dir("down_running");
+        let service = service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let _ = std::fs::remove_file(service.join("down"));
+        let mut stepper = Stepper::new(dir);
+        wait_until(|| (!stepper.invoke().unwrap().spawned.is_empty()).then_some(()));
+
+        // A down file appearing stops the service like a removed directory
+        std::fs::write(service.join("down"), "").unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running.is_empty().then_some(())
+        });
+        assert!(stepper.invoke().unwrap().spawned.is_empty());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chdir_test() {
+        let log_dir = empty_dir("chdir");
+        let log = log_dir.join("pwd/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/chdir"));
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+        stepper.invoke().unwrap();
+
+        let hash = Shash::try_from(Path::new("test_res/chdir/pwd/run")).unwrap();
+        if let Some(child) = stepper.running.get_mut(&hash).unwrap().child.as_mut() {
+            child.wait().unwrap();
+        }
+
+        let cwd = std::fs::canonicalize("test_res/chdir/pwd").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(log).unwrap(),
+            format!("{}\n", cwd.display())
+        );
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reaped_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        let hash = Shash::try_from(Path::new("test_res/stubborn/svc/run")).unwrap();
+        stepper.invoke().unwrap();
+
+        let child = stepper.running[&hash].child.as_ref().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        kill(pid, Signal::SIGKILL).unwrap();
+        let status = waitpid(pid, None).unwrap();
+        assert_eq!(status, WaitStatus::Signaled(pid, Signal::SIGKILL, false));
+        stepper.reaped(pid, ExitStatus::from_raw(Signal::SIGKILL as i32));
+        stepper.invoke().unwrap();
+
+        let service = &stepper.running[&hash];
+        assert!(service.child.is_none());
+        assert_eq!(service.failures, 1);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        let hash = Shash::try_from(Path::new("test_res/stubborn/svc/run")).unwrap();
+        stepper.invoke().unwrap();
+
+        let pid = stepper.running[&hash].child.as_ref().unwrap().id();
+        let status = stepper.status();
+        assert_eq!(
+            status,
+            vec![ServiceStatus {
+                shash: hash.clone(),
+                pid: Some(pid),
+                state: ServiceState::Running,
+                uptime: status[0].uptime,
+                restarts: 0,
+                failed_starts: 0,
+                check_failures: 0,
+                last_exit: None,
+                desired: DesiredState::Up,
+            }]
+        );
+        assert!(status[0].uptime.is_some());
+
+        stepper.backoff_base = Duration::from_secs(60);
+        kill(Pid::from_raw(pid as i32), Signal::SIGKILL).unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&hash].child.is_none().then_some(())
+        });
+        let status = &stepper.status()[0];
+        assert!(matches!(
+            status.state,
+            ServiceState::Backoff { until } if until > SystemTime::now() + Duration::from_secs(30)
+        ));
+        assert_eq!(
+            status.last_exit,
+            Some(Termination::Signaled(Signal::SIGKILL as i32))
+        );
+
+        assert_eq!(stepper.control(&ControlCommand::Down("svc".into())), "ok\n");
+        stepper.invoke().unwrap();
+        let status = &stepper.status()[0];
+        assert_eq!(status.state, ServiceState::Down);
+        assert_eq!(status.desired, DesiredState::Down);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn control_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        let hash = Shash::try_from(Path::new("test_res/stubborn/svc/run")).unwrap();
+        stepper.invoke().unwrap();
+
+        assert_eq!(
+            stepper.control(&ControlCommand::Restart("other".into())),
+            "error: no such service \"other\"\n"
+        );
+        assert_eq!(stepper.control(&ControlCommand::Down("svc".into())), "ok\n");
+        stepper.invoke().unwrap();
+        assert!(stepper.running[&hash].term_sent.is_some());
+        assert_eq!(stepper.status()[0].desired, DesiredState::Down);
+        assert_eq!(
+            stepper
+                .control(&ControlCommand::Status(None))
+                .lines()
+                .last(),
+            Some("svc down")
+        );
+
+        stepper.kill_timeout = Duration::ZERO;
+        for _ in 0..100 {
+            if stepper.running[&hash].child.is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            stepper.invoke().unwrap();
+        }
+        // Kept to be reported down, once
+        assert_eq!(stepper.status()[0].state, ServiceState::Down);
+        assert_eq!(stepper.control(&ControlCommand::Status(None)), "svc down\n");
+
+        assert_eq!(stepper.control(&ControlCommand::Up("svc".into())), "ok\n");
+        stepper.invoke().unwrap();
+        assert!(stepper.running[&hash].child.is_some());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn credential_error_test() {
+        let stepper = Stepper::new(PathBuf::from("test_res/credentials"));
+        let run = Path::new("test_res/b/run");
+        let hash = Shash::try_from(run).unwrap();
+
+        let err = stepper
+            .command(
+                Path::new("test_res/credentials/unknown"),
+                &ServiceConfig::default(),
+                run,
+                &hash,
+            )
+            .unwrap_err();
+        assert!(matches!(err, StepError::Credential(..)), "{err}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn credentials_test() {
+        if !nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+        let stepper = Stepper::new(PathBuf::from("test_res/credentials"));
+        let sh = Path::new("/bin/sh");
+        let hash = Shash::try_from(sh).unwrap();
+        let mut command = stepper
+            .command(
+                Path::new("test_res/credentials/numeric"),
+                &ServiceConfig::default(),
+                sh,
+                &hash,
+            )
+            .unwrap();
+        let output = command
+            .args(["-c", "id -u; id -g; id -G"])
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "1234\n5678\n5678\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn service_config_test() {
+        let log_dir = empty_dir("service_config");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/svcconfig"))
+            .log_mode(LogMode::File)
+            .log_dir(log_dir)
+            .build();
+        let output = wait_until(|| {
+            stepper.invoke().unwrap();
+            std::fs::read_to_string(&log)
+                .ok()
+                .filter(|log| !log.is_empty())
+        });
+        let cwd = absolute("test_res/svcconfig").unwrap();
+        assert_eq!(output, format!("a b c|bar|{}|5|0027\n", cwd.display()));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn default_limits_test() {
+        let stepper = Stepper::builder(PathBuf::from("test_res/limits"))
+            .limits(Limits {
+                open_files: Some(128),
+                cpu: Some(60),
+                ..Limits::default()
+            })
+            .build();
+        let sh = Path::new("/bin/sh");
+        let hash = Shash::try_from(sh).unwrap();
+        let limits = |service_dir: &str| {
+            let mut command = stepper
+                .command(Path::new(service_dir), &ServiceConfig::default(), sh, &hash)
+                .unwrap();
+            let output = command
+                .args(["-c", "ulimit -n; ulimit -t"])
+                .stdout(Stdio::piped())
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        assert_eq!(limits("test_res/b"), "128\n60\n");
+        // The limits file sets nofile 256 and cpu unlimited
+        assert_eq!(limits("test_res/limits/svc"), "256\nunlimited\n");
+    }
+
+    #[cfg(all(feature = "cgroup", target_os = "linux"))]
+    #[test]
+    fn cgroup_test() {
+        // A plain directory stands in for the cgroup filesystem
+        let root = empty_dir("cgroup");
+        let group = root.join("svc");
+        std::fs::create_dir_all(&group).unwrap();
+        for file in ["cgroup.procs", "cgroup.kill", "memory.max", "cpu.max"] {
+            std::fs::write(group.join(file), "").unwrap();
+        }
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/cgroup"))
+            .cgroup_root(root)
+            .build();
+        stepper.invoke().unwrap();
+        let read = |file| std::fs::read_to_string(group.join(file)).unwrap();
+        assert_eq!(read("memory.max"), "512M");
+        assert_eq!(read("cpu.max"), "50000 100000");
+        // The service wrote its own PID, as the kernel would replace it
+        assert_eq!(read("cgroup.procs"), "0");
+        // Let the service install its SIGTERM trap
+        thread::sleep(Duration::from_millis(200));
+
+        stepper.kill_timeout = Duration::ZERO;
+        stepper.dir = empty_dir("cgroup_empty");
+        stepper.invoke().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        stepper.invoke().unwrap();
+        assert_eq!(read("cgroup.kill"), "1");
+        // Without cgroup.kill the service gets SIGKILL instead
+        std::fs::remove_file(group.join("cgroup.kill")).unwrap();
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_group_test() {
+        let log_dir = empty_dir("process_group");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/group"));
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+        stepper.invoke().unwrap();
+
+        let grandchild = wait_until(|| {
+            let pid = std::fs::read_to_string(&log).ok()?.trim().parse().ok()?;
+            Some(Pid::from_raw(pid))
+        });
+        assert!(alive(grandchild));
+
+        stepper.dir = empty_dir("process_group_empty");
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running.is_empty().then_some(())
+        });
+        wait_until(|| (!alive(grandchild)).then_some(()));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    fn wait_until<T>(mut f: impl FnMut() -> Option<T>) -> T {
+        for _ in 0..100 {
+            if let Some(t) = f() {
+                return t;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out");
+    }
+
+    /// Reruns the test `name` alone in a child process, returning whether this is the child
+    ///
+    /// For tests reaping any child of the process, which would steal the children of the others.
+    #[cfg(unix)]
+    fn isolated(name: &str) -> bool {
+        const TEST: &str = "RUNSVDIR_ISOLATED_TEST";
+        if std::env::var(TEST).is_ok_and(|test| test == name) {
+            return true;
+        }
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args([name, "--exact"])
+            .env(TEST, name)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{name} failed in a child process");
+        false
+    }
+
+    /// Whether `pid` exists and is not a zombie
+    #[cfg(unix)]
+    fn alive(pid: Pid) -> bool {
+        std::fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+            !stat
+                .rsplit(')')
+                .next()
+                .unwrap()
+                .trim_start()
+                .starts_with('Z')
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finish_test() {
+        let log_dir = empty_dir("finish");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/finish"));
+        stepper.backoff_base = Duration::from_secs(60);
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            std::fs::read_to_string(&log)
+                .ok()
+                .filter(|log| log.contains("finish"))
+        });
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.finishing.is_empty().then_some(())
+        });
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "finish 3 0\n");
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finish_timeout_test() {
+        let clock = TestClock::new();
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/finish_timeout"))
+            .backoff_base(Duration::ZERO)
+            .clock(clock.clone())
+            .build();
+        let hash = Shash::try_from(Path::new("test_res/finish_timeout/svc/run")).unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            (!stepper.finishing.is_empty()).then_some(())
+        });
+        let pid = Pid::from_raw(stepper.finishing[0].1.id() as i32);
+        stepper.invoke().unwrap();
+        assert!(stepper.running[&hash].child.is_none());
+        assert!(alive(pid));
+        assert_eq!(
+            stepper.status()[0].state,
+            ServiceState::Finishing(Termination::Exited(0))
+        );
+
+        clock.advance(stepper.finish_timeout + Duration::from_millis(1));
+        stepper.invoke().unwrap();
+        assert!(!alive(pid));
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&hash].child.is_some().then_some(())
+        });
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[test]
+    fn candidates_test() {
+        let dir = empty_dir("candidates");
+        for i in 0..32 {
+            let service = dir.join(format!("svc{i}"));
+            std::fs::create_dir_all(&service).unwrap();
+            std::fs::write(service.join("run"), format!("#!/bin/sh\necho {i}\n")).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        let entries: Vec<_> = read_dir(&dir).unwrap().map(Result::unwrap).collect();
+        let mut stepper = Stepper::new(dir);
+        let outcome = |stepper: &Stepper| {
+            stepper
+                .candidates(&entries)
+                .into_iter()
+                .map(|candidate| candidate.map(|c| c.map(|c| c.hash)).ok())
+                .collect::<Vec<_>>()
+        };
+
+        stepper.hash_threads = 1;
+        let sequential = outcome(&stepper);
+        stepper.hash_threads = 4;
+        let parallel = outcome(&stepper);
+
+        assert_eq!(sequential.len(), 33);
+        assert_eq!(sequential.iter().filter(|c| c.is_none()).count(), 0);
+        assert_eq!(sequential.iter().filter(|c| *c == &Some(None)).count(), 1);
+        assert_eq!(sequential, parallel);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn events_test() {
+        let (tx, rx) = mpsc::channel();
+        let mut stepper = Stepper::new(PathBuf::from("test_res"));
+        stepper.events = Box::new(ChannelSink(tx));
+        stepper.invoke().unwrap();
+        let pids: HashMap<_, _> = stepper
+            .running
+            .iter()
+            .flat_map(|(hash, service)| Some((hash.clone(), service.child.as_ref()?.id())))
+            .collect();
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+
+        let spawned: HashSet<_> = rx
+            .try_iter()
+            .flat_map(|event| match event {
+                Event::Spawn { shash, pid } => Some((shash, pid)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            spawned
+                .iter()
+                .map(|(shash, _)| shash)
+                .collect::<HashSet<_>>(),
+            HashSet::from([
+                &Shash::try_from(Path::new("test_res/b/run")).unwrap(),
+                &Shash::try_from(Path::new("test_res/d/run")).unwrap()
+            ])
+        );
+        for (shash, pid) in pids {
+            assert!(spawned.contains(&(shash, pid)));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res"));
+        let report = stepper.invoke().unwrap();
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+
+        assert_eq!(
+            report.spawned.iter().collect::<HashSet<_>>(),
+            HashSet::from([
+                &Shash::try_from(Path::new("test_res/b/run")).unwrap(),
+                &Shash::try_from(Path::new("test_res/d/run")).unwrap()
+            ])
+        );
+        // test_res/c/run is not executable and skipped without a spawn attempt
+        assert_eq!(report.failures, 0);
+        // test_res/a is not a directory
+        assert!(matches!(
+            &report.errors[..],
+            [StepError::Shash(path, _)] if path == Path::new("test_res/a/run")
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restarts_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        stepper.backoff_base = Duration::ZERO;
+        let hash = Shash::try_from(Path::new("test_res/stubborn/svc/run")).unwrap();
+        stepper.invoke().unwrap();
+        let child = stepper
+            .running
+            .get_mut(&hash)
+            .unwrap()
+            .child
+            .as_mut()
+            .unwrap();
+        let pid = child.id();
+        child.kill().unwrap();
+
+        let status = wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper
+                .status()
+                .pop()
+                .filter(|status| status.pid.is_some_and(|p| p != pid))
+        });
+        assert_eq!(status.restarts, 1);
+        assert_eq!(status.state, ServiceState::Running);
+
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interval_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/interval"));
+        let hash = Shash::try_from(Path::new("test_res/interval/svc/run")).unwrap();
+        stepper.invoke().unwrap();
+        let next_check = stepper.next_check().unwrap();
+        assert!(next_check > Instant::now() + Duration::from_secs(50));
+
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&hash].child.is_none().then_some(())
+        });
+        stepper.invoke().unwrap();
+        let service = &stepper.running[&hash];
+        assert!(service.child.is_none());
+        assert_eq!(service.restarts, 0);
+        assert_eq!(stepper.next_check(), Some(next_check));
+
+        stepper
+            .checks
+            .values_mut()
+            .for_each(|(at, _)| *at = Instant::now());
+        stepper.invoke().unwrap();
+        assert_eq!(stepper.running[&hash].restarts, 1);
+        assert!(stepper.next_check().unwrap() > next_check);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn classify_test() {
+        assert!(candidate(Path::new("test_res/classify/norun"))
+            .unwrap()
+            .is_none());
+
+        let dir = empty_dir("classify").join("unreadable");
+        std::fs::create_dir_all(&dir).unwrap();
+        let run = dir.join("run");
+        std::fs::write(&run, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&run, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if std::fs::File::open(&run).is_ok() {
+            // Privileged users can read anything
+            return;
+        }
+        assert!(matches!(
+            candidate(&dir),
+            Err(StepError::RunNotReadable(path, _)) if path == run
+        ));
+    }
+
+    #[cfg(unix)]
+    #[derive(Clone, Default)]
+    /// Captured tracing output
+    struct Logs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    #[cfg(unix)]
+    impl Logs {
+        /// Everything logged so far
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+
+        /// The number of lines logged so far containing `needle`
+        fn count(&self, needle: &str) -> usize {
+            self.contents()
+                .lines()
+                .filter(|line| line.contains(needle))
+                .count()
+        }
+    }
+
+    #[cfg(unix)]
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A subscriber writing plain lines to the returned logs
+    #[cfg(unix)]
+    fn capture_logs() -> (impl tracing::Subscriber + Send + Sync, Logs) {
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        (subscriber, logs)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn not_executable_test() {
+        let (subscriber, logs) = capture_logs();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/noexec"));
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                let report = stepper.invoke().unwrap();
+                assert!(report.spawned.is_empty());
+                assert_eq!(report.failures, 0);
+            }
+        });
+        assert!(stepper.running.is_empty());
+        assert_eq!(logs.count("run not executable"), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_kind_test() {
+        let dir = Path::new("test_res/runkind/dir");
+        assert!(matches!(
+            candidate(dir),
+            Err(StepError::RunNotFile(path)) if path == dir.join("run")
+        ));
+        let link = candidate(Path::new("test_res/runkind/link"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(link.run, Path::new("test_res/runkind/link/run"));
+        assert!(link.hash.target().unwrap().ends_with("runkind/link/script"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_test() {
+        let log_dir = empty_dir("env");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/env"));
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+        stepper.invoke().unwrap();
+
+        let hash = Shash::try_from(Path::new("test_res/env/svc/run")).unwrap();
+        if let Some(child) = stepper.running.get_mut(&hash).unwrap().child.as_mut() {
+            child.wait().unwrap();
+        }
+
+        assert_eq!(std::fs::read_to_string(log).unwrap(), "foo bar\n");
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recursive_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/nested"));
+        let flat = stepper.invoke().unwrap();
+        assert!(flat.spawned.is_empty());
+
+        stepper.recursive = true;
+        let report = stepper.invoke().unwrap();
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+
+        assert_eq!(
+            report.spawned.iter().collect::<HashSet<_>>(),
+            HashSet::from([
+                &Shash::try_from(Path::new("test_res/nested/web/api/run")).unwrap(),
+                &Shash::try_from(Path::new("test_res/nested/web/worker/run")).unwrap()
+            ])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn min_uptime_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/startup"));
+        stepper.min_uptime = Duration::from_millis(300);
+        stepper.backoff_base = Duration::from_secs(60);
+        let quick = Shash::try_from(Path::new("test_res/startup/quick/run")).unwrap();
+        let slow = Shash::try_from(Path::new("test_res/startup/slow/run")).unwrap();
+        for _ in 0..500 {
+            stepper.invoke().unwrap();
+            if stepper.running.values().all(|s| s.last_exit.is_some()) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let now = Instant::now();
+
+        assert_eq!(stepper.running[&quick].failed_starts, 1);
+        assert!(stepper.running[&quick].respawn_at > now);
+        assert_eq!(stepper.running[&slow].failed_starts, 0);
+        assert!(stepper.running[&slow].respawn_at <= now);
+        assert_eq!(stepper.running[&slow].failures, 0);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn no_kill_stale_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        stepper.kill_stale = false;
+        stepper.invoke().unwrap();
+        stepper.dir = empty_dir("no_kill_stale");
+        stepper.invoke().unwrap();
+
+        let service = stepper.running.values_mut().next().unwrap();
+        assert!(service.term_sent.is_none());
+        let child = service.child.as_mut().unwrap();
+        assert!(child.try_wait().unwrap().is_none());
+
+        stepper.kill_stale = true;
+        stepper.invoke().unwrap();
+        let service = stepper.running.values().next().unwrap();
+        assert!(service.term_sent.is_some());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn limits_test() {
+        let log_dir = empty_dir("limits");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/limits"));
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+        stepper.invoke().unwrap();
+
+        let hash = Shash::try_from(Path::new("test_res/limits/svc/run")).unwrap();
+        if let Some(child) = stepper.running.get_mut(&hash).unwrap().child.as_mut() {
+            child.wait().unwrap();
+        }
+
+        assert_eq!(std::fs::read_to_string(log).unwrap(), "256\n");
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_errors_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/nospawn"));
+        let report = stepper.invoke().unwrap();
+
+        assert!(report.spawned.is_empty());
+        assert_eq!(report.failures, 1);
+        assert!(matches!(
+            &report.errors[..],
+            [StepError::Credential(path, CredentialError::UnknownUser(_))]
+                if path == Path::new("test_res/nospawn/svc")
+        ));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn debounce_test() {
+        let dir = empty_dir("debounce");
+        let run = dir.join("svc/run");
+        let write = |version: u32| {
+            service(
+                &dir,
+                "svc",
+                &format!("#!/bin/sh\n# {version}\nexec sleep 60\n"),
+            );
+        };
+        write(1);
+        let mut stepper = Stepper::new(dir.clone());
+        stepper.debounce = Duration::from_millis(300);
+        // A run just written may be busy for a moment when other tests fork concurrently
+        let mut spawned = wait_until(|| {
+            let spawned = stepper.invoke().unwrap().spawned;
+            (!spawned.is_empty()).then_some(spawned)
+        });
+        let first = spawned[0].clone();
+        // Let the shell read its script before it is rewritten
+        thread::sleep(Duration::from_millis(200));
+
+        write(2);
+        spawned.extend(stepper.invoke().unwrap().spawned);
+        write(3);
+        spawned.extend(stepper.invoke().unwrap().spawned);
+        assert_eq!(spawned.len(), 1);
+        assert!(stepper.running[&first].term_sent.is_none());
+
+        thread::sleep(Duration::from_millis(400));
+        spawned.extend(wait_until(|| {
+            let spawned = stepper.invoke().unwrap().spawned;
+            (!spawned.is_empty()).then_some(spawned)
+        }));
+        assert_eq!(spawned.len(), 2);
+        assert_eq!(spawned[1], Shash::try_from(run.as_path()).unwrap());
+        // Gone already if the respawn had to be retried
+        assert!(stepper
+            .running
+            .get(&first)
+            .is_none_or(|service| service.term_sent.is_some()));
+
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn max_concurrent_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/concurrent"));
+        stepper.max_concurrent = Some(2);
+        stepper.invoke().unwrap();
+        stepper.invoke().unwrap();
+        let running: HashSet<_> = stepper
+            .running
+            .iter()
+            .filter(|(_, s)| s.child.is_some())
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+
+        assert_eq!(
+            running,
+            HashSet::from([
+                Shash::try_from(Path::new("test_res/concurrent/a/run")).unwrap(),
+                Shash::try_from(Path::new("test_res/concurrent/b/run")).unwrap()
+            ])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timestamped_test() {
+        let log_dir = empty_dir("timestamped");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stamped"));
+        stepper.log_mode = LogMode::Timestamped;
+        stepper.log_dir = Some(log_dir);
+        stepper.invoke().unwrap();
+
+        let lines = || {
+            std::fs::read_to_string(&log)
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        wait_until(|| (lines().len() == 2).then_some(()));
+        let lines = lines();
+        for (line, expected) in lines.iter().zip(["svc: first", "svc: second"]) {
+            let (stamp, rest) = line.split_once(' ').unwrap();
+            assert!(OffsetDateTime::parse(stamp, &Rfc3339).is_ok(), "{line}");
+            assert_eq!(rest, expected);
+        }
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn running_pids_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        assert!(stepper.running_pids().is_empty());
+        stepper.invoke().unwrap();
+        let hash = Shash::try_from(Path::new("test_res/stubborn/svc/run")).unwrap();
+        let pid = stepper.running[&hash].child.as_ref().unwrap().id();
+
+        assert_eq!(stepper.running_pids(), [(hash, pid)]);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+        assert!(stepper.running_pids().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restart_policy_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/restart"));
+        stepper.backoff_base = Duration::ZERO;
+        let restarts = |stepper: &Stepper, name: &str| {
+            let run = PathBuf::from(format!("test_res/restart/{name}/run"));
+            let service = &stepper.running[&Shash::try_from(run.as_path()).unwrap()];
+            (service.restarts, service.completed)
+        };
+        for _ in 0..100 {
+            stepper.invoke().unwrap();
+            if restarts(&stepper, "always").0 >= 2 && restarts(&stepper, "on-failure").0 >= 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(restarts(&stepper, "never"), (0, true));
+        assert_eq!(restarts(&stepper, "on-failure-ok"), (0, true));
+        assert!(!restarts(&stepper, "on-failure").1);
+        assert!(!restarts(&stepper, "always").1);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn default_restart_policy_test() {
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/restart"))
+            .restart_policy(RestartPolicy::Never)
+            .build();
+        let always = Shash::try_from(Path::new("test_res/restart/always/run")).unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&always].completed.then_some(())
+        });
+        // The restart file of a service overrides the default
+        let on_failure = Shash::try_from(Path::new("test_res/restart/on-failure/run")).unwrap();
+        assert!(!stepper.running[&on_failure].completed);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn needs_test() {
+        let (tx, rx) = mpsc::channel();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/needs"));
+        stepper.events = Box::new(ChannelSink(tx));
+        let report = stepper.invoke().unwrap();
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+
+        let spawned: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::Spawn { shash, .. } => Some(shash),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            spawned,
+            [
+                Shash::try_from(Path::new("test_res/needs/b/run")).unwrap(),
+                Shash::try_from(Path::new("test_res/needs/a/run")).unwrap()
+            ]
+        );
+        assert!(report.errors.is_empty());
+    }
+
+    #[test]
+    fn dependency_cycle_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/cycle"));
+        let report = stepper.invoke().unwrap();
+
+        assert!(report.spawned.is_empty());
+        assert!(matches!(
+            &report.errors[..],
+            [StepError::DependencyCycle(dirs)] if dirs.len() == 2
+        ));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[test]
+    fn vanished_test() {
+        let dir = empty_dir("vanished");
+        let service = service(&dir, "svc", "#!/bin/sh\nexit 0\n");
+        let candidate = candidate(&service).unwrap().unwrap();
+        std::fs::remove_dir_all(&service).unwrap();
+
+        let mut stepper = Stepper::new(dir);
+        let spawned = stepper
+            .step_candidate(candidate, &mut HashSet::new())
+            .unwrap();
+        assert_eq!(spawned, None);
+        assert!(stepper.running.is_empty());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clock_kill_test() {
+        let clock = TestClock::new();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        stepper.clock = Box::new(clock.clone());
+        stepper.invoke().unwrap();
+        stepper.dir = empty_dir("test_clock_kill");
+        stepper.invoke().unwrap();
+        stepper.invoke().unwrap();
+        let hash = Shash::try_from(Path::new("test_res/stubborn/svc/run")).unwrap();
+        assert!(!stepper.running[&hash].killed);
+        assert_eq!(stepper.status()[0].state, ServiceState::Stopping);
+
+        clock.advance(stepper.kill_timeout + Duration::from_millis(1));
+        stepper.invoke().unwrap();
+        assert!(stepper.running.get(&hash).is_none_or(|s| s.killed));
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running.is_empty().then_some(())
+        });
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ignore_test() {
+        let (subscriber, logs) = capture_logs();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/ignore"));
+        stepper.ignore = vec![Pattern::new("*~").unwrap()];
+        let report = tracing::subscriber::with_default(subscriber, || stepper.invoke().unwrap());
+        assert_eq!(
+            report.spawned,
+            [Shash::try_from(Path::new("test_res/ignore/svc/run")).unwrap()]
+        );
+        assert_eq!(report.failures, 0);
+        assert!(report.errors.is_empty());
+        assert_eq!(logs.count("broken~"), 0);
+
+        stepper.ignore.clear();
+        let report = stepper.invoke().unwrap();
+        assert!(matches!(&report.errors[..], [StepError::RunNotFile(_)]));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn term_signal_test() {
+        assert_eq!(parse_signal("SIGINT"), Some(Signal::SIGINT));
+        assert_eq!(parse_signal("hup"), Some(Signal::SIGHUP));
+        assert_eq!(parse_signal("15"), Some(Signal::SIGTERM));
+        assert_eq!(parse_signal("SIGBOGUS"), None);
+        assert_eq!(parse_signal("0"), None);
+        assert_eq!(read_term_signal(Path::new("test_res/b")), Signal::SIGTERM);
+        assert_eq!(
+            read_term_signal(Path::new("test_res/termsig/svc")),
+            Signal::SIGINT
+        );
+
+        let log_dir = empty_dir("term_signal");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/termsig"));
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+        stepper.invoke().unwrap();
+        // Let the service install its traps
+        thread::sleep(Duration::from_millis(200));
+
+        stepper.dir = empty_dir("term_signal_stale");
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running.is_empty().then_some(())
+        });
+        assert_eq!(std::fs::read_to_string(log).unwrap(), "2\n");
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signaled_test() {
+        let (tx, rx) = mpsc::channel();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/signaled"));
+        stepper.events = Box::new(ChannelSink(tx));
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running.values().all(|s| s.completed).then_some(())
+        });
+
+        let signaled = Termination::Signaled(Signal::SIGUSR1 as i32);
+        assert_eq!(stepper.status()[0].state, ServiceState::Completed(signaled));
+        assert_eq!(
+            stepper.control(&ControlCommand::Status(None)),
+            "svc completed, killed 10\n"
+        );
+        let exits: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::Exit { status, .. } => Some(Termination::from(status)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(exits, [signaled]);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dry_run_test() {
+        let (subscriber, logs) = capture_logs();
+        let mut stepper = Stepper::new(PathBuf::from("test_res"));
+        stepper.dry_run = true;
+        let report = tracing::subscriber::with_default(subscriber, || stepper.invoke().unwrap());
+        assert!(report.spawned.is_empty());
+        assert!(stepper.running.is_empty());
+
+        let logs = logs.contents();
+        let intents: Vec<_> = logs
+            .lines()
+            .filter(|line| line.ends_with(" would be spawned"))
+            .collect();
+        assert_eq!(intents.len(), 2);
+        assert!(intents[0].contains("\"test_res/b/run\""));
+        assert!(intents[1].contains("\"test_res/d/run\""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identical_run_test() {
+        // The path is part of the identity, so identical scripts cannot claim the same service
+        let mut stepper = Stepper::new(PathBuf::from("test_res/twins"));
+        let report = stepper.invoke().unwrap();
+        let one = Shash::try_from(Path::new("test_res/twins/one/run")).unwrap();
+        let two = Shash::try_from(Path::new("test_res/twins/two/run")).unwrap();
+        assert_ne!(one, two);
+        assert_eq!(report.spawned, [one, two]);
+        assert_eq!(stepper.running_pids().len(), 2);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn same_target_test() {
+        let dir = empty_dir("same_target");
+        let _ = std::fs::remove_dir_all(&dir);
+        let scan = dir.join("scan");
+        std::fs::create_dir_all(&scan).unwrap();
+        let target = service(&dir.join("sv"), "svc", "#!/bin/sh\nexec sleep 60\n");
+        for link in ["a", "b"] {
+            std::os::unix::fs::symlink(&target, scan.join(link)).unwrap();
+        }
+        let mut stepper = Stepper::new(scan.clone());
+        let report = stepper.invoke().unwrap();
+        assert_eq!(report.spawned.len(), 1);
+        assert_eq!(report.spawned[0].path(), scan.join("a/run"));
+
+        // The second link neither spawns another process nor moves the service back and forth
+        let running = stepper.running_pids();
+        assert_eq!(running[0].0, report.spawned[0]);
+        for _ in 0..3 {
+            assert!(stepper.invoke().unwrap().spawned.is_empty());
+            assert_eq!(stepper.running_pids(), running);
+        }
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_state_test() {
+        let hash = Shash::try_from(Path::new("test_res/backoff/fail/run")).unwrap();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/backoff"));
+        stepper.backoff_base = Duration::from_secs(60);
+        stepper.invoke().unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            (stepper.running[&hash].failures == 1).then_some(())
+        });
+        let state = stepper.saved_state();
+        let saved = &state.services[&hash.digest()];
+        assert_eq!(saved.failures, 1);
+        assert_eq!(saved.last_exit, Some(Termination::Exited(1)));
+        assert!(saved.respawn_at.is_some());
+
+        let mut restarted = Stepper::new(PathBuf::from("test_res/backoff"));
+        restarted.restore_state(state.clone());
+        assert_eq!(restarted.saved_state(), state);
+        let report = restarted.invoke().unwrap();
+        assert!(report.spawned.is_empty());
+        let service = &restarted.running[&hash];
+        assert!(service.child.is_none());
+        assert_eq!(service.failures, 1);
+        assert!(matches!(
+            restarted.status()[0].state,
+            ServiceState::Backoff { until } if until > SystemTime::now()
+        ));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn adopt_test() {
+        let dir = empty_dir("adopt");
+        service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let mut stepper = Stepper::new(dir.clone());
+        let hash = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        let pid = stepper.running_pids()[0].1;
+        let state = stepper.saved_state();
+        assert_eq!(state.services[&hash.digest()].pid, Some(pid));
+        // The supervisor goes away without stopping its services
+        drop(stepper);
+
+        let mut restarted = Stepper::new(dir.clone());
+        restarted.restore_state(state.clone());
+        assert!(restarted.invoke().unwrap().spawned.is_empty());
+        assert_eq!(restarted.running_pids(), [(hash.clone(), pid)]);
+        assert_eq!(restarted.status()[0].state, ServiceState::Running);
+        restarted.shutdown();
+        assert!(!alive(Pid::from_raw(pid as i32)));
+        waitpid(Pid::from_raw(pid as i32), None).unwrap();
+
+        let mut restarted = Stepper::new(dir);
+        restarted.restore_state(state);
+        assert_eq!(
+            wait_until(|| restarted.invoke().unwrap().spawned.pop()),
+            hash
+        );
+        assert_ne!(restarted.running_pids()[0].1, pid);
+        restarted.shutdown_timeout = Duration::ZERO;
+        restarted.shutdown();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn supervise_pid_test() {
+        let dir = empty_dir("supervise_pid");
+        let service = service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let pidfile = service.join("supervise/pid");
+        let _ = std::fs::remove_file(&pidfile);
+        let mut stepper = Stepper::builder(dir.clone()).supervise(true).build();
+        let hash = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        let pid = stepper.running_pids()[0].1;
+        assert_eq!(
+            std::fs::read_to_string(&pidfile).unwrap(),
+            format!("{pid}\n")
+        );
+        let supervise = service.join("supervise");
+        assert_eq!(
+            std::fs::read_to_string(supervise.join("stat")).unwrap(),
+            "run\n"
+        );
+        let status = std::fs::read(supervise.join("status")).unwrap();
+        assert_eq!(
+            status[12..],
+            [&pid.to_le_bytes()[..], &[0, b'u', 0, 1]].concat()
+        );
+        for fifo in ["control", "ok"] {
+            assert!(std::fs::metadata(supervise.join(fifo))
+                .unwrap()
+                .file_type()
+                .is_fifo());
+        }
+        // The supervisor goes away without stopping its services
+        drop(stepper);
+
+        let mut restarted = Stepper::builder(dir)
+            .supervise(true)
+            .backoff_base(Duration::ZERO)
+            .build();
+        assert!(restarted.invoke().unwrap().spawned.is_empty());
+        assert_eq!(restarted.running_pids(), [(hash, pid)]);
+        kill(Pid::from_raw(pid as i32), Signal::SIGKILL).unwrap();
+        waitpid(Pid::from_raw(pid as i32), None).unwrap();
+        wait_until(|| {
+            restarted.invoke().unwrap();
+            (!pidfile.exists()).then_some(())
+        });
+        assert_eq!(
+            std::fs::read_to_string(supervise.join("stat")).unwrap(),
+            "down\n"
+        );
+        wait_until(|| restarted.invoke().unwrap().spawned.pop());
+        assert!(pidfile.exists());
+        restarted.shutdown_timeout = Duration::ZERO;
+        restarted.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn once_test() {
+        let dir = empty_dir("once");
+        service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let mut stepper = Stepper::builder(dir).backoff_base(Duration::ZERO).build();
+        let once = ControlCommand::Once("svc".into());
+
+        assert_eq!(stepper.control(&ControlCommand::Down("svc".into())), "ok\n");
+        stepper.invoke().unwrap();
+        assert!(stepper.running.is_empty());
+        assert_eq!(stepper.control(&once), "ok\n");
+        assert_eq!(stepper.invoke().unwrap().spawned.len(), 1);
+
+        // Not respawned though its exit is the term signal
+        stepper.control(&ControlCommand::Term("svc".into()));
+        let signaled = Termination::Signaled(Signal::SIGTERM as i32);
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            (stepper.status()[0].state == ServiceState::Completed(signaled)).then_some(())
+        });
+        assert!(stepper.invoke().unwrap().spawned.is_empty());
+        assert_eq!(stepper.status()[0].desired, DesiredState::Up);
+
+        assert_eq!(stepper.control(&once), "ok\n");
+        assert_eq!(stepper.invoke().unwrap().spawned.len(), 1);
+        assert_eq!(stepper.control(&ControlCommand::Up("svc".into())), "ok\n");
+        stepper.control(&ControlCommand::Kill("svc".into()));
+        wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn supervise_control_test() {
+        let dir = empty_dir("supervise_control");
+        let service = service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let mut stepper = Stepper::builder(dir)
+            .supervise(true)
+            .backoff_base(Duration::ZERO)
+            .build();
+        wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        let supervise = service.join("supervise");
+        let send = |commands: &[u8]| {
+            use std::io::Write;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(supervise.join("control"))
+                .unwrap()
+                .write_all(commands)
+                .unwrap();
+        };
+        let stat = || std::fs::read_to_string(supervise.join("stat")).unwrap();
+        let pid = stepper.running_pids()[0].1;
+
+        send(b"p");
+        stepper.invoke().unwrap();
+        assert_eq!(stat(), "run, paused\n");
+        assert_eq!(std::fs::read(supervise.join("status")).unwrap()[16], 1);
+        send(b"c\n");
+        stepper.invoke().unwrap();
+        assert_eq!(stat(), "run\n");
+        assert_eq!(stepper.running_pids()[0].1, pid);
+
+        send(b"k");
+        let killed = wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper
+                .running_pids()
+                .first()
+                .map(|&(_, p)| p)
+                .filter(|&p| p != pid)
+        });
+
+        send(b"d");
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            (stat() == "down\n").then_some(())
+        });
+        assert!(!alive(Pid::from_raw(killed as i32)));
+        assert_eq!(std::fs::read(supervise.join("status")).unwrap()[17], b'd');
+        send(b"u");
+        wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        assert_eq!(stat(), "run\n");
+        send(b"o");
+        stepper.invoke().unwrap();
+        assert_eq!(stat(), "run, want down\n");
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_file_test() {
+        let log_dir = empty_dir("env_file");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(PathBuf::from("test_res/globalenv"));
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+        stepper.env_file = EnvFile::load(Path::new("test_res/config/global.env")).unwrap();
+        stepper.invoke().unwrap();
+
+        let hash = Shash::try_from(Path::new("test_res/globalenv/svc/run")).unwrap();
+        if let Some(child) = stepper.running.get_mut(&hash).unwrap().child.as_mut() {
+            child.wait().unwrap();
+        }
+
+        assert_eq!(std::fs::read_to_string(log).unwrap(), "hello local\n");
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn error_cooldown_test() {
+        let (subscriber, logs) = capture_logs();
+        let dir = empty_dir("error_cooldown");
+        // Not a directory, so hashing its run fails on every tick
+        std::fs::write(dir.join("svc"), "").unwrap();
+        let clock = TestClock::new();
+        let mut stepper = Stepper::new(dir.clone());
+        stepper.clock = Box::new(clock.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..2 {
+                assert_eq!(stepper.invoke().unwrap().errors.len(), 1);
+            }
+            assert_eq!(logs.count("skipping entry"), 1);
+
+            clock.advance(stepper.error_cooldown);
+            stepper.invoke().unwrap();
+            assert_eq!(logs.count("skipping entry"), 1);
+            assert_eq!(logs.count("still failing (3 occurrences)"), 1);
+
+            // Recovering forgets the error, so failing again is logged at once
+            std::fs::remove_file(dir.join("svc")).unwrap();
+            assert!(stepper.invoke().unwrap().errors.is_empty());
+            std::fs::write(dir.join("svc"), "").unwrap();
+            stepper.invoke().unwrap();
+            assert_eq!(logs.count("skipping entry"), 2);
+        });
+        std::fs::remove_file(dir.join("svc")).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_port_test() {
+        let dir = empty_dir("check_port");
+        let service = service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        std::fs::write(service.join("check-port"), format!("{port}\n")).unwrap();
+
+        let clock = TestClock::new();
+        let mut stepper = Stepper::new(dir);
+        stepper.clock = Box::new(clock.clone());
+        wait_until(|| (!stepper.invoke().unwrap().spawned.is_empty()).then_some(()));
+        stepper.invoke().unwrap();
+        assert_eq!(stepper.status()[0].state, ServiceState::Starting);
+
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port)).unwrap();
+        stepper.invoke().unwrap();
+        assert_eq!(stepper.status()[0].state, ServiceState::Ready);
+        let pid = stepper.status()[0].pid.unwrap();
+        assert_eq!(
+            stepper.control(&ControlCommand::Status(None)),
+            format!("svc ready {pid}\n")
+        );
+
+        // Without the listener a service not ready yet turns unhealthy on the timeout
+        drop(listener);
+        let hash = stepper.running_pids()[0].0.clone();
+        stepper.running.get_mut(&hash).unwrap().ready = false;
+        clock.advance(stepper.ready_timeout);
+        stepper.invoke().unwrap();
+        assert_eq!(stepper.status()[0].state, ServiceState::Unhealthy);
+
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn max_stops_test() {
+        let (tx, rx) = mpsc::channel();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/concurrent"));
+        stepper.events = Box::new(ChannelSink(tx));
+        stepper.max_stops = Some(1);
+        assert_eq!(stepper.invoke().unwrap().spawned.len(), 3);
+        stepper.dir = empty_dir("max_stops");
+
+        let mut stopped = vec![];
+        for _ in 0..3 {
+            stepper.invoke().unwrap();
+            let stale: Vec<_> = rx
+                .try_iter()
+                .filter_map(|event| match event {
+                    Event::Stale { shash } => Some(shash),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(stale.len(), 1);
+            stopped.extend(stale);
+        }
+        let paths: Vec<_> = stopped.iter().map(Shash::path).collect();
+        assert_eq!(
+            paths,
+            ["a", "b", "c"].map(|name| PathBuf::from(format!("test_res/concurrent/{name}/run")))
+        );
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running.is_empty().then_some(())
+        });
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[test]
+    fn builder_test() {
+        let (tx, rx) = mpsc::channel();
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/concurrent"))
+            .identity(Identity::Path)
+            .max_concurrent(1)
+            .kill_timeout(Duration::from_millis(1))
+            .event_sink(ChannelSink(tx))
+            .build();
+        assert_eq!(stepper.kill_timeout, Duration::from_millis(1));
+        assert_eq!(stepper.max_concurrent, Some(1));
+        // The scripts of the services cannot be spawned on Windows
+        if cfg!(windows) {
+            return;
+        }
+        assert_eq!(stepper.invoke().unwrap().spawned.len(), 1);
+        let spawned: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::Spawn { shash, .. } => Some(shash),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            spawned,
+            [Identity::Path
+                .shash(
+                    Path::new("test_res/concurrent/a/run"),
+                    HashAlg::default(),
+                    None
+                )
+                .unwrap()]
+        );
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[test]
+    fn spawn_failed_test() {
+        let (tx, rx) = mpsc::channel();
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/spawn_failed"))
+            .event_sink(ChannelSink(tx))
+            .build();
+        assert_eq!(stepper.invoke().unwrap().failures, 1);
+        let events: Vec<_> = rx.try_iter().collect();
+        let [Event::SpawnFailed { path, error }] = events.as_slice() else {
+            panic!("{events:?}");
+        };
+        assert_eq!(path, Path::new("test_res/spawn_failed/svc/run"));
+        assert!(error.starts_with("Spawn process"), "{error}");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/spawn_failed"));
+        stepper.invoke().unwrap();
+        stepper.invoke().unwrap();
+        let metrics = stepper.metrics();
+        assert!(
+            metrics
+                .lines()
+                .any(|l| l == "runsvdir_spawn_errors_total{service=\"svc\"} 2"),
+            "{metrics}"
+        );
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[test]
+    fn scan_timeout_test() {
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/finish"))
+            .scan_timeout(Duration::ZERO)
+            .build();
+        // The worker thread cannot read the directory in no time
+        assert!(matches!(
+            stepper.invoke(),
+            Err(StepError::ScanTimeout(dir, Duration::ZERO)) if dir == Path::new("test_res/finish")
+        ));
+        assert!(stepper.running.is_empty());
+        assert!(stepper.pending_scan.is_some());
+
+        stepper.scan_timeout = Some(Duration::from_secs(5));
+        let report = stepper.invoke().unwrap();
+        // The script of the service cannot be spawned on Windows
+        if cfg!(unix) {
+            assert_eq!(report.spawned.len(), 1);
+        } else {
+            assert_eq!(report.failures, 1);
+        }
+        assert_eq!(report.elapsed, stepper.last_step());
+        assert!(stepper.pending_scan.is_none());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hash_timeout_test() {
+        let dir = empty_dir("hash_timeout");
+        let fast = service(&dir, "fast", "#!/bin/sh\nexec sleep 100\n").join("run");
+        std::fs::create_dir_all(dir.join("slow")).unwrap();
+        // Sparse, but read in full
+        let slow = dir.join("slow/run");
+        std::fs::File::create(&slow)
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+        let mut stepper = Stepper::builder(dir.clone())
+            .hash_timeout(Duration::from_millis(50))
+            .build();
+
+        let report = wait_until(|| Some(stepper.invoke().unwrap()).filter(|r| r.failures == 0));
+        assert_eq!(report.spawned, [Shash::try_from(fast.as_path()).unwrap()]);
+        assert!(stepper.pending_hashes.contains_key(&dir.join("slow")));
+        std::fs::remove_dir_all(dir.join("slow")).unwrap();
+        stepper.invoke().unwrap();
+        assert!(stepper.pending_hashes.is_empty());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn empty_confirmations_test() {
+        let (tx, rx) = mpsc::channel();
+        let populated = PathBuf::from("test_res/concurrent");
+        let mut stepper = Stepper::builder(populated.clone())
+            .empty_confirmations(1)
+            .event_sink(ChannelSink(tx))
+            .build();
+        assert_eq!(stepper.invoke().unwrap().spawned.len(), 3);
+        let stale = || {
+            rx.try_iter()
+                .filter(|event| matches!(event, Event::Stale { .. }))
+                .count()
+        };
+
+        // A swap caught in the middle shows an empty directory once
+        stepper.dir = empty_dir("empty_confirmations");
+        stepper.invoke().unwrap();
+        stepper.dir = populated;
+        assert!(stepper.invoke().unwrap().spawned.is_empty());
+        assert_eq!(stale(), 0);
+        assert_eq!(stepper.running_pids().len(), 3);
+
+        stepper.dir = empty_dir("empty_confirmations");
+        stepper.invoke().unwrap();
+        assert_eq!(stale(), 0);
+        stepper.invoke().unwrap();
+        assert_eq!(stale(), 3);
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running.is_empty().then_some(())
+        });
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reload_signal_test() {
+        let dir = empty_dir("reload_signal");
+        let write = |version: u32| {
+            let script = format!(
+                "#!/bin/bash\n# {version}\ntrap 'echo reloaded' HUP\n\
+                 while true; do sleep 0.1; done\n"
+            );
+            service(&dir, "svc", &script)
+        };
+        let service = write(1);
+        let run = service.join("run");
+        std::fs::write(service.join("reload-signal"), "HUP\n").unwrap();
+        let log_dir = empty_dir("reload_signal_log");
+        let log = log_dir.join("svc/current");
+        let _ = std::fs::remove_file(&log);
+        let mut stepper = Stepper::new(dir.clone());
+        stepper.log_mode = LogMode::File;
+        stepper.log_dir = Some(log_dir);
+        wait_until(|| (!stepper.invoke().unwrap().spawned.is_empty()).then_some(()));
+        let pid = stepper.running_pids()[0].1;
+        // Let the service install its trap
+        thread::sleep(Duration::from_millis(200));
+
+        write(2);
+        assert!(stepper.invoke().unwrap().spawned.is_empty());
+        assert_eq!(
+            stepper.running_pids(),
+            [(Shash::try_from(run.as_path()).unwrap(), pid)]
+        );
+        wait_until(|| {
+            let log = std::fs::read_to_string(&log).unwrap_or_default();
+            log.contains("reloaded").then_some(())
+        });
+        stepper.invoke().unwrap();
+        assert_eq!(stepper.running_pids()[0].1, pid);
+
+        // Without the file a change restarts the service once the old one stopped
+        std::fs::remove_file(service.join("reload-signal")).unwrap();
+        write(3);
+        assert!(stepper.invoke().unwrap().spawned.is_empty());
+        assert_eq!(
+            wait_until(|| stepper.invoke().unwrap().spawned.pop()),
+            Shash::try_from(run.as_path()).unwrap()
+        );
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tracing_log_test() {
+        let (subscriber, logs) = capture_logs();
+        let mut stepper = Stepper::new(PathBuf::from("test_res/logging"));
+        stepper.log_mode = LogMode::Tracing;
+        tracing::subscriber::with_default(subscriber, || stepper.invoke().unwrap());
+
+        let line = wait_until(|| {
+            logs.contents()
+                .lines()
+                .find(|line| line.ends_with(" hello service=\"echo\""))
+                .map(str::to_string)
+        });
+        assert!(line.contains(" INFO "));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn logger_test() {
+        let dir = empty_dir("logger");
+        service(&dir.join("svc"), "log", "#!/bin/sh\nexec cat > current\n");
+        let service = service(&dir, "svc", "#!/bin/sh\necho run\nexec sleep 60\n");
+        let log = service.join("log/current");
+        let lines = || std::fs::read_to_string(&log).unwrap_or_default();
+        let mut stepper = Stepper::new(dir.clone());
+        stepper.backoff_base = Duration::ZERO;
+        wait_until(|| (!stepper.invoke().unwrap().spawned.is_empty()).then_some(()));
+        wait_until(|| (lines() == "run\n").then_some(()));
+        let logger = stepper.loggers[&service].pid().unwrap();
+
+        // The logger outlives a restart of its service and reads on from the same pipe
+        let (hash, pid) = stepper.running_pids()[0].clone();
+        terminate(&hash, pid, Signal::SIGKILL, ProcessGroup::Own);
+        wait_until(|| (!stepper.invoke().unwrap().spawned.is_empty()).then_some(()));
+        wait_until(|| (lines() == "run\nrun\n").then_some(()));
+        assert_eq!(stepper.loggers[&service].pid().unwrap(), logger);
+
+        std::fs::remove_dir_all(&service).unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            (stepper.running.is_empty() && stepper.finishing.is_empty()).then_some(())
+        });
+        assert!(stepper.loggers.is_empty());
+        assert!(!alive(Pid::from_raw(logger as i32)));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn control_signal_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/stubborn"));
+        stepper.invoke().unwrap();
+        // Let the service install its traps
+        thread::sleep(Duration::from_millis(200));
+        let pid = stepper.running_pids()[0].1;
+        assert_eq!(
+            stepper.control(&ControlCommand::Status(Some("svc".into()))),
+            format!("svc running {pid}\n")
+        );
+        assert_eq!(
+            stepper.control(&ControlCommand::Status(Some("other".into()))),
+            "error: no such service \"other\"\n"
+        );
+        assert_eq!(
+            stepper.control(&ControlCommand::Kill("other".into())),
+            "error: no such service \"other\"\n"
+        );
+
+        // The service ignores SIGTERM, but not SIGKILL
+        assert_eq!(stepper.control(&ControlCommand::Term("svc".into())), "ok\n");
+        thread::sleep(Duration::from_millis(100));
+        assert!(alive(Pid::from_raw(pid as i32)));
+        assert_eq!(stepper.control(&ControlCommand::Kill("svc".into())), "ok\n");
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            let pids = stepper.running_pids();
+            pids.first().is_some_and(|(_, p)| *p != pid).then_some(())
+        });
+        assert_eq!(stepper.status()[0].restarts, 1);
+        assert_eq!(
+            stepper.status()[0].last_exit,
+            Some(Termination::Signaled(Signal::SIGKILL as i32))
+        );
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn control_term_test() {
+        let clock = TestClock::new();
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/stubborn"))
+            .backoff_base(Duration::ZERO)
+            .clock(clock.clone())
+            .build();
+        stepper.invoke().unwrap();
+        // Let the service install its traps
+        thread::sleep(Duration::from_millis(200));
+        let pid = stepper.running_pids()[0].1;
+
+        assert_eq!(stepper.control(&ControlCommand::Term("svc".into())), "ok\n");
+        stepper.invoke().unwrap();
+        assert_eq!(stepper.running_pids()[0].1, pid);
+        assert_eq!(stepper.status()[0].state, ServiceState::Stopping);
+        // Ignoring SIGTERM, it is killed after the kill timeout and respawned
+        clock.advance(stepper.kill_timeout + Duration::from_millis(1));
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper
+                .running_pids()
+                .first()
+                .filter(|(_, p)| *p != pid)
+                .map(drop)
+        });
+        assert_eq!(
+            stepper.status()[0].last_exit,
+            Some(Termination::Signaled(Signal::SIGKILL as i32))
+        );
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hash_cache_test() {
+        let dir = empty_dir("hash_cache");
+        let run = dir.join("svc/run");
+        let write = |version: u32| {
+            service(
+                &dir,
+                "svc",
+                &format!("#!/bin/sh\n# {version}\nexec sleep 60\n"),
+            );
+            // Old enough to trust the cache
+            std::fs::File::options()
+                .write(true)
+                .open(&run)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000))
+                .unwrap();
+        };
+        write(1);
+        let mut stepper = Stepper::new(dir.clone());
+        let first = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        // Let the shell read its script before it is rewritten
+        thread::sleep(Duration::from_millis(200));
+
+        // The same size, modification time and inode hide the edit
+        write(2);
+        stepper.invoke().unwrap();
+        assert_eq!(stepper.running_pids()[0].0, first);
+
+        stepper.hash_cache = false;
+        assert_eq!(
+            wait_until(|| stepper.invoke().unwrap().spawned.pop()),
+            Shash::try_from(run.as_path()).unwrap()
+        );
+        assert_ne!(first, Shash::try_from(run.as_path()).unwrap());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn content_change_test() {
+        let dir = empty_dir("content_change");
+        let write = |version: u32| {
+            service(
+                &dir,
+                "svc",
+                &format!("#!/bin/sh\n# {version}\nexec sleep 60\n"),
+            );
+        };
+        write(1);
+        let mut stepper = Stepper::new(dir.clone());
+        let first = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        let pid = Pid::from_raw(stepper.running_pids()[0].1 as i32);
+        // Let the shell read its script before it is rewritten
+        thread::sleep(Duration::from_millis(200));
+
+        write(2);
+        assert!(stepper.invoke().unwrap().spawned.is_empty());
+        assert!(
+            !stepper.running.contains_key(&first) || stepper.running[&first].term_sent.is_some()
+        );
+
+        let second = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        assert_ne!(second, first);
+        assert!(!stepper.running.contains_key(&first));
+        assert!(!alive(pid));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notification_fd_test() {
+        let mut stepper = Stepper::new(PathBuf::from("test_res/notify"));
+        let svc = Shash::try_from(Path::new("test_res/notify/svc/run")).unwrap();
+        assert_eq!(stepper.invoke().unwrap().spawned, [svc]);
+        assert_eq!(stepper.status()[0].state, ServiceState::Starting);
+
+        // The dependent waits until the service notified it is ready
+        let spawned = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        assert_eq!(
+            spawned,
+            Shash::try_from(Path::new("test_res/notify/dependent/run")).unwrap()
+        );
+        let states: Vec<_> = stepper.status().into_iter().map(|s| s.state).collect();
+        assert!(states.contains(&ServiceState::Ready));
+        assert!(states.contains(&ServiceState::Running));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restart_dependents_test() {
+        let mut stepper = Stepper::builder(PathBuf::from("test_res/needs"))
+            .restart_dependents(true)
+            .backoff_base(Duration::ZERO)
+            .build();
+        stepper.invoke().unwrap();
+        let a = Shash::try_from(Path::new("test_res/needs/a/run")).unwrap();
+        let b = Shash::try_from(Path::new("test_res/needs/b/run")).unwrap();
+        let pid = |stepper: &Stepper, hash: &Shash| {
+            stepper
+                .running_pids()
+                .into_iter()
+                .find(|(h, _)| h == hash)
+                .map(|(_, pid)| pid)
+        };
+        let (a_pid, b_pid) = (pid(&stepper, &a).unwrap(), pid(&stepper, &b).unwrap());
+
+        kill(Pid::from_raw(b_pid as i32), Signal::SIGKILL).unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&a].term_sent.map(drop)
+        });
+        // Both start again, the dependent after the service it needs
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            pid(&stepper, &a).filter(|pid| *pid != a_pid)
+        });
+        assert_ne!(pid(&stepper, &b), Some(b_pid));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stubborn_dependent_test() {
+        let dir = empty_dir("stubborn_dependent");
+        for name in ["a", "b"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        std::fs::copy("test_res/stubborn/svc/run", dir.join("a/run")).unwrap();
+        std::fs::write(dir.join("a/needs"), "b\n").unwrap();
+        std::fs::copy("test_res/needs/b/run", dir.join("b/run")).unwrap();
+        let clock = TestClock::new();
+        let mut stepper = Stepper::builder(dir.clone())
+            .restart_dependents(true)
+            .backoff_base(Duration::ZERO)
+            .clock(clock.clone())
+            .build();
+        let pid = |stepper: &Stepper, name: &str| {
+            stepper
+                .running_pids()
+                .into_iter()
+                .find(|(h, _)| service_name(h) == Some(OsStr::new(name)))
+                .map(|(_, pid)| pid)
+        };
+        let a_pid = wait_until(|| {
+            stepper.invoke().unwrap();
+            pid(&stepper, "a")
+        });
+        // Let the dependent install its traps
+        thread::sleep(Duration::from_millis(200));
+        let a = Shash::try_from(dir.join("a/run").as_path()).unwrap();
+
+        kill(
+            Pid::from_raw(pid(&stepper, "b").unwrap() as i32),
+            Signal::SIGKILL,
+        )
+        .unwrap();
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&a].term_sent.map(drop)
+        });
+        // The dependent ignores its term signal, so it is killed after the kill timeout
+        clock.advance(stepper.kill_timeout + Duration::from_millis(1));
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            pid(&stepper, "a").filter(|pid| *pid != a_pid)
+        });
+        assert!(!alive(Pid::from_raw(a_pid as i32)));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn health_check_test() {
+        let dir = empty_dir("health_check");
+        let service = service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let check = service.join("check");
+        std::fs::write(&check, "#!/bin/sh\nexit 1\n").unwrap();
+        std::fs::set_permissions(&check, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let clock = TestClock::new();
+        let mut stepper = Stepper::builder(dir)
+            .health_interval(Duration::from_secs(1))
+            .health_failures(2)
+            .clock(clock.clone())
+            .build();
+        let hash = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        let pid = stepper.running_pids()[0].1;
+
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            (stepper.status()[0].check_failures == 1).then_some(())
+        });
+        assert_eq!(stepper.status()[0].state, ServiceState::Unhealthy);
+        assert!(stepper.running[&hash].term_sent.is_none());
+
+        // The second failure in a row restarts the service
+        clock.advance(Duration::from_secs(1));
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&hash].term_sent.map(drop)
+        });
+        wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        assert_ne!(stepper.running_pids()[0].1, pid);
+        assert_eq!(stepper.status()[0].check_failures, 0);
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reaped_check_test() {
+        if !isolated("stepper::tests::reaped_check_test") {
+            return;
+        }
+        let dir = empty_dir("reaped_check");
+        let service = service(&dir, "svc", "#!/bin/sh\nexec sleep 60\n");
+        let check = service.join("check");
+        std::fs::write(&check, "#!/bin/sh\nexit 0\n").unwrap();
+        std::fs::set_permissions(&check, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let clock = TestClock::new();
+        let mut stepper = Stepper::builder(dir)
+            .reap_orphans(true)
+            .health_interval(Duration::from_secs(1))
+            .health_failures(1)
+            .clock(clock.clone())
+            .build();
+        let hash = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        let pid = stepper.running_pids()[0].1;
+
+        for _ in 0..3 {
+            let check = stepper.running[&hash].health.child.as_ref().unwrap().id();
+            // Exited before the step looking at it, so reaping the orphans collects it
+            wait_until(|| (!alive(Pid::from_raw(check as i32))).then_some(()));
+            for (pid, status) in reap_all() {
+                stepper.reaped(pid, status);
+            }
+            stepper.invoke().unwrap();
+            assert!(stepper.running[&hash].health.child.is_none());
+            clock.advance(Duration::from_secs(1));
+            stepper.invoke().unwrap();
+        }
+        assert_eq!(stepper.running_pids()[0].1, pid);
+        assert_eq!(stepper.status()[0].check_failures, 0);
+        assert!(stepper.running[&hash].term_sent.is_none());
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unhealthy_kill_test() {
+        let dir = empty_dir("unhealthy_kill");
+        let stubborn = std::fs::read_to_string("test_res/stubborn/svc/run").unwrap();
+        let service = service(&dir, "svc", &stubborn);
+        let check = service.join("check");
+        std::fs::write(&check, "#!/bin/sh\nexit 1\n").unwrap();
+        std::fs::set_permissions(&check, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let clock = TestClock::new();
+        let mut stepper = Stepper::builder(dir)
+            .health_interval(Duration::from_secs(1))
+            .health_failures(1)
+            .backoff_base(Duration::ZERO)
+            .clock(clock.clone())
+            .build();
+        let hash = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        // Let the service install its traps
+        thread::sleep(Duration::from_millis(200));
+        let pid = stepper.running_pids()[0].1;
+
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper.running[&hash].term_sent.map(drop)
+        });
+        // The service ignores its term signal, so it is killed after the kill timeout
+        clock.advance(stepper.kill_timeout + Duration::from_millis(1));
+        wait_until(|| {
+            stepper.invoke().unwrap();
+            stepper
+                .running_pids()
+                .first()
+                .filter(|(_, p)| *p != pid)
+                .map(drop)
+        });
+        assert!(!alive(Pid::from_raw(pid as i32)));
+        assert_eq!(
+            stepper.status()[0].last_exit,
+            Some(Termination::Signaled(Signal::SIGKILL as i32))
+        );
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_rename_test() {
+        let dir = empty_dir("symlink_rename");
+        let _ = std::fs::remove_dir_all(&dir);
+        let scan = dir.join("scan");
+        std::fs::create_dir_all(&scan).unwrap();
+        let target = service(&dir.join("sv"), "svc", "#!/bin/sh\nexec sleep 60\n");
+        std::os::unix::fs::symlink(&target, scan.join("a")).unwrap();
+        let mut stepper = Stepper::new(scan.clone());
+        let first = wait_until(|| stepper.invoke().unwrap().spawned.pop());
+        let pid = stepper.running_pids()[0].1;
+
+        std::fs::rename(scan.join("a"), scan.join("b")).unwrap();
+        let report = stepper.invoke().unwrap();
+        assert!(report.spawned.is_empty());
+        assert_eq!(
+            stepper.running_pids(),
+            [(
+                Identity::default()
+                    .shash(&scan.join("b/run"), HashAlg::default(), None)
+                    .unwrap(),
+                pid
+            )]
         );
+        assert!(first.same_identity(&stepper.running_pids()[0].0));
+        stepper.shutdown_timeout = Duration::ZERO;
+        stepper.shutdown();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
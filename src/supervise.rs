@@ -0,0 +1,387 @@
+//! The `supervise` directory runit keeps in every service directory.
+//!
+//! `pid`, `stat` and the binary `status` tell `sv` and scripts about the service, `sv` finds the
+//! supervisor running by opening the `ok` FIFO and sends commands to the `control` FIFO, both are
+//! Unix only.
+
+#[cfg(unix)]
+use crate::process::Signal;
+use crate::process::{start_time, Process};
+#[cfg(unix)]
+use std::fs::File;
+use std::fs::{create_dir_all, read_to_string, remove_file, rename, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use tracing::{debug, warn};
+
+/// A command `sv` writes to `supervise/control`, a single character
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Control {
+    /// `u`, wants the service up
+    Up,
+    /// `d`, wants the service down, `x` as well as the supervisor does not exit for one service
+    Down,
+    /// `o`, spawns the service if it is down, but does not respawn it
+    Once,
+    /// `t` and `k` stop the service, which is respawned if it is wanted up, `p` pauses it, `c`
+    /// continues it, `a`, `h`, `i`, `q`, `1` and `2` send ALRM, HUP, INT, QUIT, USR1 and USR2
+    Signal(Signal),
+}
+
+#[cfg(unix)]
+impl Control {
+    fn parse(command: u8) -> Option<Self> {
+        Some(match command {
+            b'u' => Self::Up,
+            b'd' | b'x' => Self::Down,
+            b'o' => Self::Once,
+            b't' => Self::Signal(Signal::SIGTERM),
+            b'k' => Self::Signal(Signal::SIGKILL),
+            b'p' => Self::Signal(Signal::SIGSTOP),
+            b'c' => Self::Signal(Signal::SIGCONT),
+            b'a' => Self::Signal(Signal::SIGALRM),
+            b'h' => Self::Signal(Signal::SIGHUP),
+            b'i' => Self::Signal(Signal::SIGINT),
+            b'q' => Self::Signal(Signal::SIGQUIT),
+            b'1' => Self::Signal(Signal::SIGUSR1),
+            b'2' => Self::Signal(Signal::SIGUSR2),
+            _ => return None,
+        })
+    }
+}
+
+/// The state of a service as runit tells it
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum State {
+    Down,
+    Run,
+    /// Its `finish` script runs
+    Finish,
+}
+
+/// What `supervise` tells about a service
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Status {
+    pub(crate) pid: Option<u32>,
+    pub(crate) state: State,
+    /// Whether the supervisor wants the service up
+    pub(crate) want_up: bool,
+    /// Whether the service got its term signal
+    pub(crate) term: bool,
+    pub(crate) paused: bool,
+}
+
+impl Status {
+    /// The 20 bytes of `supervise/status`: the TAI64N time of the last change, the PID in little
+    /// endian, the paused flag, `u` or `d` for the wanted state, the term flag and the state
+    fn encode(&self, since: SystemTime) -> [u8; 20] {
+        let since = since.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut status = [0; 20];
+        status[..8].copy_from_slice(&(TAI64_EPOCH + since.as_secs()).to_be_bytes());
+        status[8..12].copy_from_slice(&since.subsec_nanos().to_be_bytes());
+        status[12..16].copy_from_slice(&self.pid.unwrap_or_default().to_le_bytes());
+        status[16] = u8::from(self.paused);
+        status[17] = if self.want_up { b'u' } else { b'd' };
+        status[18] = u8::from(self.term);
+        status[19] = match self.state {
+            State::Down => 0,
+            State::Run => 1,
+            State::Finish => 2,
+        };
+        status
+    }
+
+    /// The line of `supervise/stat`, such as `run, got TERM, want down`
+    fn stat(&self) -> String {
+        let mut stat = match self.state {
+            State::Down => "down",
+            State::Run => "run",
+            State::Finish => "finish",
+        }
+        .to_string();
+        if self.paused {
+            stat += ", paused";
+        }
+        if self.term {
+            stat += ", got TERM";
+        }
+        if self.state != State::Down && !self.want_up {
+            stat += ", want down";
+        }
+        stat + "\n"
+    }
+}
+
+/// The TAI64 label of the Unix epoch
+const TAI64_EPOCH: u64 = (1 << 62) + 10;
+
+/// The `supervise` directory of a service, holding its FIFOs open
+pub(crate) struct Supervise {
+    dir: PathBuf,
+    /// The status last written and since when it holds
+    written: Option<(Status, SystemTime)>,
+    #[cfg(unix)]
+    control: Option<File>,
+    /// Only kept open, `sv` tells the supervisor runs by opening it for writing
+    #[cfg(unix)]
+    _ok: Option<File>,
+}
+
+impl Supervise {
+    /// Creates the directory of the service in `service_dir` with its FIFOs, as far as it can
+    pub(crate) fn new(service_dir: &Path) -> Self {
+        let dir = dir(service_dir);
+        let _ = create_dir_all(&dir);
+        Self {
+            #[cfg(unix)]
+            control: fifo(&dir.join("control")),
+            #[cfg(unix)]
+            _ok: fifo(&dir.join("ok")),
+            dir,
+            written: None,
+        }
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Takes the commands written to `control` since the last call
+    #[cfg(unix)]
+    pub(crate) fn commands(&mut self) -> Vec<Control> {
+        use std::io::{ErrorKind, Read};
+
+        let Some(control) = &mut self.control else {
+            return vec![];
+        };
+        let mut commands = vec![];
+        let mut buf = [0; 64];
+        loop {
+            match control.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    for &command in &buf[..len] {
+                        match Control::parse(command) {
+                            Some(command) => commands.push(command),
+                            None if command.is_ascii_whitespace() => {}
+                            None => warn!(
+                                "unknown command {:?} in {:?}",
+                                char::from(command),
+                                self.dir.join("control")
+                            ),
+                        }
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("reading {:?} failed: {err}", self.dir.join("control"));
+                    break;
+                }
+            }
+        }
+        commands
+    }
+
+    /// Writes `status` unless it was written last, the time of the change is kept while the
+    /// state and the PID stay the same
+    pub(crate) fn update(&mut self, status: Status) -> io::Result<()> {
+        let since = match self.written {
+            Some((written, _)) if written == status => return Ok(()),
+            Some((written, since))
+                if (written.state, written.pid) == (status.state, status.pid) =>
+            {
+                since
+            }
+            _ => SystemTime::now(),
+        };
+        self.written = Some((status, since));
+        match status.pid {
+            Some(pid) => replace(&self.dir.join("pid"), format!("{pid}\n").as_bytes())?,
+            None => {
+                let _ = remove_file(self.dir.join("pid"));
+            }
+        }
+        replace(&self.dir.join("stat"), status.stat().as_bytes())?;
+        replace(&self.dir.join("status"), &status.encode(since))
+    }
+}
+
+/// Creates the FIFO at `path` unless there is one, opening it for reading without blocking
+#[cfg(unix)]
+fn fifo(path: &Path) -> Option<File> {
+    use nix::libc::{mkfifo, O_NONBLOCK};
+    use std::ffi::CString;
+    use std::fs::OpenOptions;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+    if !std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo()) {
+        let _ = remove_file(path);
+        let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        // SAFETY: the path is a valid C string
+        if unsafe { mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            debug!(
+                "creating FIFO {path:?} failed: {}",
+                io::Error::last_os_error()
+            );
+            return None;
+        }
+    }
+    // Opened for writing too, so reads do not see the end of the file whenever a writer is gone
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(O_NONBLOCK)
+        .open(path)
+        .inspect_err(|err| debug!("opening FIFO {path:?} failed: {err}"))
+        .ok()
+}
+
+/// Writes `contents` next to `path` and renames it over `path` at once
+fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut new = path.as_os_str().to_owned();
+    new.push(".new");
+    write(&new, contents)?;
+    rename(new, path)
+}
+
+/// `<service_dir>/supervise`
+pub(crate) fn dir(service_dir: &Path) -> PathBuf {
+    service_dir.join("supervise")
+}
+
+/// Takes up the process `supervise/pid` names if it is alive and is the service, Linux only
+///
+/// The process is the service if it is `run` itself or runs in the service directory, as `run`
+/// is started from there.
+pub(crate) fn adopt(service_dir: &Path) -> Option<Process> {
+    let pid = read_to_string(dir(service_dir).join("pid"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    if !runs_service(pid, service_dir) {
+        return None;
+    }
+    Process::adopt(pid, start_time(pid)?)
+}
+
+#[cfg(target_os = "linux")]
+fn runs_service(pid: u32, service_dir: &Path) -> bool {
+    use std::fs::{canonicalize, read_link};
+
+    let Ok(service_dir) = canonicalize(service_dir) else {
+        return false;
+    };
+    let proc = Path::new("/proc").join(pid.to_string());
+    read_link(proc.join("exe")).is_ok_and(|exe| exe == service_dir.join("run"))
+        || read_link(proc.join("cwd")).is_ok_and(|cwd| cwd == service_dir)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn runs_service(_pid: u32, _service_dir: &Path) -> bool {
+    false
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn status_test() {
+        let status = Status {
+            pid: Some(0x01020304),
+            state: State::Finish,
+            want_up: false,
+            term: true,
+            paused: true,
+        };
+        let since = UNIX_EPOCH + std::time::Duration::new(1, 5);
+        assert_eq!(
+            status.encode(since),
+            [0x40, 0, 0, 0, 0, 0, 0, 11, 0, 0, 0, 5, 4, 3, 2, 1, 1, b'd', 1, 2]
+        );
+        assert_eq!(status.stat(), "finish, paused, got TERM, want down\n");
+        let down = Status {
+            pid: None,
+            state: State::Down,
+            ..status
+        };
+        assert_eq!(down.stat(), "down, paused, got TERM\n");
+        assert_eq!(down.encode(since)[12..16], [0; 4]);
+    }
+
+    #[test]
+    fn commands_test() {
+        use std::io::Write;
+
+        let service_dir =
+            std::env::temp_dir().join(format!("runsvdir-control-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&service_dir);
+        let mut supervise = Supervise::new(&service_dir);
+        assert_eq!(supervise.commands(), []);
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(service_dir.join("supervise/control"))
+            .unwrap()
+            .write_all(b"dz k\n")
+            .unwrap();
+        assert_eq!(
+            supervise.commands(),
+            [Control::Down, Control::Signal(Signal::SIGKILL)]
+        );
+        assert_eq!(supervise.commands(), []);
+        std::fs::remove_dir_all(service_dir).unwrap();
+    }
+
+    #[test]
+    fn adopt_test() {
+        let service_dir =
+            std::env::temp_dir().join(format!("runsvdir-supervise-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&service_dir);
+        std::fs::create_dir_all(&service_dir).unwrap();
+        assert!(adopt(&service_dir).is_none());
+
+        let mut supervise = Supervise::new(&service_dir);
+        let running = |pid| Status {
+            pid: Some(pid),
+            state: State::Run,
+            want_up: true,
+            term: false,
+            paused: false,
+        };
+        // This process runs elsewhere
+        supervise.update(running(std::process::id())).unwrap();
+        assert!(adopt(&service_dir).is_none());
+
+        let mut child = Command::new("sleep")
+            .arg("60")
+            .current_dir(&service_dir)
+            .spawn()
+            .unwrap();
+        supervise.update(running(child.id())).unwrap();
+        assert_eq!(
+            read_to_string(service_dir.join("supervise/pid")).unwrap(),
+            format!("{}\n", child.id())
+        );
+        assert_eq!(adopt(&service_dir).map(|p| p.id()), Some(child.id()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(adopt(&service_dir).is_none());
+
+        supervise
+            .update(Status {
+                pid: None,
+                state: State::Down,
+                ..running(0)
+            })
+            .unwrap();
+        assert!(!service_dir.join("supervise/pid").exists());
+        std::fs::remove_dir_all(service_dir).unwrap();
+    }
+}
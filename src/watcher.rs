@@ -0,0 +1,126 @@
+//! Change detection for the service directory.
+//!
+//! Uses inotify on Linux. Elsewhere [`Watcher::new`] fails with
+//! [`io::ErrorKind::Unsupported`] and callers are expected to fall back to polling.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+pub struct Watcher {
+    inotify: nix::sys::inotify::Inotify,
+    dir: std::path::PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    /// Watches `dir` for added or removed entries and every service in it for `run` changes
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            dir,
+            AddWatchFlags::IN_CREATE
+                | AddWatchFlags::IN_DELETE
+                | AddWatchFlags::IN_MOVED_FROM
+                | AddWatchFlags::IN_MOVED_TO,
+        )?;
+        let watcher = Self {
+            inotify,
+            dir: dir.into(),
+        };
+        watcher.watch_services();
+        Ok(watcher)
+    }
+
+    fn watch_services(&self) {
+        use nix::sys::inotify::AddWatchFlags;
+
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            // Entries which are not directories are of no interest
+            let _ = self.inotify.add_watch(
+                entry.path().as_path(),
+                AddWatchFlags::IN_CREATE
+                    | AddWatchFlags::IN_DELETE
+                    | AddWatchFlags::IN_MOVED_FROM
+                    | AddWatchFlags::IN_MOVED_TO
+                    | AddWatchFlags::IN_CLOSE_WRITE
+                    | AddWatchFlags::IN_ATTRIB
+                    | AddWatchFlags::IN_ONLYDIR,
+            );
+        }
+    }
+
+    /// Waits up to `timeout` for a change, returning whether one happened
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        use nix::errno::Errno;
+        use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+        use std::os::fd::AsFd;
+
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(false),
+            Ok(_) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut changed = false;
+        loop {
+            match self.inotify.read_events() {
+                Ok(events) if !events.is_empty() => changed = true,
+                Ok(_) | Err(Errno::EAGAIN) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if changed {
+            self.watch_services();
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Watcher(());
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    pub fn new(_dir: &Path) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        std::thread::sleep(timeout);
+        Ok(false)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn watcher_test() {
+        let dir = std::env::temp_dir().join(format!("runsvdir-watcher-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut watcher = Watcher::new(&dir).unwrap();
+        let timeout = Duration::from_secs(1);
+        assert!(!watcher.wait(Duration::from_millis(10)).unwrap());
+
+        fs::create_dir(dir.join("svc")).unwrap();
+        assert!(watcher.wait(timeout).unwrap());
+
+        fs::write(dir.join("svc/run"), "#!/bin/sh\n").unwrap();
+        assert!(watcher.wait(timeout).unwrap());
+
+        fs::remove_dir_all(dir.join("svc")).unwrap();
+        assert!(watcher.wait(timeout).unwrap());
+    }
+}
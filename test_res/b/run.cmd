@@ -0,0 +1,4 @@
+@echo off
+:loop
+ping -n 3 127.0.0.1 >nul
+goto loop
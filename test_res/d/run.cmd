@@ -0,0 +1 @@
+@exit /b 0
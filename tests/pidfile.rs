@@ -0,0 +1,46 @@
+#![cfg(unix)]
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::fs::read_to_string;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn supervisor(pidfile: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_runsvdir"));
+    command
+        .args([
+            "--pidfile",
+            pidfile,
+            "--shutdown-timeout",
+            "0",
+            "test_res/stubborn",
+        ])
+        .stderr(Stdio::null());
+    command
+}
+
+#[test]
+fn pidfile_test() {
+    let path = std::env::temp_dir().join(format!("runsvdir-pidfile-bin-{}", std::process::id()));
+    let pidfile = path.to_str().unwrap();
+    let mut first = supervisor(pidfile).spawn().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let pid = loop {
+        if let Some(pid) = read_to_string(&path).ok().filter(|pid| pid.ends_with('\n')) {
+            break pid;
+        }
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(pid.trim(), first.id().to_string());
+
+    let second = supervisor(pidfile).status().unwrap();
+    assert!(!second.success());
+    assert_eq!(read_to_string(&path).unwrap(), pid);
+
+    kill(Pid::from_raw(first.id() as i32), Signal::SIGTERM).unwrap();
+    assert!(first.wait().unwrap().success());
+    assert!(!path.exists());
+}
@@ -0,0 +1,60 @@
+#![cfg(unix)]
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn runsvctl(socket: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_runsvctl"))
+        .args(["--socket", socket])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn runsvctl_test() {
+    let path = std::env::temp_dir().join(format!("runsvdir-runsvctl-{}", std::process::id()));
+    let socket = path.to_str().unwrap();
+    let mut supervisor = Command::new(env!("CARGO_BIN_EXE_runsvdir"))
+        .args([
+            "--control-socket",
+            socket,
+            "--pause",
+            "100",
+            "--shutdown-timeout",
+            "0",
+            "test_res/stubborn",
+        ])
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let status = loop {
+        let output = runsvctl(socket, &["status"]);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        if output.status.success() && stdout.starts_with("svc running ") {
+            break stdout;
+        }
+        assert!(Instant::now() < deadline, "no status: {stdout}");
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(status.lines().count(), 1);
+
+    let output = runsvctl(socket, &["restart", "other"]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "error: no such service \"other\"\n"
+    );
+    let output = runsvctl(socket, &["down", "svc"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "ok\n");
+
+    kill(Pid::from_raw(supervisor.id() as i32), Signal::SIGTERM).unwrap();
+    assert!(supervisor.wait().unwrap().success());
+    assert!(!runsvctl(socket, &["status"]).status.success());
+}
@@ -0,0 +1,47 @@
+#![cfg(unix)]
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn sighup_test() {
+    let mut supervisor = Command::new(env!("CARGO_BIN_EXE_runsvdir"))
+        .args([
+            "--pause",
+            "60000",
+            "--shutdown-timeout",
+            "0",
+            "test_res/stubborn",
+        ])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = Pid::from_raw(supervisor.id() as i32);
+    let (tx, rx) = mpsc::channel();
+    let stderr = supervisor.stderr.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            let _ = tx.send(line.unwrap());
+        }
+    });
+    let wait_for = |pattern: &str| {
+        while let Ok(line) = rx.recv_timeout(Duration::from_secs(2)) {
+            if line.contains(pattern) {
+                return true;
+            }
+        }
+        false
+    };
+
+    assert!(wait_for("spawn"));
+    kill(pid, Signal::SIGHUP).unwrap();
+    assert!(wait_for("is already running"));
+
+    kill(pid, Signal::SIGTERM).unwrap();
+    assert!(supervisor.wait().unwrap().success());
+}
@@ -0,0 +1,27 @@
+#![cfg(target_os = "linux")]
+
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+use runsvdir::become_subreaper;
+use std::process::{Command, Stdio};
+
+// The flag stays on the whole test process, so this is the only test of its binary
+#[test]
+fn subreaper_test() {
+    become_subreaper().unwrap();
+    let output = Command::new("sh")
+        .args(["-c", "sleep 0.1 & echo $!"])
+        .stdout(Stdio::piped())
+        .output()
+        .unwrap();
+    let orphan: i32 = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    // Only the parent can wait for the orphan
+    assert_eq!(
+        waitpid(Pid::from_raw(orphan), None).unwrap(),
+        WaitStatus::Exited(Pid::from_raw(orphan), 0)
+    );
+}